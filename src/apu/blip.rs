@@ -0,0 +1,166 @@
+// Band-limited step synthesis, after blargg's blip_buf. The APU's output
+// is a square-edged signal changing at CPU clock resolution, and point
+// sampling it at 44.1 kHz folds everything above the output rate's Nyquist
+// frequency back down as hiss and whine. Instead every change in level gets
+// added to the output as a windowed sinc step, which only contains
+// frequencies the output can carry.
+//
+// The buffer holds the differences between output samples. Reading them
+// out runs them through an integrator, so a delta only has to be spread
+// over the few samples around where it happened.
+
+// Sub-sample positions a step can start at
+const PHASES: usize = 64;
+// Output samples each step gets spread over
+const TAPS: usize = 16;
+// Fraction of the output's Nyquist frequency the steps are limited to,
+// just under 1 so the sinc's transition band doesn't alias
+const CUTOFF: f64 = 0.9;
+
+pub struct BlipBuffer {
+    // Output samples per input clock
+    factor: f64,
+    // Output position, in samples, of the current frame's start
+    offset: f64,
+    buffer: Vec<f32>,
+    integrator: f32,
+    kernel: Vec<[f32; TAPS]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        Self {
+            factor: sample_rate / clock_rate,
+            offset: 0.0,
+            buffer: vec![0.0; TAPS],
+            integrator: 0.0,
+            kernel: make_kernel(),
+        }
+    }
+
+    // Adds a change in level at a time counted in input clocks from the
+    // start of the current frame
+    pub fn add_delta(&mut self, time: u64, delta: f32) {
+        let position = self.offset + time as f64 * self.factor;
+        let index = position as usize;
+        let phase = ((position - index as f64) * PHASES as f64) as usize;
+
+        let end = index + TAPS;
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0.0);
+        }
+        for (sample, weight) in self.buffer[index..end].iter_mut().zip(&self.kernel[phase]) {
+            *sample += delta * weight;
+        }
+    }
+
+    // Ends the current frame after the given number of clocks, making
+    // the samples before that point available. Returns how many there are.
+    pub fn end_frame(&mut self, clocks: u64) -> usize {
+        self.offset += clocks as f64 * self.factor;
+        let available = self.offset as usize;
+
+        let end = available + TAPS;
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0.0);
+        }
+        available
+    }
+
+    // Removes the samples end_frame made available and appends them to out
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let available = self.offset as usize;
+
+        for delta in self.buffer.drain(..available) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.offset -= available as f64;
+    }
+}
+
+// A sinc impulse under a Blackman window for each phase, normalised so a
+// whole step adds up to exactly its delta
+fn make_kernel() -> Vec<[f32; TAPS]> {
+    let half = TAPS as f64 / 2.0;
+
+    (0..PHASES)
+        .map(|phase| {
+            let fraction = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; TAPS];
+
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = k as f64 - (half - 1.0) - fraction;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let angle = std::f64::consts::PI * CUTOFF * x;
+                    angle.sin() / angle
+                };
+                let window = if x.abs() >= half {
+                    0.0
+                } else {
+                    let angle = std::f64::consts::PI * x / half;
+                    0.42 + 0.5 * angle.cos() + 0.08 * (2.0 * angle).cos()
+                };
+                *tap = sinc * window;
+            }
+
+            let sum: f64 = taps.iter().sum();
+            taps.map(|tap| (tap / sum) as f32)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_step_settles_on_delta() {
+        let mut blip = BlipBuffer::new(1_000_000.0, 44_100.0);
+        blip.add_delta(1000, 0.5);
+        blip.add_delta(5000, -0.25);
+
+        let mut samples = Vec::new();
+        let available = blip.end_frame(10_000);
+        blip.read_samples(&mut samples);
+
+        assert_eq!(available, 441);
+        assert_eq!(samples.len(), 441);
+        assert!(samples[..40].iter().all(|s| s.abs() < 1e-6));
+        assert!((samples[150] - 0.5).abs() < 1e-4);
+        assert!((samples[440] - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_frames_keep_fractional_position() {
+        let mut blip = BlipBuffer::new(1_789_773.0, 48_000.0);
+        let mut samples = Vec::new();
+
+        for _ in 0..60 {
+            blip.end_frame(29_830);
+            blip.read_samples(&mut samples);
+        }
+
+        // 60 frames of 29830 clocks
+        let expected = 60.0 * 29_830.0 * 48_000.0 / 1_789_773.0;
+        assert!((samples.len() as f64 - expected).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_step_is_band_limited() {
+        let mut blip = BlipBuffer::new(44_100.0 * 64.0, 44_100.0);
+        blip.add_delta(64 * 20 + 17, 1.0);
+        let mut samples = Vec::new();
+        blip.end_frame(64 * 64);
+        blip.read_samples(&mut samples);
+
+        // A band-limited edge rings around its target, which a point
+        // sampled one never does
+        let peak = samples.iter().fold(0.0, |peak: f32, s| peak.max(*s));
+        assert!(peak > 1.02);
+        assert!(samples[..10].iter().all(|s| s.abs() < 1e-6));
+        assert!((samples[63] - 1.0).abs() < 1e-4);
+    }
+}
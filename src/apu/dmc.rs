@@ -0,0 +1,206 @@
+// The delta modulation channel at $4010-$4013. It plays 1 bit delta encoded
+// samples out of $C000-$FFFF, nudging a 7 bit output level up or down by 2
+// for every bit. The bytes come in through DMA: the channel asks for one
+// whenever its buffer runs dry and the CPU fetches it over its own bus.
+
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    pub irq: bool,
+    period: u16,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    pub fn new(periods: &[u16; 16]) -> Self {
+        Self {
+            irq_enabled: false,
+            looping: false,
+            irq: false,
+            period: periods[0],
+            timer: periods[0],
+            level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            address: 0xc000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    // Takes the output periods of the console's region, in CPU cycles
+    pub fn write(&mut self, register: u16, data: u8, periods: &[u16; 16]) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.looping = data & 0x40 != 0;
+                self.period = periods[(data & 0x0f) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = data & 0x7f,
+            2 => self.sample_address = 0xc000 | (data as u16) << 6,
+            _ => self.sample_length = ((data as u16) << 4) | 1,
+        }
+    }
+
+    // $4015 bit 4. Enabling only restarts a sample that has finished.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // The address the channel wants a byte from, if its buffer is empty
+    // and the sample isn't over
+    pub fn dma_request(&self) -> Option<u16> {
+        match self.buffer {
+            None if self.bytes_remaining > 0 => Some(self.address),
+            _ => None,
+        }
+    }
+
+    // Hands over the byte the CPU fetched for the last request
+    pub fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        // Wraps around to $8000 rather than $0000
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle, which is what the period table counts in
+    pub fn clock_timer(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period;
+
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.shift = data;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PERIODS: [u16; 16] = [8; 16];
+
+    // Feeds the channel from a fake bus for a number of CPU cycles
+    fn run(dmc: &mut Dmc, cycles: usize, memory: impl Fn(u16) -> u8) -> Vec<u16> {
+        let mut fetched = Vec::new();
+        for _ in 0..cycles {
+            if let Some(addr) = dmc.dma_request() {
+                fetched.push(addr);
+                dmc.fill(memory(addr));
+            }
+            dmc.clock_timer();
+        }
+        fetched
+    }
+
+    #[test]
+    fn test_playback_moves_level() {
+        let mut dmc = Dmc::new(&PERIODS);
+        dmc.write(1, 0x40, &PERIODS);
+        // A single byte at $C000
+        dmc.write(3, 0x00, &PERIODS);
+        dmc.set_enabled(true);
+
+        // The first 8 bits play out of the empty shift register, then the
+        // fetched $0F goes up four times and down four times
+        run(&mut dmc, 8 * 8, |_| 0x0f);
+        assert_eq!(dmc.output(), 0x40);
+        run(&mut dmc, 4 * 8, |_| 0x0f);
+        assert_eq!(dmc.output(), 0x48);
+        run(&mut dmc, 4 * 8, |_| 0x0f);
+        assert_eq!(dmc.output(), 0x40);
+        assert!(!dmc.active());
+    }
+
+    #[test]
+    fn test_irq_and_looping() {
+        let mut dmc = Dmc::new(&PERIODS);
+        dmc.write(0, 0x80, &PERIODS);
+        dmc.write(2, 0xff, &PERIODS);
+        dmc.write(3, 0x04, &PERIODS);
+        dmc.set_enabled(true);
+
+        // 65 bytes from $FFC0, wrapping past $FFFF to $8000
+        let fetched = run(&mut dmc, 65 * 8 * 8, |_| 0);
+        assert_eq!(fetched.len(), 65);
+        assert_eq!(fetched[0], 0xffc0);
+        assert_eq!(fetched[63], 0xffff);
+        assert_eq!(fetched[64], 0x8000);
+        assert!(dmc.irq);
+
+        dmc.set_enabled(false);
+        assert!(!dmc.irq);
+
+        dmc.write(0, 0x40, &PERIODS);
+        dmc.set_enabled(true);
+        let fetched = run(&mut dmc, 100 * 8 * 8, |_| 0);
+        assert!(fetched.len() > 65);
+        assert!(dmc.active());
+        assert!(!dmc.irq);
+    }
+}
@@ -0,0 +1,144 @@
+// The pieces the pulse and noise channels share: the volume envelope and,
+// together with the triangle, the length counter that silences a note once
+// it has played for as long as its $4003/$4007/$400B/$400F write asked.
+
+// Note lengths in half frames, indexed by the top 5 bits of the length
+// register
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Decays from 15 to 0, one step per divider period, or holds a constant
+// volume when the channel asks for one
+#[derive(Default)]
+pub struct Envelope {
+    pub start: bool,
+    // Shared with the length counter halt bit
+    pub looping: bool,
+    pub constant: bool,
+    // Either the constant volume or the divider period
+    pub volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // Takes the --LC VVVV bits of the channel's first register
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0f;
+    }
+
+    // Clocked every quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LengthCounter {
+    enabled: bool,
+    pub halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    // Disabling a channel through $4015 clears its counter right away
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // Takes the LLLL L--- bits of the channel's last register
+    pub fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTHS[(data >> 3) as usize];
+        }
+    }
+
+    // Clocked every half frame
+    pub fn clock(&mut self) {
+        if self.counter > 0 && !self.halt {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_envelope_decay_and_loop() {
+        let mut envelope = Envelope::default();
+        // Divider period of 1, so the volume drops every other clock
+        envelope.write(0x21);
+        envelope.start = true;
+
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+        for _ in 0..30 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+
+        envelope.write(0x17);
+        assert_eq!(envelope.output(), 7);
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut length = LengthCounter::default();
+        length.load(0x08);
+        assert!(!length.active());
+
+        length.set_enabled(true);
+        // Index 1 is the 254 half frame note
+        length.load(0x08);
+        length.halt = true;
+        length.clock();
+        length.halt = false;
+        for _ in 0..253 {
+            length.clock();
+        }
+        assert!(length.active());
+        length.clock();
+        assert!(!length.active());
+
+        length.load(0x00);
+        length.set_enabled(false);
+        assert!(!length.active());
+    }
+}
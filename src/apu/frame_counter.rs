@@ -0,0 +1,177 @@
+use crate::region::Region;
+
+// The $4017 frame counter. It divides the CPU clock into quarter and half
+// frame ticks for the envelopes, sweeps and counters, either in 4 steps
+// with an IRQ at the end or in 5 steps without one. A write restarts the
+// sequence 3 or 4 CPU cycles later, depending on whether it landed on an
+// APU cycle.
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct FrameClock {
+    pub quarter: bool,
+    pub half: bool,
+}
+
+const QUARTER: FrameClock = FrameClock {
+    quarter: true,
+    half: false,
+};
+const HALF: FrameClock = FrameClock {
+    quarter: true,
+    half: true,
+};
+
+pub struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    pub irq: bool,
+    // CPU cycles since the sequence last restarted
+    cycle: u32,
+    // CPU cycles until a $4017 write restarts the sequence, and the mode
+    // it restarts in
+    reset_delay: Option<(u8, bool)>,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        Self {
+            five_step: false,
+            irq_inhibit: false,
+            irq: false,
+            cycle: 0,
+            reset_delay: None,
+        }
+    }
+
+    pub fn write(&mut self, data: u8, apu_cycle: bool) {
+        // Setting the inhibit flag clears a pending IRQ straight away
+        self.irq_inhibit = data & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq = false;
+        }
+
+        let delay = if apu_cycle { 3 } else { 4 };
+        self.reset_delay = Some((delay, data & 0x80 != 0));
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock(&mut self, region: Region) -> FrameClock {
+        if let Some((delay, five_step)) = self.reset_delay {
+            if delay > 1 {
+                self.reset_delay = Some((delay - 1, five_step));
+            } else {
+                self.reset_delay = None;
+                self.five_step = five_step;
+                self.cycle = 0;
+
+                // The 5 step mode clocks everything as it starts
+                if five_step {
+                    return HALF;
+                }
+                return FrameClock::default();
+            }
+        }
+
+        self.cycle += 1;
+
+        if self.five_step {
+            let steps = region.frame_counter_five_step();
+            match self.cycle {
+                c if c == steps[0] || c == steps[2] => QUARTER,
+                c if c == steps[1] || c == steps[4] => HALF,
+                c if c == steps[4] + 1 => {
+                    self.cycle = 0;
+                    FrameClock::default()
+                }
+                _ => FrameClock::default(),
+            }
+        } else {
+            let steps = region.frame_counter_four_step();
+            // The IRQ flag gets set over the last three cycles
+            if self.cycle >= steps[3] && !self.irq_inhibit {
+                self.irq = true;
+            }
+
+            match self.cycle {
+                c if c == steps[0] || c == steps[2] => QUARTER,
+                c if c == steps[1] || c == steps[4] => HALF,
+                c if c == steps[5] => {
+                    self.cycle = 0;
+                    FrameClock::default()
+                }
+                _ => FrameClock::default(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Cycles, counted from the $4017 write, on which the counter does
+    // something
+    fn events(frame_counter: &mut FrameCounter, cycles: u32) -> Vec<(u32, FrameClock, bool)> {
+        let mut events = Vec::new();
+        let mut irq = frame_counter.irq;
+
+        for cycle in 1..=cycles {
+            let clock = frame_counter.clock(Region::Ntsc);
+            if clock != FrameClock::default() || frame_counter.irq != irq {
+                events.push((cycle, clock, frame_counter.irq));
+                irq = frame_counter.irq;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_four_step_sequence() {
+        let mut frame_counter = FrameCounter::new();
+        frame_counter.write(0x00, true);
+
+        assert_eq!(
+            events(&mut frame_counter, 29833 + 7457),
+            [
+                (3 + 7457, QUARTER, false),
+                (3 + 14913, HALF, false),
+                (3 + 22371, QUARTER, false),
+                (3 + 29828, FrameClock::default(), true),
+                (3 + 29829, HALF, true),
+                (3 + 29830 + 7457, QUARTER, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_five_step_sequence() {
+        let mut frame_counter = FrameCounter::new();
+        // Between APU cycles, so the restart takes a cycle longer
+        frame_counter.write(0x80, false);
+
+        assert_eq!(
+            events(&mut frame_counter, 4 + 37282 + 7457),
+            [
+                (4, HALF, false),
+                (4 + 7457, QUARTER, false),
+                (4 + 14913, HALF, false),
+                (4 + 22371, QUARTER, false),
+                (4 + 37281, HALF, false),
+                (4 + 37282 + 7457, QUARTER, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_irq_inhibit() {
+        let mut frame_counter = FrameCounter::new();
+        frame_counter.write(0x00, true);
+        events(&mut frame_counter, 30000);
+        assert!(frame_counter.irq);
+
+        frame_counter.write(0x40, true);
+        assert!(!frame_counter.irq);
+        events(&mut frame_counter, 30000);
+        assert!(!frame_counter.irq);
+    }
+}
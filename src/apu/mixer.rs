@@ -0,0 +1,191 @@
+use super::blip::BlipBuffer;
+
+// The 2A03 mixes its channels through two resistor networks whose outputs
+// aren't linear in the channel levels, and the console's audio path then
+// runs the result through two high-pass filters and a low-pass one before
+// it reaches the TV.
+
+// The pulse network, for the sum of both pulse levels (0-30)
+pub fn pulse_level(pulse: f32) -> f32 {
+    if pulse <= 0.0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / pulse + 100.0)
+    }
+}
+
+// The triangle/noise/DMC network, for levels of 0-15, 0-15 and 0-127
+pub fn tnd_level(triangle: f32, noise: f32, dmc: f32) -> f32 {
+    let sum = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+
+    if sum <= 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / sum + 100.0)
+    }
+}
+
+// How loud one channel goes into the mix. None of it touches the channel
+// itself, a muted channel keeps running and comes back in step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelControl {
+    pub volume: f32,
+    pub muted: bool,
+    // While any channel is soloed only the soloed ones are heard
+    pub solo: bool,
+}
+
+impl Default for ChannelControl {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+            solo: false,
+        }
+    }
+}
+
+// A first order RC filter running at the output sample rate
+struct OnePole {
+    high_pass: bool,
+    alpha: f32,
+    last_input: f32,
+    last_output: f32,
+}
+
+impl OnePole {
+    fn new(high_pass: bool, cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        let alpha = if high_pass {
+            rc / (rc + dt)
+        } else {
+            dt / (rc + dt)
+        };
+
+        Self {
+            high_pass,
+            alpha,
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.last_output + input - self.last_input)
+        } else {
+            self.last_output + self.alpha * (input - self.last_output)
+        };
+
+        self.last_input = input;
+        self.last_output = output;
+        output
+    }
+}
+
+// The NES's own output filters: high-pass at 90 Hz and 440 Hz, which also
+// take the DC offset out, and low-pass at 14 kHz
+pub struct FilterChain {
+    filters: [OnePole; 3],
+}
+
+impl FilterChain {
+    pub fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f32;
+
+        Self {
+            filters: [
+                OnePole::new(true, 90.0, rate),
+                OnePole::new(true, 440.0, rate),
+                OnePole::new(false, 14_000.0, rate),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.filters
+            .iter_mut()
+            .fold(sample, |sample, filter| filter.process(sample))
+    }
+}
+
+// Turns a level that changes at the CPU clock into filtered samples at the
+// output rate
+pub struct Resampler {
+    blip: BlipBuffer,
+    filters: FilterChain,
+    // Level the blip buffer was last given
+    level: f32,
+    samples: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        Self {
+            blip: BlipBuffer::new(clock_rate, sample_rate as f64),
+            filters: FilterChain::new(sample_rate),
+            level: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    // The level from a time, in clocks into the current block, on
+    pub fn set_level(&mut self, time: u64, level: f32) {
+        if level != self.level {
+            self.blip.add_delta(time, level - self.level);
+            self.level = level;
+        }
+    }
+
+    pub fn end_block(&mut self, clocks: u64) {
+        let start = self.samples.len();
+        self.blip.end_frame(clocks);
+        self.blip.read_samples(&mut self.samples);
+
+        for sample in &mut self.samples[start..] {
+            *sample = self.filters.process(*sample);
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mixer_levels() {
+        assert_eq!(pulse_level(0.0), 0.0);
+        assert!((pulse_level(30.0) - 0.2585).abs() < 1e-4);
+        // Two pulses at 15 come out quieter than twice one of them
+        assert!(pulse_level(30.0) < 2.0 * pulse_level(15.0));
+
+        assert_eq!(tnd_level(0.0, 0.0, 0.0), 0.0);
+        assert!((tnd_level(15.0, 15.0, 127.0) - 0.7417).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_filters_remove_dc() {
+        let mut chain = FilterChain::new(44_100);
+        let mut last = 0.0;
+        for _ in 0..44_100 {
+            last = chain.process(0.5);
+        }
+        assert!(last.abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_low_pass_keeps_audible_range() {
+        let mut low_pass = OnePole::new(false, 14_000.0, 44_100.0);
+        let mut peak: f32 = 0.0;
+        for n in 0..4410 {
+            let input = (2.0 * std::f32::consts::PI * 1000.0 * n as f32 / 44_100.0).sin();
+            peak = peak.max(low_pass.process(input));
+        }
+        assert!(peak > 0.95);
+    }
+}
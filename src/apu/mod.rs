@@ -0,0 +1,662 @@
+mod blip;
+mod dmc;
+mod envelope;
+mod frame_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod triangle;
+
+use dmc::Dmc;
+use frame_counter::FrameCounter;
+pub use mixer::ChannelControl;
+use mixer::Resampler;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+use crate::region::Region;
+
+// The 2A03's audio processing unit. It sits on the CPU bus at $4000-$4013,
+// $4015 and $4017 and runs off the CPU clock. Every change in the mixed
+// level, the cartridge's expansion audio included, goes into a band-limited
+// resampler, whose output passes through the console's filter chain on the
+// way out. Each channel can also be resampled on its own as a stem.
+//
+// The mix can be adjusted per channel with mute, solo and volume controls
+// and a master volume. They reach the cartridge's expansion audio channels
+// too, through gains the CPU hands on to the mapper. Stems ignore them and
+// always carry the channel as the console plays it.
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// CPU cycles the resampler collects before turning them into samples
+const BLOCK_CYCLES: u64 = 4096;
+
+// The sound sources that get mixed together, expansion audio being
+// whatever the cartridge adds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+}
+
+// A channel the mixer controls can address: one of the APU's own, where
+// Channel::Expansion is the cartridge's audio as a whole, or a single
+// expansion audio channel by its index in Mapper::audio_channels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MixerChannel {
+    Apu(Channel),
+    Expansion(usize),
+}
+
+pub struct Apu {
+    region: Region,
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    // CPU cycles since power on. The pulse timers tick on every other one.
+    cycle: u64,
+    // Level of the cartridge's own sound channels
+    expansion: f32,
+    sample_rate: u32,
+    resampler: Resampler,
+    // One per channel, in Channel::ALL order, when stems are being made
+    stems: Vec<Resampler>,
+    // CPU cycles into the resamplers' current block
+    block_cycles: u64,
+
+    // Mixer controls for Channel::ALL and for the expansion audio channels
+    controls: [ChannelControl; 6],
+    expansion_channels: Vec<&'static str>,
+    expansion_controls: Vec<ChannelControl>,
+    master_volume: f32,
+    // What the controls work out to, and the expansion audio's share of
+    // them until the CPU passes it on to the mapper
+    gains: [f32; 6],
+    expansion_gains: Option<Vec<f32>>,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(region.dmc_rates()),
+            frame_counter: FrameCounter::new(),
+            cycle: 0,
+            expansion: 0.0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            resampler: Resampler::new(region.cpu_clock_hz(), DEFAULT_SAMPLE_RATE),
+            stems: Vec::new(),
+            block_cycles: 0,
+            controls: [ChannelControl::default(); 6],
+            expansion_channels: Vec::new(),
+            expansion_controls: Vec::new(),
+            master_volume: 1.0,
+            gains: [1.0; 6],
+            expansion_gains: None,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.reset_resampler();
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.reset_resampler();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Drops whatever is buffered, the old rates no longer apply to it
+    fn reset_resampler(&mut self) {
+        let stems = !self.stems.is_empty();
+        self.resampler = Resampler::new(self.region.cpu_clock_hz(), self.sample_rate);
+        self.block_cycles = 0;
+        self.set_stems(stems);
+    }
+
+    // Starts or stops resampling every channel separately as well
+    pub fn set_stems(&mut self, enabled: bool) {
+        self.stems = if enabled {
+            Channel::ALL
+                .iter()
+                .map(|_| Resampler::new(self.region.cpu_clock_hz(), self.sample_rate))
+                .collect()
+        } else {
+            Vec::new()
+        };
+    }
+
+    // Mixed in with the APU's own channels from the next cycle on
+    pub fn set_expansion_output(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    // The channels of a newly inserted cartridge's expansion audio, which
+    // start out with default controls
+    pub fn set_expansion_channels(&mut self, names: Vec<&'static str>) {
+        self.expansion_controls = vec![ChannelControl::default(); names.len()];
+        self.expansion_channels = names;
+        self.update_gains();
+    }
+
+    pub fn expansion_channels(&self) -> &[&'static str] {
+        &self.expansion_channels
+    }
+
+    // Looks a channel up by its stem name or expansion channel name
+    pub fn find_channel(&self, name: &str) -> Option<MixerChannel> {
+        let apu = Channel::ALL
+            .iter()
+            .find(|channel| channel.name() == name)
+            .map(|channel| MixerChannel::Apu(*channel));
+        let expansion = || {
+            self.expansion_channels
+                .iter()
+                .position(|channel| *channel == name)
+                .map(MixerChannel::Expansion)
+        };
+
+        apu.or_else(expansion)
+    }
+
+    pub fn control(&self, channel: MixerChannel) -> ChannelControl {
+        match channel {
+            MixerChannel::Apu(channel) => self.controls[channel as usize],
+            MixerChannel::Expansion(index) => self.expansion_controls[index],
+        }
+    }
+
+    pub fn set_volume(&mut self, channel: MixerChannel, volume: f32) {
+        self.control_mut(channel).volume = volume;
+        self.update_gains();
+    }
+
+    pub fn set_muted(&mut self, channel: MixerChannel, muted: bool) {
+        self.control_mut(channel).muted = muted;
+        self.update_gains();
+    }
+
+    pub fn set_solo(&mut self, channel: MixerChannel, solo: bool) {
+        self.control_mut(channel).solo = solo;
+        self.update_gains();
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume;
+    }
+
+    pub fn master_volume(&self) -> f32 {
+        self.master_volume
+    }
+
+    // The gains for the mapper's expansion audio channels, when they
+    // changed since the last call
+    pub fn take_expansion_gains(&mut self) -> Option<Vec<f32>> {
+        self.expansion_gains.take()
+    }
+
+    fn control_mut(&mut self, channel: MixerChannel) -> &mut ChannelControl {
+        match channel {
+            MixerChannel::Apu(channel) => &mut self.controls[channel as usize],
+            MixerChannel::Expansion(index) => &mut self.expansion_controls[index],
+        }
+    }
+
+    // A soloed expansion channel keeps the expansion audio as a whole
+    // audible, and soloing the whole of it keeps all of its channels
+    fn update_gains(&mut self) {
+        let expansion = Channel::Expansion as usize;
+        let expansion_solo = self.expansion_controls.iter().any(|control| control.solo);
+        let any_solo = self.controls.iter().any(|control| control.solo) || expansion_solo;
+
+        let gain = |control: &ChannelControl, soloed: bool| {
+            if control.muted || (any_solo && !soloed) {
+                0.0
+            } else {
+                control.volume
+            }
+        };
+
+        for (n, control) in self.controls.iter().enumerate() {
+            let soloed = control.solo || (n == expansion && expansion_solo);
+            self.gains[n] = gain(control, soloed);
+        }
+        let group_solo = self.controls[expansion].solo;
+        self.expansion_gains = Some(
+            self.expansion_controls
+                .iter()
+                .map(|control| gain(control, control.solo || group_solo))
+                .collect(),
+        );
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        let register = addr & 0x03;
+
+        match addr {
+            0x4000..=0x4003 => self.pulse_1.write(register, data),
+            0x4004..=0x4007 => self.pulse_2.write(register, data),
+            0x4008..=0x400b => self.triangle.write(register, data),
+            0x400c..=0x400f => self
+                .noise
+                .write(register, data, self.region.noise_periods()),
+            0x4010..=0x4013 => self.dmc.write(register, data, self.region.dmc_rates()),
+            0x4015 => {
+                self.pulse_1.length.set_enabled(data & 0x01 != 0);
+                self.pulse_2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            0x4017 => {
+                // The pulse timers tick on even cycles, which are the APU's
+                let apu_cycle = self.cycle.is_multiple_of(2);
+                self.frame_counter.write(data, apu_cycle);
+            }
+            _ => {}
+        }
+    }
+
+    // $4015 reads: which channels still have a note or sample playing and
+    // the two IRQs, of which reading acknowledges the frame one. Bit 5 isn't
+    // driven and comes from the open bus.
+    pub fn read_status(&mut self) -> u8 {
+        let status = (self.pulse_1.length.active() as u8)
+            | (self.pulse_2.length.active() as u8) << 1
+            | (self.triangle.length.active() as u8) << 2
+            | (self.noise.length.active() as u8) << 3
+            | (self.dmc.active() as u8) << 4
+            | (self.frame_counter.irq as u8) << 6
+            | (self.dmc.irq as u8) << 7;
+
+        self.frame_counter.irq = false;
+        status
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq || self.dmc.irq
+    }
+
+    // The address the DMC wants its next sample byte from. The CPU fetches
+    // it and passes it to dmc_fill.
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    // Moves every channel forward by one CPU cycle
+    pub fn clock(&mut self) {
+        self.cycle += 1;
+
+        let frame = self.frame_counter.clock(self.region);
+        if frame.quarter {
+            self.quarter_frame();
+        }
+        if frame.half {
+            self.half_frame();
+        }
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle.is_multiple_of(2) {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+
+        let level = self.output();
+        self.resampler.set_level(self.block_cycles, level);
+        if !self.stems.is_empty() {
+            let levels = Channel::ALL.map(|channel| self.channel_output(channel));
+            for (stem, level) in self.stems.iter_mut().zip(levels) {
+                stem.set_level(self.block_cycles, level);
+            }
+        }
+
+        self.block_cycles += 1;
+        if self.block_cycles == BLOCK_CYCLES {
+            self.end_block();
+        }
+    }
+
+    fn end_block(&mut self) {
+        self.resampler.end_block(self.block_cycles);
+        for stem in &mut self.stems {
+            stem.end_block(self.block_cycles);
+        }
+        self.block_cycles = 0;
+    }
+
+    // Envelopes and the triangle's linear counter
+    fn quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    // Length counters and sweeps
+    fn half_frame(&mut self) {
+        self.pulse_1.length.clock();
+        self.pulse_2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    // The level coming out of the 2A03's DACs plus the cartridge's, before
+    // any filtering. The mixer controls scale what goes into the DACs, so
+    // the channels left playing still mix the way the console's do.
+    pub fn output(&self) -> f32 {
+        let [pulse_1, pulse_2, triangle, noise, dmc, expansion] = self.gains;
+        let pulse = self.pulse_1.output() as f32 * pulse_1 + self.pulse_2.output() as f32 * pulse_2;
+        let triangle = self.triangle.output() as f32 * triangle;
+        let noise = self.noise.output() as f32 * noise;
+        let dmc = self.dmc.output() as f32 * dmc;
+
+        let mix = mixer::pulse_level(pulse)
+            + mixer::tnd_level(triangle, noise, dmc)
+            + self.expansion * expansion;
+        mix * self.master_volume
+    }
+
+    // What a channel would put out if it played alone
+    pub fn channel_output(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::Pulse1 => mixer::pulse_level(self.pulse_1.output() as f32),
+            Channel::Pulse2 => mixer::pulse_level(self.pulse_2.output() as f32),
+            Channel::Triangle => mixer::tnd_level(self.triangle.output() as f32, 0.0, 0.0),
+            Channel::Noise => mixer::tnd_level(0.0, self.noise.output() as f32, 0.0),
+            Channel::Dmc => mixer::tnd_level(0.0, 0.0, self.dmc.output() as f32),
+            Channel::Expansion => self.expansion,
+        }
+    }
+
+    // The samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.end_block();
+        self.resampler.take_samples()
+    }
+
+    // The samples of each stem, in Channel::ALL order, up to the last
+    // take_samples call
+    pub fn take_stem_samples(&mut self) -> Vec<Vec<f32>> {
+        self.stems
+            .iter_mut()
+            .map(|stem| stem.take_samples())
+            .collect()
+    }
+}
+
+// Converts samples to 16 bit PCM, clipping anything past full scale
+pub fn to_i16(samples: &[f32]) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_follows_length_counters() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x0f);
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x400b, 0x08);
+        assert_eq!(apu.read_status(), 0x05);
+
+        // A length of 2 half frames
+        apu.write_register(0x400f, 0x18);
+        assert_eq!(apu.read_status(), 0x0d);
+        apu.half_frame();
+        apu.half_frame();
+        assert_eq!(apu.read_status(), 0x05);
+
+        apu.write_register(0x4015, 0x01);
+        assert_eq!(apu.read_status(), 0x01);
+        // Loading a disabled channel does nothing
+        apu.write_register(0x4007, 0x08);
+        assert_eq!(apu.read_status(), 0x01);
+    }
+
+    #[test]
+    fn test_frame_irq_and_acknowledge() {
+        let mut apu = Apu::new(Region::Ntsc);
+        for _ in 0..29833 {
+            apu.clock();
+        }
+        assert!(apu.irq());
+
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0);
+    }
+
+    #[test]
+    fn test_frame_counter_clocks_length_counters() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x01);
+        // Inhibit the IRQ and use the 5 step mode, whose first clock comes
+        // as soon as the write takes effect
+        apu.write_register(0x4017, 0xc0);
+        // A length of 2 half frames
+        apu.write_register(0x4003, 0x18);
+        for _ in 0..4 {
+            apu.clock();
+        }
+        assert_eq!(apu.read_status(), 0x01);
+
+        for _ in 0..14913 {
+            apu.clock();
+        }
+        assert_eq!(apu.read_status(), 0x00);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_samples_at_output_rate() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_sample_rate(48_000);
+        for _ in 0..Region::Ntsc.cpu_clock_hz() as u32 {
+            apu.clock();
+        }
+
+        let samples = apu.take_samples();
+        assert!((47_999..=48_001).contains(&samples.len()));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_pulse_output() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x01);
+        // 75% duty, constant volume 15
+        apu.write_register(0x4000, 0xff);
+        apu.write_register(0x4002, 0x20);
+        apu.write_register(0x4003, 0x08);
+
+        // The silent triangle holds its level, so only look at the swing
+        let (mut low, mut high) = (f32::MAX, f32::MIN);
+        for _ in 0..200 {
+            apu.clock();
+            low = low.min(apu.output());
+            high = high.max(apu.output());
+        }
+        assert!((high - low - mixer::pulse_level(15.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_stems_follow_the_mix() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_stems(true);
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0xbf);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0x08);
+        for _ in 0..20_000 {
+            apu.clock();
+        }
+
+        let mix = apu.take_samples();
+        let stems = apu.take_stem_samples();
+        assert_eq!(stems.len(), Channel::ALL.len());
+        for stem in &stems {
+            assert_eq!(stem.len(), mix.len());
+        }
+        // The pulse and triangle go through separate networks, so with the
+        // rest silent their stems add up to the mix
+        for n in 0..mix.len() {
+            assert!((stems[0][n] + stems[2][n] - mix[n]).abs() < 1e-4);
+        }
+        assert!(stems[1].iter().all(|s| *s == 0.0));
+
+        apu.set_stems(false);
+        assert!(apu.take_stem_samples().is_empty());
+    }
+
+    #[test]
+    fn test_mute_solo_and_volume() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x03);
+        // Both pulses in step at 50% duty and constant volume 15, run
+        // until they're high
+        apu.write_register(0x4000, 0xbf);
+        apu.write_register(0x4004, 0xbf);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4006, 0xfd);
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x4007, 0x08);
+        while apu.channel_output(Channel::Pulse1) == 0.0 {
+            apu.clock();
+        }
+        // The silent triangle holds a level, leave it out
+        let triangle = MixerChannel::Apu(Channel::Triangle);
+        apu.set_muted(triangle, true);
+        let both = apu.output();
+
+        let pulse_1 = MixerChannel::Apu(Channel::Pulse1);
+        let pulse_2 = apu.find_channel("pulse2").unwrap();
+        apu.set_muted(pulse_1, true);
+        assert!((apu.output() - mixer::pulse_level(15.0)).abs() < 1e-6);
+        assert!(apu.control(pulse_1).muted);
+
+        // Soloing overrides what isn't soloed, but not a mute
+        apu.set_muted(pulse_1, false);
+        apu.set_solo(MixerChannel::Apu(Channel::Noise), true);
+        assert_eq!(apu.output(), 0.0);
+        apu.set_solo(pulse_2, true);
+        apu.set_volume(pulse_2, 0.5);
+        assert!((apu.output() - mixer::pulse_level(7.5)).abs() < 1e-6);
+        apu.set_solo(pulse_2, false);
+        apu.set_solo(MixerChannel::Apu(Channel::Noise), false);
+
+        apu.set_volume(pulse_2, 1.0);
+        apu.set_master_volume(0.25);
+        assert!((apu.output() - both * 0.25).abs() < 1e-6);
+        // The channels themselves are left alone
+        assert_eq!(apu.read_status() & 0x03, 0x03);
+    }
+
+    #[test]
+    fn test_expansion_channel_gains() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_expansion_channels(vec!["vrc6-pulse1", "vrc6-pulse2", "vrc6-sawtooth"]);
+        assert_eq!(apu.take_expansion_gains(), Some(vec![1.0; 3]));
+        assert_eq!(apu.take_expansion_gains(), None);
+        assert_eq!(
+            apu.find_channel("vrc6-sawtooth"),
+            Some(MixerChannel::Expansion(2))
+        );
+        assert_eq!(apu.find_channel("fds"), None);
+
+        // Soloing one expansion channel silences the APU and the others
+        apu.set_expansion_output(0.1);
+        apu.set_solo(MixerChannel::Expansion(2), true);
+        apu.set_volume(MixerChannel::Expansion(0), 0.5);
+        assert_eq!(apu.take_expansion_gains(), Some(vec![0.0, 0.0, 1.0]));
+        assert!((apu.output() - 0.1).abs() < 1e-6);
+
+        apu.set_solo(MixerChannel::Expansion(2), false);
+        apu.set_solo(MixerChannel::Apu(Channel::Expansion), true);
+        assert_eq!(apu.take_expansion_gains(), Some(vec![0.5, 1.0, 1.0]));
+        apu.set_muted(MixerChannel::Apu(Channel::Expansion), true);
+        assert_eq!(apu.output(), 0.0);
+    }
+
+    #[test]
+    fn test_expansion_audio_is_mixed() {
+        let mut apu = Apu::new(Region::Ntsc);
+        let silent = apu.output();
+        apu.set_expansion_output(0.1);
+        assert!((apu.output() - silent - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_square_wave_comes_out_filtered() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0xbf);
+        // Around 440 Hz
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0x08);
+        for _ in 0..Region::Ntsc.cpu_clock_hz() as u32 / 10 {
+            apu.clock();
+        }
+
+        let samples = apu.take_samples();
+        let settled = &samples[samples.len() / 2..];
+        let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+        let peak = settled.iter().fold(0.0, |peak: f32, s| peak.max(s.abs()));
+
+        // The high-pass filters centre the wave on 0
+        assert!(mean.abs() < 0.01);
+        assert!(peak > 0.05 && peak < 0.2);
+        assert!(to_i16(&[peak])[0] > 1000);
+    }
+}
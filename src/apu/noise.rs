@@ -0,0 +1,108 @@
+use super::envelope::{Envelope, LengthCounter};
+
+// The noise channel at $400C-$400F: a 15 bit linear feedback shift
+// register whose feedback taps bit 1, or bit 6 in the short mode that
+// repeats after 93 or 31 steps for a metallic tone.
+
+pub struct Noise {
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            short_mode: false,
+            period: 0,
+            timer: 0,
+            // The register comes up as 1 at power on
+            shift: 1,
+        }
+    }
+
+    // Takes the timer periods of the console's region, in CPU cycles
+    pub fn write(&mut self, register: u16, data: u8, periods: &[u16; 16]) {
+        match register {
+            0 => {
+                self.envelope.write(data);
+                self.length.halt = self.envelope.looping;
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.period = periods[(data & 0x0f) as usize];
+            }
+            _ => {
+                self.length.load(data);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle, which is what the period table counts in
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period.saturating_sub(1);
+
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.shift & 0x01 != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PERIODS: [u16; 16] = [1; 16];
+
+    fn sequence_length(mode: u8) -> usize {
+        let mut noise = Noise::new();
+        noise.write(2, mode, &PERIODS);
+
+        let start = noise.shift;
+        for n in 1..=0x8000 {
+            noise.clock_timer();
+            if noise.shift == start {
+                return n;
+            }
+        }
+        panic!("the shift register never repeated");
+    }
+
+    #[test]
+    fn test_lfsr_periods() {
+        assert_eq!(sequence_length(0x00), 32767);
+        assert_eq!(sequence_length(0x80), 93);
+    }
+
+    #[test]
+    fn test_output_follows_bit_0() {
+        let mut noise = Noise::new();
+        noise.length.set_enabled(true);
+        noise.write(0, 0x1c, &PERIODS);
+        noise.write(3, 0x08, &PERIODS);
+        assert_eq!(noise.output(), 0);
+
+        // 1 -> $4000, which has bit 0 clear
+        noise.clock_timer();
+        assert_eq!(noise.output(), 12);
+    }
+}
@@ -0,0 +1,182 @@
+use super::envelope::{Envelope, LengthCounter};
+
+// One of the two square wave channels at $4000-$4003 and $4004-$4007. The
+// sweep unit bends the period up or down every few half frames, and the
+// first channel negates with one's complement, so it always ends up one
+// lower than the second for the same settings.
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+pub struct Pulse {
+    // Pulse 1 subtracts an extra 1 when sweeping down
+    ones_complement: bool,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    sweep: Sweep,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Self {
+            ones_complement,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            sweep: Sweep::default(),
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.envelope.write(data);
+                self.length.halt = self.envelope.looping;
+            }
+            1 => {
+                self.sweep.enabled = data & 0x80 != 0;
+                self.sweep.period = (data >> 4) & 0x07;
+                self.sweep.negate = data & 0x08 != 0;
+                self.sweep.shift = data & 0x07;
+                self.sweep.reload = true;
+            }
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | (data as u16 & 0x07) << 8;
+                self.length.load(data);
+                self.envelope.start = true;
+                self.step = 0;
+            }
+        }
+    }
+
+    // Clocked every APU cycle, every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // The period the sweep unit is aiming for, which mutes the channel when
+    // it goes past $7FF even with the sweep disabled
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep.shift;
+
+        if self.sweep.negate {
+            let extra = self.ones_complement as u16;
+            self.period.saturating_sub(change + extra)
+        } else {
+            self.period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < 8 || self.target_period() > 0x7ff
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.muted() {
+            self.period = self.target_period();
+        }
+
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.muted()
+            || !self.length.active()
+            || DUTY_CYCLES[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pulse_channel(ones_complement: bool, period: u16) -> Pulse {
+        let mut pulse = Pulse::new(ones_complement);
+        pulse.length.set_enabled(true);
+        // 50% duty, constant volume 9
+        pulse.write(0, 0xb9);
+        pulse.write(2, period as u8);
+        pulse.write(3, 0x08 | (period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn test_duty_cycle_output() {
+        let mut pulse = pulse_channel(false, 0x100);
+        let mut levels = Vec::new();
+        for _ in 0..8 {
+            levels.push(pulse.output());
+            for _ in 0..=0x100 {
+                pulse.clock_timer();
+            }
+        }
+
+        assert_eq!(levels, [0, 9, 9, 9, 9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_sweep_negate_differs_between_channels() {
+        for (ones_complement, expected) in [(true, 0x100 - 0x40 - 1), (false, 0x100 - 0x40)] {
+            let mut pulse = pulse_channel(ones_complement, 0x100);
+            // Enabled, divider period 0, negate, shift 2
+            pulse.write(1, 0x8a);
+            pulse.clock_sweep();
+
+            assert_eq!(pulse.period, expected);
+        }
+    }
+
+    #[test]
+    fn test_sweep_muting() {
+        // A target above $7FF mutes the channel even with the sweep off
+        let mut pulse = pulse_channel(false, 0x600);
+        pulse.write(1, 0x01);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 0);
+        pulse.write(1, 0x08);
+        assert_eq!(pulse.output(), 9);
+
+        // So do periods under 8
+        let mut pulse = pulse_channel(false, 7);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 0);
+    }
+}
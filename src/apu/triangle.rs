@@ -0,0 +1,120 @@
+use super::envelope::LengthCounter;
+
+// The triangle channel at $4008-$400B. It has no volume control, only a
+// 32 step ramp that stops wherever it is when either the length counter or
+// the finer grained linear counter runs out.
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+#[derive(Default)]
+pub struct Triangle {
+    pub length: LengthCounter,
+    // Also halts the length counter
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Triangle {
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7f;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | (data as u16 & 0x07) << 8;
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Unlike the other channels the triangle's timer runs at the full CPU
+    // clock
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.linear_counter > 0 && self.length.active() {
+                self.step = (self.step + 1) & 0x1f;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked every quarter frame
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // Silencing the channel freezes the ramp rather than dropping it to 0
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(triangle: &mut Triangle, steps: usize) {
+        for _ in 0..steps * (triangle.period as usize + 1) {
+            triangle.clock_timer();
+        }
+    }
+
+    #[test]
+    fn test_linear_counter_gates_the_sequencer() {
+        let mut triangle = Triangle::default();
+        triangle.length.set_enabled(true);
+        // Linear counter of 2, control clear
+        triangle.write(0, 0x02);
+        triangle.write(2, 0x10);
+        triangle.write(3, 0x08);
+
+        // Nothing moves until the first quarter frame loads the counter
+        run(&mut triangle, 4);
+        assert_eq!(triangle.output(), 15);
+
+        triangle.clock_linear_counter();
+        run(&mut triangle, 4);
+        assert_eq!(triangle.output(), 11);
+
+        triangle.clock_linear_counter();
+        triangle.clock_linear_counter();
+        run(&mut triangle, 4);
+        assert_eq!(triangle.output(), 11);
+    }
+
+    #[test]
+    fn test_control_keeps_reloading() {
+        let mut triangle = Triangle::default();
+        triangle.length.set_enabled(true);
+        triangle.write(0, 0x81);
+        triangle.write(3, 0x08);
+
+        for _ in 0..10 {
+            triangle.clock_linear_counter();
+        }
+        assert_eq!(triangle.linear_counter, 1);
+    }
+}
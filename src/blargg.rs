@@ -0,0 +1,188 @@
+use crate::cpu::CPU;
+use crate::headless::Headless;
+
+// Reads the results of blargg's test ROMs. Once a test is up it writes the
+// $DE $B0 $61 signature to $6001-$6003, keeps $6000 at $80 while it runs and
+// finally stores the result code there, with a zero terminated message
+// starting at $6004. A status of $81 asks for the reset button to be pressed.
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const MESSAGE: u16 = 0x6004;
+const SIGNATURE_BYTES: [u8; 3] = [0xde, 0xb0, 0x61];
+
+const RUNNING: u8 = 0x80;
+const RESET_REQUIRED: u8 = 0x81;
+
+// The ROMs want the reset to come at least 100ms after asking for it
+const RESET_DELAY_FRAMES: u32 = 6;
+
+// The message lives in cartridge RAM, this is only a guard against garbage
+const MAX_MESSAGE_LEN: u16 = 0x1000;
+
+#[derive(Debug, PartialEq)]
+pub struct BlarggResult {
+    pub code: u8,
+    pub message: String,
+}
+
+impl BlarggResult {
+    pub fn passed(&self) -> bool {
+        self.code == 0
+    }
+}
+
+pub struct BlarggReader {
+    reset_countdown: Option<u32>,
+    // Set after a reset until the ROM moves the status away from $81, so the
+    // stale value doesn't trigger another one
+    reset_done: bool,
+}
+
+impl BlarggReader {
+    pub fn new() -> Self {
+        Self {
+            reset_countdown: None,
+            reset_done: false,
+        }
+    }
+
+    // Checks the status once, meant to be called after every frame. Returns
+    // the result once the test has finished.
+    pub fn poll(&mut self, cpu: &mut CPU) -> Option<BlarggResult> {
+        // Peeking keeps the polling from touching the open bus or anything
+        // the mapper watches for between frames
+        let signature = [
+            cpu.peek(SIGNATURE),
+            cpu.peek(SIGNATURE + 1),
+            cpu.peek(SIGNATURE + 2),
+        ];
+        if signature != SIGNATURE_BYTES {
+            return None;
+        }
+
+        match cpu.peek(STATUS) {
+            RUNNING => {
+                self.reset_done = false;
+                None
+            }
+            RESET_REQUIRED => {
+                if self.reset_done {
+                    return None;
+                }
+
+                match self.reset_countdown {
+                    None => self.reset_countdown = Some(RESET_DELAY_FRAMES),
+                    Some(0) => {
+                        cpu.soft_reset();
+                        self.reset_countdown = None;
+                        self.reset_done = true;
+                    }
+                    Some(frames) => self.reset_countdown = Some(frames - 1),
+                }
+                None
+            }
+            code => Some(BlarggResult {
+                code,
+                message: read_message(cpu),
+            }),
+        }
+    }
+}
+
+fn read_message(cpu: &CPU) -> String {
+    let mut bytes = Vec::new();
+
+    for address in MESSAGE..MESSAGE + MAX_MESSAGE_LEN {
+        let byte = cpu.peek(address);
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+    }
+
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+// Runs a test ROM until it reports a result or the frame limit is hit
+pub fn run_test(console: &mut Headless, max_frames: u64) -> Result<BlarggResult, String> {
+    let mut reader = BlarggReader::new();
+
+    for _ in 0..max_frames {
+        console.run_frame()?;
+
+        if let Some(result) = reader.poll(&mut console.cpu) {
+            return Ok(result);
+        }
+    }
+
+    Err(format!("test didn't finish within {} frames", max_frames))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_status(cpu: &mut CPU, status: u8, message: &str) {
+        cpu.mem_write(STATUS, status);
+        for (n, byte) in SIGNATURE_BYTES.iter().enumerate() {
+            cpu.mem_write(SIGNATURE + n as u16, *byte);
+        }
+        for (n, byte) in message.bytes().chain([0]).enumerate() {
+            cpu.mem_write(MESSAGE + n as u16, byte);
+        }
+    }
+
+    #[test]
+    fn test_waits_for_signature() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(STATUS, 0x00);
+
+        assert_eq!(BlarggReader::new().poll(&mut cpu), None);
+    }
+
+    #[test]
+    fn test_running_then_result() {
+        let mut cpu = CPU::new();
+        let mut reader = BlarggReader::new();
+
+        write_status(&mut cpu, RUNNING, "");
+        assert_eq!(reader.poll(&mut cpu), None);
+
+        write_status(&mut cpu, 0x03, "Failed #3\n");
+        let result = reader.poll(&mut cpu).unwrap();
+        assert_eq!(result.code, 3);
+        assert_eq!(result.message, "Failed #3");
+        assert!(!result.passed());
+    }
+
+    #[test]
+    fn test_reset_required() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0x69, 0x01, 0x00]);
+        cpu.reset();
+        let mut reader = BlarggReader::new();
+
+        write_status(&mut cpu, RESET_REQUIRED, "");
+        cpu.pc = 0x1234;
+        cpu.acc_reg = 0x42;
+        for _ in 0..=RESET_DELAY_FRAMES {
+            assert_eq!(reader.poll(&mut cpu), None);
+            assert_eq!(cpu.pc, 0x1234);
+        }
+
+        // The reset happens once the delay is over, and only once. It's the
+        // reset button, which leaves the registers alone
+        assert_eq!(reader.poll(&mut cpu), None);
+        assert_eq!(cpu.pc, 0x8000);
+        assert_eq!(cpu.acc_reg, 0x42);
+        cpu.pc = 0x1234;
+        for _ in 0..2 * RESET_DELAY_FRAMES {
+            reader.poll(&mut cpu);
+        }
+        assert_eq!(cpu.pc, 0x1234);
+
+        write_status(&mut cpu, 0x00, "Passed");
+        assert!(reader.poll(&mut cpu).unwrap().passed());
+    }
+}
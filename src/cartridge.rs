@@ -0,0 +1,631 @@
+use std::fmt;
+
+use crate::mapper::{
+    Axrom, BandaiFcg, Cnrom, Fme7, Mapper, Mirroring, Mmc1, Mmc3, Mmc5, Namco163, Nrom, Uxrom,
+    Vrc4, Vrc6, Vrc7,
+};
+use crate::region::Region;
+
+// The contents of a ROM file, before it gets turned into a mapper. The
+// loader understands iNES, its NES 2.0 extension and UNIF.
+pub struct Cartridge {
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub trainer: Option<Vec<u8>>,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Region,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RomError {
+    UnknownFormat,
+    Truncated,
+    UnsupportedMapper(u16),
+    MissingChunk(&'static str),
+    UnsupportedBoard(String),
+    BadRomSize,
+    NoPrgRom,
+    BadChunk(String),
+    BadPrgSize(usize),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::UnknownFormat => write!(f, "not an iNES, NES 2.0 or UNIF file"),
+            RomError::Truncated => write!(f, "the file is shorter than its header says"),
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} isn't supported", mapper),
+            RomError::MissingChunk(id) => write!(f, "the UNIF file has no {} chunk", id),
+            RomError::UnsupportedBoard(board) => write!(f, "UNIF board {} isn't supported", board),
+            RomError::BadRomSize => write!(f, "the header gives a ROM size that can't exist"),
+            RomError::NoPrgRom => write!(f, "the file has no PRG-ROM"),
+            RomError::BadChunk(id) => write!(f, "the UNIF chunk {} has no index from 0 to F", id),
+            RomError::BadPrgSize(size) => {
+                write!(
+                    f,
+                    "{} bytes of PRG-ROM aren't a whole number of 8K banks",
+                    size
+                )
+            }
+        }
+    }
+}
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const UNIF_HEADER_SIZE: usize = 32;
+
+// What a UNIF board name stands for: the iNES mapper running it and the
+// PRG-RAM the board carries, which UNIF files don't record
+struct UnifBoard {
+    mapper: u16,
+    submapper: u8,
+    prg_ram_size: usize,
+}
+
+const fn board(mapper: u16, submapper: u8, prg_ram_size: usize) -> UnifBoard {
+    UnifBoard {
+        mapper,
+        submapper,
+        prg_ram_size,
+    }
+}
+
+// Board names without their NES-/HVC-/UNL- style prefix
+const UNIF_BOARDS: &[(&str, UnifBoard)] = &[
+    ("NROM", board(0, 0, 0)),
+    ("NROM-128", board(0, 0, 0)),
+    ("NROM-256", board(0, 0, 0)),
+    ("SAROM", board(1, 0, 0x2000)),
+    ("SBROM", board(1, 0, 0)),
+    ("SCROM", board(1, 0, 0)),
+    ("SEROM", board(1, 0, 0)),
+    ("SGROM", board(1, 0, 0)),
+    ("SKROM", board(1, 0, 0x2000)),
+    ("SLROM", board(1, 0, 0)),
+    ("SL1ROM", board(1, 0, 0)),
+    ("SNROM", board(1, 0, 0x2000)),
+    ("SOROM", board(1, 0, 0x4000)),
+    ("SUROM", board(1, 0, 0x2000)),
+    ("SXROM", board(1, 0, 0x8000)),
+    ("UNROM", board(2, 2, 0)),
+    ("UOROM", board(2, 2, 0)),
+    ("CNROM", board(3, 2, 0)),
+    ("TBROM", board(4, 0, 0)),
+    ("TEROM", board(4, 0, 0)),
+    ("TFROM", board(4, 0, 0)),
+    ("TGROM", board(4, 0, 0)),
+    ("TKROM", board(4, 0, 0x2000)),
+    ("TLROM", board(4, 0, 0)),
+    ("TL1ROM", board(4, 0, 0)),
+    ("TNROM", board(4, 0, 0x2000)),
+    ("TSROM", board(4, 0, 0x2000)),
+    ("TVROM", board(4, 0, 0)),
+    ("EKROM", board(5, 0, 0x2000)),
+    ("ELROM", board(5, 0, 0)),
+    ("ETROM", board(5, 0, 0x4000)),
+    ("EWROM", board(5, 0, 0x8000)),
+    ("AMROM", board(7, 2, 0)),
+    ("ANROM", board(7, 1, 0)),
+    ("AOROM", board(7, 0, 0)),
+];
+
+fn unif_board(name: &str) -> Option<&'static UnifBoard> {
+    let name = name.trim_end_matches('\0').trim();
+    let name = match name.split_once('-') {
+        Some((prefix, rest)) if ["NES", "HVC", "UNL", "BTL", "BMC", "IREM"].contains(&prefix) => {
+            rest
+        }
+        _ => name,
+    };
+
+    UNIF_BOARDS
+        .iter()
+        .find(|(board, _)| board.eq_ignore_ascii_case(name))
+        .map(|(_, board)| board)
+}
+
+impl Cartridge {
+    pub fn is_ines(bytes: &[u8]) -> bool {
+        bytes.len() >= HEADER_SIZE && bytes[0..4] == *b"NES\x1a"
+    }
+
+    pub fn from_ines(bytes: &[u8]) -> Result<Cartridge, RomError> {
+        if !Cartridge::is_ines(bytes) {
+            return Err(RomError::UnknownFormat);
+        }
+
+        let header = &bytes[0..HEADER_SIZE];
+        let nes2 = (header[7] & 0x0c) == 0x08;
+
+        let mut mapper = ((header[6] >> 4) | (header[7] & 0xf0)) as u16;
+        let mut submapper = 0;
+        let prg_rom_size;
+        let chr_rom_size;
+        let prg_ram_size;
+        let prg_nvram_size;
+        let chr_ram_size;
+
+        if nes2 {
+            mapper |= ((header[8] & 0x0f) as u16) << 8;
+            submapper = header[8] >> 4;
+            prg_rom_size = nes2_rom_size(header[4], header[9] & 0x0f, 0x4000)?;
+            chr_rom_size = nes2_rom_size(header[5], header[9] >> 4, 0x2000)?;
+            prg_ram_size = nes2_ram_size(header[10] & 0x0f);
+            prg_nvram_size = nes2_ram_size(header[10] >> 4);
+            chr_ram_size = nes2_ram_size(header[11] & 0x0f);
+        } else {
+            prg_rom_size = header[4] as usize * 0x4000;
+            chr_rom_size = header[5] as usize * 0x2000;
+            // A zero here means the 8K everyone assumed before the field
+            // existed
+            let ram = (header[8].max(1)) as usize * 0x2000;
+            if header[6] & 0x02 != 0 {
+                prg_ram_size = 0;
+                prg_nvram_size = ram;
+            } else {
+                prg_ram_size = ram;
+                prg_nvram_size = 0;
+            }
+            chr_ram_size = if chr_rom_size == 0 { 0x2000 } else { 0 };
+        }
+
+        let mirroring = if header[6] & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if header[6] & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut offset = HEADER_SIZE;
+        let trainer = if header[6] & 0x04 != 0 {
+            let trainer = take(bytes, &mut offset, TRAINER_SIZE)?;
+            Some(trainer)
+        } else {
+            None
+        };
+        let prg_rom = take(bytes, &mut offset, prg_rom_size)?;
+        let chr_rom = take(bytes, &mut offset, chr_rom_size)?;
+        // Every mapper has its fixed banks at the end of the PRG-ROM
+        if prg_rom.is_empty() {
+            return Err(RomError::NoPrgRom);
+        }
+        check_prg_size(&prg_rom)?;
+
+        Ok(Cartridge {
+            mapper,
+            submapper,
+            prg_rom,
+            chr_rom,
+            trainer,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            mirroring,
+            battery: header[6] & 0x02 != 0,
+            region: Region::detect(header),
+        })
+    }
+
+    pub fn is_unif(bytes: &[u8]) -> bool {
+        bytes.len() >= UNIF_HEADER_SIZE && bytes[0..4] == *b"UNIF"
+    }
+
+    // UNIF files are a 32 byte header followed by chunks of a 4 character
+    // id, a little endian length and the data. The board name stands in for
+    // the mapper number and the ROM comes in numbered pieces that get joined
+    // in order.
+    pub fn from_unif(bytes: &[u8]) -> Result<Cartridge, RomError> {
+        if !Cartridge::is_unif(bytes) {
+            return Err(RomError::UnknownFormat);
+        }
+
+        let mut board_name = None;
+        let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut mirroring = Mirroring::Horizontal;
+        let mut battery = false;
+        let mut region = Region::Ntsc;
+
+        let mut offset = UNIF_HEADER_SIZE;
+        while offset < bytes.len() {
+            let header = bytes.get(offset..offset + 8).ok_or(RomError::Truncated)?;
+            let id = &header[0..4];
+            let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            let data = bytes
+                .get(offset + 8..offset + 8 + len)
+                .ok_or(RomError::Truncated)?;
+            offset += 8 + len;
+
+            // PRG0-PRGF and CHR0-CHRF, anything else would land on the
+            // wrong chunk
+            let index = || {
+                (id[3] as char)
+                    .to_digit(16)
+                    .map(|index| index as usize)
+                    .ok_or_else(|| RomError::BadChunk(String::from_utf8_lossy(id).into_owned()))
+            };
+            match id {
+                b"MAPR" => board_name = Some(String::from_utf8_lossy(data).into_owned()),
+                [b'P', b'R', b'G', _] => prg_chunks[index()?] = Some(data),
+                [b'C', b'H', b'R', _] => chr_chunks[index()?] = Some(data),
+                b"MIRR" => {
+                    mirroring = match data.first() {
+                        Some(1) => Mirroring::Vertical,
+                        Some(2) => Mirroring::SingleScreenLower,
+                        Some(3) => Mirroring::SingleScreenUpper,
+                        Some(4) => Mirroring::FourScreen,
+                        // 0 and the mapper controlled 5
+                        _ => Mirroring::Horizontal,
+                    }
+                }
+                b"BATR" => battery = true,
+                b"TVCI" if data.first() == Some(&1) => region = Region::Pal,
+                // Names, dumper info, checksums and the like
+                _ => {}
+            }
+        }
+
+        let board_name = board_name.ok_or(RomError::MissingChunk("MAPR"))?;
+        let board = unif_board(&board_name).ok_or_else(|| {
+            RomError::UnsupportedBoard(board_name.trim_end_matches('\0').to_string())
+        })?;
+
+        let prg_rom: Vec<u8> = prg_chunks
+            .iter()
+            .flatten()
+            .flat_map(|chunk| chunk.iter())
+            .copied()
+            .collect();
+        let chr_rom: Vec<u8> = chr_chunks
+            .iter()
+            .flatten()
+            .flat_map(|chunk| chunk.iter())
+            .copied()
+            .collect();
+        if prg_rom.is_empty() {
+            return Err(RomError::MissingChunk("PRG0"));
+        }
+        check_prg_size(&prg_rom)?;
+
+        let (prg_ram_size, prg_nvram_size) = if battery {
+            (0, board.prg_ram_size.max(0x2000))
+        } else {
+            (board.prg_ram_size, 0)
+        };
+
+        Ok(Cartridge {
+            mapper: board.mapper,
+            submapper: board.submapper,
+            chr_ram_size: if chr_rom.is_empty() { 0x2000 } else { 0 },
+            prg_rom,
+            chr_rom,
+            trainer: None,
+            prg_ram_size,
+            prg_nvram_size,
+            mirroring,
+            battery,
+            region,
+        })
+    }
+
+    // A 32K NROM cartridge around a bare program image, with the reset
+    // vector pointing at its start at $8000
+    pub fn from_program(program: &[u8]) -> Cartridge {
+        let mut prg_rom = vec![0; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x7ffc] = 0x00;
+        prg_rom[0x7ffd] = 0x80;
+
+        Cartridge {
+            mapper: 0,
+            submapper: 0,
+            prg_rom,
+            chr_rom: Vec::new(),
+            trainer: None,
+            prg_ram_size: 0x2000,
+            prg_nvram_size: 0,
+            chr_ram_size: 0x2000,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Region::Ntsc,
+        }
+    }
+
+    pub fn create_mapper(&self) -> Result<Box<dyn Mapper>, RomError> {
+        let mut mapper: Box<dyn Mapper> = match self.mapper {
+            0 => Box::new(Nrom::new(self)),
+            1 => Box::new(Mmc1::new(self)),
+            2 => Box::new(Uxrom::new(self)),
+            3 => Box::new(Cnrom::new(self)),
+            4 => Box::new(Mmc3::new(self)),
+            5 => Box::new(Mmc5::new(self)),
+            7 => Box::new(Axrom::new(self)),
+            16 => Box::new(BandaiFcg::new(self)),
+            19 => Box::new(Namco163::new(self)),
+            21 | 22 | 23 | 25 => Box::new(Vrc4::new(self)),
+            24 | 26 => Box::new(Vrc6::new(self)),
+            69 => Box::new(Fme7::new(self)),
+            85 => Box::new(Vrc7::new(self)),
+            mapper => return Err(RomError::UnsupportedMapper(mapper)),
+        };
+
+        // Trainers get copied to $7000 of the PRG-RAM before the game
+        // starts, boards without 8K of it have nowhere to put them
+        if let (Some(trainer), Some(ram)) = (&self.trainer, mapper.save_ram_mut()) {
+            if let Some(target) = ram.get_mut(0x1000..0x1000 + TRAINER_SIZE) {
+                target.copy_from_slice(trainer);
+            }
+        }
+
+        Ok(mapper)
+    }
+}
+
+fn take(bytes: &[u8], offset: &mut usize, len: usize) -> Result<Vec<u8>, RomError> {
+    let end = offset.checked_add(len).ok_or(RomError::Truncated)?;
+    let data = bytes.get(*offset..end).ok_or(RomError::Truncated)?.to_vec();
+    *offset = end;
+
+    Ok(data)
+}
+
+// The mappers switch PRG-ROM in banks of 8K and up, so anything smaller
+// than a bank or in between would have them read past its end. NES 2.0's
+// exponent sizes can describe such a ROM.
+fn check_prg_size(prg_rom: &[u8]) -> Result<(), RomError> {
+    if !prg_rom.len().is_multiple_of(0x2000) {
+        return Err(RomError::BadPrgSize(prg_rom.len()));
+    }
+
+    Ok(())
+}
+
+// NES 2.0 ROM sizes are either a plain count of units or, when the upper
+// nibble is $F, 2^exponent * (multiplier * 2 + 1) bytes. Exponents go up
+// to 63, so a bad header can ask for more than fits in a usize.
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Result<usize, RomError> {
+    if msb == 0x0f {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .ok_or(RomError::BadRomSize)
+    } else {
+        Ok((((msb as usize) << 8) | lsb as usize) * unit)
+    }
+}
+
+// RAM sizes are stored as a shift count of 64 bytes, 0 meaning none
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom(header: [u8; 16], trainer: bool) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        if trainer {
+            bytes.extend(vec![0xee; TRAINER_SIZE]);
+        }
+        bytes.extend(vec![0x11; header[4] as usize * 0x4000]);
+        bytes.extend(vec![0x22; header[5] as usize * 0x2000]);
+        bytes
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let header = [
+            b'N', b'E', b'S', 0x1a, 2, 1, 0x13, 0x00, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let cartridge = Cartridge::from_ines(&rom(header, false)).unwrap();
+
+        assert_eq!(cartridge.mapper, 1);
+        assert_eq!(cartridge.prg_rom.len(), 0x8000);
+        assert_eq!(cartridge.chr_rom.len(), 0x2000);
+        assert_eq!(cartridge.chr_ram_size, 0);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.region, Region::Ntsc);
+    }
+
+    #[test]
+    fn test_ines_trainer_and_chr_ram() {
+        let header = [
+            b'N', b'E', b'S', 0x1a, 1, 0, 0x0c, 0x00, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let cartridge = Cartridge::from_ines(&rom(header, true)).unwrap();
+
+        assert_eq!(cartridge.trainer.as_ref().unwrap()[0], 0xee);
+        let mut mapper = cartridge.create_mapper().unwrap();
+        assert_eq!(mapper.cpu_read(0x6fff), Some(0x00));
+        assert_eq!(mapper.cpu_read(0x7000), Some(0xee));
+        assert_eq!(mapper.cpu_read(0x71ff), Some(0xee));
+        assert_eq!(mapper.cpu_read(0x7200), Some(0x00));
+        assert_eq!(cartridge.prg_rom[0], 0x11);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+        assert_eq!(cartridge.mirroring, Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let header = [
+            b'N', b'E', b'S', 0x1a, 2, 0, 0x40, 0x08, 0x31, 0x00, 0x70, 0x07, 0x01, 0, 0, 0,
+        ];
+        let cartridge = Cartridge::from_ines(&rom(header, false)).unwrap();
+
+        assert_eq!(cartridge.mapper, 0x104);
+        assert_eq!(cartridge.submapper, 3);
+        assert_eq!(cartridge.prg_ram_size, 0);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+        assert_eq!(cartridge.region, Region::Pal);
+    }
+
+    #[test]
+    fn test_nes2_exponent_size() {
+        // 2^4 * 3 bytes
+        assert_eq!(nes2_rom_size(0b0001_0001, 0x0f, 0x4000), Ok(48));
+        assert_eq!(nes2_rom_size(0x02, 0x01, 0x4000), Ok(0x102 * 0x4000));
+        // 2^63 * 7
+        assert_eq!(nes2_rom_size(0xff, 0x0f, 0x4000), Err(RomError::BadRomSize));
+
+        let header = [
+            b'N', b'E', b'S', 0x1a, 0xff, 0xfe, 0, 0x08, 0, 0xff, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            Cartridge::from_ines(&header).err(),
+            Some(RomError::BadRomSize)
+        );
+        // Sizes that do fit but are bigger than the file
+        let header = [
+            b'N', b'E', b'S', 0x1a, 0xfc, 0xfc, 0, 0x08, 0, 0xff, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            Cartridge::from_ines(&header).err(),
+            Some(RomError::Truncated)
+        );
+        // 48 bytes of PRG-ROM fit in the file but not in a bank
+        let mut bytes = vec![
+            b'N', b'E', b'S', 0x1a, 0x11, 0, 0x20, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0,
+        ];
+        bytes.extend([0; 48]);
+        assert_eq!(
+            Cartridge::from_ines(&bytes).err(),
+            Some(RomError::BadPrgSize(48))
+        );
+    }
+
+    fn unif(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut bytes = b"UNIF".to_vec();
+        bytes.extend(7u32.to_le_bytes());
+        bytes.resize(UNIF_HEADER_SIZE, 0);
+        for (id, data) in chunks {
+            bytes.extend(id.iter());
+            bytes.extend((data.len() as u32).to_le_bytes());
+            bytes.extend(data.iter());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_unif() {
+        let bytes = unif(&[
+            (b"NAME", b"Test\0"),
+            (b"MAPR", b"NES-SNROM\0"),
+            (b"PRG1", &[0x22; 0x4000]),
+            (b"PRG0", &[0x11; 0x4000]),
+            (b"MIRR", &[1]),
+            (b"BATR", &[1]),
+            (b"TVCI", &[1]),
+        ]);
+        let cartridge = Cartridge::from_unif(&bytes).unwrap();
+
+        assert_eq!(cartridge.mapper, 1);
+        assert_eq!(cartridge.prg_rom.len(), 0x8000);
+        assert_eq!(cartridge.prg_rom[0x3fff], 0x11);
+        assert_eq!(cartridge.prg_rom[0x4000], 0x22);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.region, Region::Pal);
+        assert!(cartridge.create_mapper().is_ok());
+    }
+
+    #[test]
+    fn test_unif_board_names() {
+        assert_eq!(unif_board("HVC-TLROM").unwrap().mapper, 4);
+        assert_eq!(unif_board("UNL-AMROM").unwrap().submapper, 2);
+        assert_eq!(unif_board("NROM-128\0\0").unwrap().mapper, 0);
+        assert!(unif_board("UNL-Sachen-8259A").is_none());
+    }
+
+    #[test]
+    fn test_unif_errors() {
+        let bytes = unif(&[(b"PRG0", &[0; 0x4000])]);
+        assert_eq!(
+            Cartridge::from_unif(&bytes).err(),
+            Some(RomError::MissingChunk("MAPR"))
+        );
+
+        let bytes = unif(&[(b"MAPR", b"UNL-Sachen-8259A\0"), (b"PRG0", &[0; 0x4000])]);
+        assert_eq!(
+            Cartridge::from_unif(&bytes)
+                .err()
+                .map(|err| err.to_string()),
+            Some("UNIF board UNL-Sachen-8259A isn't supported".to_string())
+        );
+
+        let bytes = unif(&[
+            (b"MAPR", b"NES-NROM\0"),
+            (b"PRG0", &[0; 0x4000]),
+            (b"PRGx", &[0; 0x4000]),
+        ]);
+        assert_eq!(
+            Cartridge::from_unif(&bytes).err(),
+            Some(RomError::BadChunk("PRGx".to_string()))
+        );
+
+        let bytes = unif(&[(b"MAPR", b"NES-SNROM\0"), (b"PRG0", &[0; 0x1000])]);
+        assert_eq!(
+            Cartridge::from_unif(&bytes).err(),
+            Some(RomError::BadPrgSize(0x1000))
+        );
+
+        let mut bytes = unif(&[(b"MAPR", b"NES-NROM\0"), (b"PRG0", &[0; 0x4000])]);
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(
+            Cartridge::from_unif(&bytes).err(),
+            Some(RomError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Cartridge::from_ines(b"not a rom").err(),
+            Some(RomError::UnknownFormat)
+        );
+
+        let header = [b'N', b'E', b'S', 0x1a, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut bytes = rom(header, false);
+        bytes.truncate(0x4000);
+        assert_eq!(
+            Cartridge::from_ines(&bytes).err(),
+            Some(RomError::Truncated)
+        );
+
+        let header = [
+            b'N', b'E', b'S', 0x1a, 0, 1, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            Cartridge::from_ines(&rom(header, false)).err(),
+            Some(RomError::NoPrgRom)
+        );
+
+        let header = [
+            b'N', b'E', b'S', 0x1a, 1, 1, 0xf0, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let cartridge = Cartridge::from_ines(&rom(header, false)).unwrap();
+        assert_eq!(
+            cartridge.create_mapper().err().map(|err| err.to_string()),
+            Some("mapper 255 isn't supported".to_string())
+        );
+    }
+}
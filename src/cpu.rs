@@ -1,16 +1,47 @@
 use std::{collections::HashMap, usize, task::Wake};
 
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::input::ControllerPorts;
+use crate::mapper::Mapper;
+use crate::ppu::Ppu;
+use crate::region::Region;
+
 struct OpsInfo {
     info: HashMap<u8, OpCode>,
 }
 
-struct CPU {
+// Where call() has subroutines return to. Nothing is mapped there, so no
+// real code jumps to it.
+const CALL_RETURN_ADDR: u16 = 0x4100;
+
+pub struct CPU {
     pub acc_reg: u8,
     pub pc: u16,
     pub status: u8,
     pub reg_x: u8,
     pub reg_y: u8,
+    pub stack_ptr: u8,
+    pub region: Region,
+    pub cycles: u64,
+    pub frame_count: u64,
+    pub apu: Apu,
+    pub ppu: Ppu,
+    pub input: ControllerPorts,
     memory: [u8; 0xffff],
+    ops_info: HashMap<u8, OpCode>,
+    mapper: Option<Box<dyn Mapper>>,
+    // Last value seen on the data bus, which is what reads from unmapped
+    // addresses return
+    open_bus: u8,
+    // Set while the CPU is halted for a DMA transfer
+    oam_dma: bool,
+    dmc_dma: bool,
+    // PPU dots owed to the PPU, in fractions of a CPU cycle
+    ppu_clock: u32,
+    // Level of the NMI line at the last instruction, NMIs trigger on its
+    // edges
+    nmi_line: bool,
 }
 
 struct OpCode {
@@ -21,7 +52,7 @@ struct OpCode {
     size: u8,
 }
 
-enum AddressingMode {
+pub enum AddressingMode {
     Implicit,
     Accumulator,
     Immediate,
@@ -48,7 +79,21 @@ impl CPU {
             status: 0,
             reg_x: 0,
             reg_y: 0,
+            stack_ptr: 0xfd,
+            region: Region::Ntsc,
+            cycles: 0,
+            frame_count: 0,
+            apu: Apu::new(Region::Ntsc),
+            ppu: Ppu::new(Region::Ntsc),
+            input: ControllerPorts::new(),
             memory: [0; 0xffff],
+            ops_info: create_ops_info(),
+            mapper: None,
+            open_bus: 0,
+            oam_dma: false,
+            dmc_dma: false,
+            ppu_clock: 0,
+            nmi_line: false,
         }
     }
 
@@ -109,9 +154,26 @@ impl CPU {
         }
     }
 
-    fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x8000..(0x8000 + program.len())].copy_from_slice(&program[..]);
-        self.mem_write_u16(0xfffc, 0x8000);
+    // Loads a bare program at $8000 by wrapping it in an NROM cartridge
+    pub fn load(&mut self, program: Vec<u8>) {
+        let cartridge = Cartridge::from_program(&program);
+        self.insert_cartridge(cartridge.create_mapper().unwrap());
+    }
+
+    pub fn insert_cartridge(&mut self, mapper: Box<dyn Mapper>) {
+        self.apu.set_expansion_channels(mapper.audio_channels());
+        self.mapper = Some(mapper);
+    }
+
+    // Switches the timing of the CPU and everything it clocks
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.apu.set_region(region);
+        self.ppu.set_region(region);
+    }
+
+    pub fn mapper(&self) -> Option<&dyn Mapper> {
+        self.mapper.as_deref()
     }
 
     fn load_and_run(&mut self, program: Vec<u8>) {
@@ -120,12 +182,49 @@ impl CPU {
         self.run();
     }
 
-    fn mem_read(&mut self, address: u16) -> u8 {
-        self.memory[address as usize]
+    pub fn mem_read(&mut self, address: u16) -> u8 {
+        if let 0x4016 | 0x4017 = address {
+            let (scanline, dot) = self.beam_position();
+            self.input.set_beam(&self.ppu.frame, scanline, dot);
+        }
+
+        let data = match (address, &mut self.mapper) {
+            (0x2000..=0x3fff, Some(mapper)) => self.ppu.read_register(address, mapper.as_mut()),
+            (0x4015, _) => self.apu.read_status() | (self.open_bus & 0x20),
+            (0x4016, _) => self.input.read(0) | (self.open_bus & 0xe0),
+            (0x4017, _) => self.input.read(1) | (self.open_bus & 0xe0),
+            (0x4020..=0xffff, Some(mapper)) => mapper.cpu_read(address).unwrap_or(self.open_bus),
+            _ => self.memory[address as usize],
+        };
+
+        self.open_bus = data;
+        data
+    }
+
+    // What mem_read would return without its side effects, for looking at
+    // memory from outside the program. The PPU, APU and controller registers
+    // can't be read without disturbing them, so they give the open bus value.
+    pub fn peek(&self, address: u16) -> u8 {
+        match (address, &self.mapper) {
+            (0x2000..=0x3fff, Some(_)) | (0x4015..=0x4017, _) => self.open_bus,
+            (0x4020..=0xffff, Some(mapper)) => mapper.cpu_peek(address).unwrap_or(self.open_bus),
+            _ => self.memory[address as usize],
+        }
     }
 
-    fn mem_write(&mut self, address: u16, data: u8) {
-        self.memory[address as usize] = data;
+    pub fn mem_write(&mut self, address: u16, data: u8) {
+        self.open_bus = data;
+
+        match (address, &mut self.mapper) {
+            (0x2000..=0x3fff, Some(mapper)) => {
+                self.ppu.write_register(address, data, mapper.as_mut())
+            }
+            (0x4000..=0x4013 | 0x4015 | 0x4017, _) => self.apu.write_register(address, data),
+            (0x4014, _) => self.oam_dma_transfer(data),
+            (0x4016, _) => self.input.write(data),
+            (0x4020..=0xffff, Some(mapper)) => mapper.cpu_write(address, data),
+            _ => self.memory[address as usize] = data,
+        }
     }
 
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
@@ -151,6 +250,49 @@ impl CPU {
         self.reg_y = 0;
         self.acc_reg = 0;
         self.status = 0;
+        self.stack_ptr = 0xfd;
+    }
+
+    // Pressing the reset button. Unlike power on it leaves the registers
+    // alone, the CPU only masks interrupts and goes through the motions of an
+    // interrupt with writes disabled, which moves the stack pointer down by
+    // three. The APU channels go quiet.
+    pub fn soft_reset(&mut self) {
+        self.status |= 0b0000_0100;
+        self.stack_ptr = self.stack_ptr.wrapping_sub(3);
+        self.apu.write_register(0x4015, 0x00);
+        self.pc = self.mem_read_u16(0xfffc);
+    }
+
+    fn stack_push(&mut self, data: u8) {
+        self.mem_write(0x0100 | self.stack_ptr as u16, data);
+        self.stack_ptr = self.stack_ptr.wrapping_sub(1);
+    }
+
+    fn stack_pop(&mut self) -> u8 {
+        self.stack_ptr = self.stack_ptr.wrapping_add(1);
+        self.mem_read(0x0100 | self.stack_ptr as u16)
+    }
+
+    // State of the shared IRQ line, low as soon as any source pulls it
+    pub fn irq_line(&self) -> bool {
+        self.apu.irq() || self.mapper.as_ref().is_some_and(|mapper| mapper.irq())
+    }
+
+    // Pushes the return address and status and jumps through the vector,
+    // $FFFE for IRQs and $FFFA for NMIs, with further IRQs masked until the
+    // handler clears the flag
+    fn interrupt(&mut self, vector: u16) {
+        self.stack_push((self.pc >> 8) as u8);
+        self.stack_push(self.pc as u8);
+        // Bit 5 is always set on the pushed copy, the B flag isn't
+        self.stack_push((self.status | 0b0010_0000) & 0b1110_1111);
+        self.status |= 0b0000_0100;
+        self.pc = self.mem_read_u16(vector);
+
+        for _ in 0..7 {
+            self.tick();
+        }
     }
 
     fn lda(&mut self, value: u8) {
@@ -210,25 +352,170 @@ impl CPU {
         self.update_negative_zero_flags(value as u8);
     }
 
+    // Executes a single instruction. Returns false when the opcode isn't
+    // implemented, in which case the CPU can't go any further
+    pub fn step(&mut self) -> bool {
+        let nmi = self.ppu.nmi();
+        if nmi && !self.nmi_line {
+            self.interrupt(0xfffa);
+        } else if self.irq_line() && self.status & 0b0000_0100 == 0 {
+            self.interrupt(0xfffe);
+        }
+        self.nmi_line = nmi;
+
+        let opcode = self.mem_read(self.pc);
+        self.pc += 1;
+
+        match opcode {
+            0x00 => {
+                self.reset();
+            }
+            0x69 => {
+                self.adc(&AddressingMode::Immediate);
+                self.pc += self.ops_info.get(&0x69).unwrap().size as u16 - 1;
+            }
+            0x60 => {
+                let lo = self.stack_pop() as u16;
+                let hi = self.stack_pop() as u16;
+                self.pc = ((hi << 8) | lo).wrapping_add(1);
+            }
+
+            _ => return false,
+        }
+
+        let cycles = self.ops_info.get(&opcode).unwrap().cycle_count;
+        for _ in 0..cycles {
+            self.tick();
+        }
+
+        true
+    }
+
+    // Moves everything clocked by the CPU forward by one cycle
+    fn tick(&mut self) {
+        self.cycles += 1;
+
+        if let Some(mapper) = &mut self.mapper {
+            if let Some(gains) = self.apu.take_expansion_gains() {
+                mapper.set_audio_gains(&gains);
+            }
+            mapper.cpu_clock();
+            self.apu.set_expansion_output(mapper.audio_output());
+
+            let (dots, cycles) = self.region.ppu_dots_per_cpu_cycle();
+            self.ppu_clock += dots;
+            while self.ppu_clock >= cycles {
+                self.ppu_clock -= cycles;
+                self.ppu.tick(mapper.as_mut());
+            }
+        }
+        self.apu.clock();
+
+        if !self.dmc_dma {
+            if let Some(addr) = self.apu.dmc_request() {
+                self.dmc_dma_transfer(addr);
+            }
+        }
+    }
+
+    // Copies a page of memory to $2004 for the PPU's sprite memory. The CPU
+    // stops for 513 cycles, or 514 when it has to wait for an even cycle
+    // before starting.
+    fn oam_dma_transfer(&mut self, page: u8) {
+        self.oam_dma = true;
+
+        let odd = !self.cycles.is_multiple_of(2);
+        self.tick();
+        if odd {
+            self.tick();
+        }
+        for offset in 0..=0xff {
+            let data = self.mem_read((page as u16) << 8 | offset);
+            self.tick();
+            self.mem_write(0x2004, data);
+            self.tick();
+        }
+
+        self.oam_dma = false;
+    }
+
+    // Fetches a sample byte for the DMC over the CPU bus. That halts the
+    // CPU for 4 cycles, but only 2 when an OAM DMA is already holding it,
+    // since the fetch slots into the transfer's cycles.
+    fn dmc_dma_transfer(&mut self, addr: u16) {
+        self.dmc_dma = true;
+
+        let stall = if self.oam_dma { 2 } else { 4 };
+        for _ in 1..stall {
+            self.tick();
+        }
+        let data = self.mem_read(addr);
+        self.apu.dmc_fill(data);
+        self.tick();
+
+        self.dmc_dma = false;
+    }
+
     pub fn run(&mut self) {
-        let ops_info = create_ops_info();
-
-        loop {
-            let opcode = self.mem_read(self.pc);
-            self.pc += 1;
-
-            match opcode {
-                0x00 => {
-                    self.reset();
-                }
-                0x69 => {
-                    self.adc(&AddressingMode::Immediate);
-                    self.pc += ops_info.get(&0x69).unwrap().size as u16 - 1;
-                }
-
-                _ => break,
+        while self.step() {}
+    }
+
+    // Lets everything clocked by the CPU run on while the CPU itself does
+    // nothing
+    pub fn idle(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.tick();
+        }
+    }
+
+    // Runs a subroutine as if it had been reached through JSR, until its
+    // RTS comes back. This drives code that has no main loop of its own,
+    // like the routines in NSF files.
+    pub fn call(&mut self, addr: u16, max_cycles: u64) -> Result<(), String> {
+        let return_addr = CALL_RETURN_ADDR.wrapping_sub(1);
+        self.stack_push((return_addr >> 8) as u8);
+        self.stack_push(return_addr as u8);
+        self.pc = addr;
+
+        let deadline = self.cycles + max_cycles;
+        while self.pc != CALL_RETURN_ADDR {
+            if !self.step() {
+                return Err(format!(
+                    "unsupported opcode at ${:04x} in the routine at ${:04x}",
+                    self.pc.wrapping_sub(1),
+                    addr
+                ));
+            }
+            if self.cycles >= deadline {
+                return Err(format!(
+                    "the routine at ${:04x} didn't return within {} cycles",
+                    addr, max_cycles
+                ));
             }
         }
+
+        Ok(())
+    }
+
+    // The scanline and dot the PPU is on
+    pub fn beam_position(&self) -> (u32, u32) {
+        (self.ppu.scanline, self.ppu.dot)
+    }
+
+    // Runs instructions until a frame worth of cycles for the current region
+    // has gone by. Returns false if the CPU stopped before the frame ended.
+    pub fn run_frame(&mut self) -> bool {
+        let (cycles, frames) = self.region.cpu_cycles_per_frame();
+        self.frame_count += 1;
+        let frame_end = self.frame_count * cycles as u64 / frames as u64;
+
+        while self.cycles < frame_end {
+            if !self.step() {
+                return false;
+            }
+        }
+
+        true
     }
 }
 
@@ -1516,6 +1803,7 @@ pub fn create_ops_info() -> HashMap<u8, OpCode> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::input::{Button, InputState, Zapper};
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
@@ -1763,4 +2051,220 @@ mod test {
 
         assert!((0b0000_0001 & cpu.status) == 0)
     }
+
+    // A cartridge that holds its IRQ line low for as long as it's told to
+    struct IrqCartridge {
+        prg: Vec<u8>,
+        irq: bool,
+    }
+
+    impl Mapper for IrqCartridge {
+        fn cpu_peek(&self, addr: u16) -> Option<u8> {
+            (addr >= 0x8000).then(|| self.prg[addr as usize - 0x8000])
+        }
+        fn cpu_write(&mut self, _addr: u16, _data: u8) {}
+        fn ppu_read(&mut self, _addr: u16) -> u8 {
+            0
+        }
+        fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+        fn mirroring(&self) -> crate::mapper::Mirroring {
+            crate::mapper::Mirroring::Vertical
+        }
+        fn irq(&self) -> bool {
+            self.irq
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![]);
+        cpu.mem_write(0x02ff, 0x5a);
+
+        cpu.mem_write(0x4014, 0x02);
+        assert_eq!(cpu.cycles, 513);
+        // The page ends up in OAM, last byte included
+        cpu.mem_write(0x2003, 0xff);
+        assert_eq!(cpu.mem_read(0x2004), 0x5a);
+        let cycles = cpu.cycles;
+
+        // Starting on an odd cycle costs one more
+        cpu.mem_write(0x4014, 0x02);
+        assert_eq!(cpu.cycles, cycles + 514);
+    }
+
+    #[test]
+    fn test_vblank_nmi() {
+        let mut cpu = CPU::new();
+        // The NMI vector points at $0000, where an ADC #$01 waits
+        cpu.load(vec![0x69, 0x00]);
+        cpu.memory[0x0000] = 0x69;
+        cpu.memory[0x0001] = 0x01;
+        cpu.reset();
+        cpu.mem_write(0x2000, 0x80);
+
+        while !cpu.ppu.nmi() {
+            cpu.idle(1);
+        }
+        assert!(cpu.step());
+        assert_eq!(cpu.pc, 0x0002);
+        assert_eq!(cpu.acc_reg, 0x01);
+        assert_eq!(cpu.stack_ptr, 0xfd - 3);
+        assert_ne!(cpu.status & 0b0000_0100, 0);
+
+        // The line stays up, but only its edge counts
+        assert!(cpu.ppu.nmi());
+        cpu.memory[0x0002] = 0x69;
+        assert!(cpu.step());
+        assert_eq!(cpu.pc, 0x0004);
+    }
+
+    #[test]
+    fn test_beam_position() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![]);
+        cpu.idle(114);
+        assert_eq!(cpu.beam_position(), (1, 1));
+
+        // PAL runs 3.2 dots a CPU cycle
+        let mut cpu = CPU::new();
+        cpu.set_region(Region::Pal);
+        cpu.load(vec![]);
+        cpu.idle(5);
+        assert_eq!(cpu.beam_position(), (0, 16));
+    }
+
+    #[test]
+    fn test_soft_reset_keeps_registers() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0x69, 0x05]);
+        cpu.reset();
+        cpu.step();
+        cpu.mem_write(0x4015, 0x01);
+        cpu.mem_write(0x4003, 0x08);
+
+        cpu.soft_reset();
+        assert_eq!(cpu.pc, 0x8000);
+        assert_eq!(cpu.acc_reg, 0x05);
+        assert_eq!(cpu.stack_ptr, 0xfd - 3);
+        assert_ne!(cpu.status & 0b0000_0100, 0);
+        assert_eq!(cpu.apu.read_status() & 0x01, 0);
+    }
+
+    #[test]
+    fn test_peek_leaves_the_bus_alone() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![]);
+        cpu.memory[0x0010] = 0x42;
+        cpu.mem_write(0x0000, 0xaa);
+
+        assert_eq!(cpu.peek(0x0010), 0x42);
+        assert_eq!(cpu.peek(0x4016), 0xaa);
+        assert_eq!(cpu.open_bus, 0xaa);
+    }
+
+    #[test]
+    fn test_controller_reads_keep_open_bus_bits() {
+        let mut cpu = CPU::new();
+        let mut state = InputState::default();
+        state.controllers[1].set(Button::A, true);
+        cpu.input.set_state(&state);
+
+        cpu.mem_write(0x4016, 0x01);
+        cpu.mem_write(0x4016, 0x40);
+        assert_eq!(cpu.mem_read(0x4016), 0x40);
+        // The last read left $40 on the bus
+        assert_eq!(cpu.mem_read(0x4017), 0x41);
+        assert_eq!(cpu.mem_read(0x4017), 0x40);
+    }
+
+    #[test]
+    fn test_zapper_sees_the_frame_being_drawn() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![]);
+        cpu.input.connect(1, Some(Box::new(Zapper::new())));
+        let mut state = InputState::default();
+        state.zapper.pointer = Some((128, 100));
+        cpu.input.set_state(&state);
+        let backdrop = |cpu: &mut CPU, colour| {
+            for (addr, data) in [(0x2006, 0x3f), (0x2006, 0x00), (0x2007, colour)] {
+                cpu.mem_write(addr, data);
+            }
+            cpu.mem_write(0x2006, 0x00);
+            cpu.mem_write(0x2006, 0x00);
+        };
+
+        // A black frame, then a white one that the game polls while it's
+        // being drawn, like Duck Hunt's target frame
+        backdrop(&mut cpu, 0x0f);
+        while cpu.ppu.scanline != 241 {
+            cpu.idle(1);
+        }
+        backdrop(&mut cpu, 0x30);
+        while cpu.ppu.scanline != 90 {
+            cpu.idle(1);
+        }
+        assert_eq!(cpu.mem_read(0x4017) & 0x08, 0x08);
+        while cpu.ppu.scanline != 102 {
+            cpu.idle(1);
+        }
+        assert_eq!(cpu.mem_read(0x4017) & 0x08, 0x00);
+    }
+
+    #[test]
+    fn test_dmc_dma_reads_through_the_bus() {
+        let mut cpu = CPU::new();
+        // The sample byte at $C000
+        let mut program = vec![0; 0x4001];
+        program[0x4000] = 0xaa;
+        cpu.load(program);
+
+        cpu.mem_write(0x4012, 0x00);
+        cpu.mem_write(0x4013, 0x00);
+        cpu.mem_write(0x4015, 0x10);
+
+        cpu.tick();
+        assert_eq!(cpu.cycles, 1 + 4);
+        assert_eq!(cpu.open_bus, 0xaa);
+        assert_eq!(cpu.mem_read(0x4015) & 0x10, 0);
+    }
+
+    #[test]
+    fn test_irq_pushes_state_and_jumps_to_vector() {
+        let mut prg = vec![0x69; 0x8000];
+        // Reset to $8000, IRQ to $9000
+        prg[0x7ffc..].copy_from_slice(&[0x00, 0x80, 0x00, 0x90]);
+
+        let mut cpu = CPU::new();
+        cpu.insert_cartridge(Box::new(IrqCartridge { prg, irq: true }));
+        cpu.reset();
+        cpu.status = 0b0000_0001;
+        cpu.step();
+
+        // The ADC at $9000 ran right after the 7 cycle interrupt sequence
+        assert_eq!(cpu.pc, 0x9002);
+        assert_eq!(cpu.cycles, 9);
+        assert_eq!(cpu.stack_ptr, 0xfa);
+        assert_eq!(cpu.memory[0x01fd], 0x80);
+        assert_eq!(cpu.memory[0x01fc], 0x00);
+        assert_eq!(cpu.memory[0x01fb], 0b0010_0001);
+
+        // Masked now, so the next instruction doesn't get interrupted
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9004);
+    }
+
+    #[test]
+    fn test_call_returns_through_rts() {
+        let mut cpu = CPU::new();
+        // ADC #$02, ADC #$03, RTS, then an unsupported opcode
+        cpu.load(vec![0x69, 0x02, 0x69, 0x03, 0x60, 0xff]);
+
+        cpu.call(0x8000, 100).unwrap();
+        assert_eq!(cpu.acc_reg, 5);
+        assert_eq!(cpu.stack_ptr, 0xfd);
+        assert_eq!(cpu.cycles, 2 + 2 + 6);
+
+        assert!(cpu.call(0x8005, 100).is_err());
+    }
 }
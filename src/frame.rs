@@ -0,0 +1,65 @@
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+// A frame as the PPU outputs it. Every pixel is a 9 bit value made of the
+// 6 bit palette index in the low bits and the 3 emphasis bits of $2001 on
+// top of it (0bEEE_LLCCCC), which is everything needed to rebuild the video
+// signal later on.
+pub struct Frame {
+    pub pixels: Vec<u16>,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self {
+            pixels: vec![0; WIDTH * HEIGHT],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * WIDTH + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, pixel: u16) {
+        self.pixels[y * WIDTH + x] = pixel & 0x1ff;
+    }
+
+    // Converts the frame to packed 24 bit RGB using a palette with one entry
+    // for every palette index and emphasis combination
+    pub fn to_rgb(&self, palette: &[[u8; 3]; 512]) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(WIDTH * HEIGHT * 3);
+
+        for pixel in self.pixels.iter() {
+            rgb.extend_from_slice(&palette[(*pixel & 0x1ff) as usize]);
+        }
+
+        rgb
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_masks_to_nine_bits() {
+        let mut frame = Frame::new();
+        frame.set(10, 20, 0xffff);
+
+        assert_eq!(frame.get(10, 20), 0x1ff);
+    }
+
+    #[test]
+    fn test_to_rgb_uses_palette() {
+        let mut palette = [[0u8; 3]; 512];
+        palette[0x21] = [1, 2, 3];
+
+        let mut frame = Frame::new();
+        frame.set(1, 0, 0x21);
+        let rgb = frame.to_rgb(&palette);
+
+        assert_eq!(rgb.len(), WIDTH * HEIGHT * 3);
+        assert_eq!(&rgb[3..6], &[1, 2, 3]);
+        assert_eq!(&rgb[0..3], &[0, 0, 0]);
+    }
+}
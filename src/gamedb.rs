@@ -0,0 +1,449 @@
+use std::fmt;
+
+use crate::cartridge::Cartridge;
+use crate::crc32::crc32;
+use crate::mapper::Mirroring;
+use crate::region::Region;
+use crate::sha1::sha1;
+
+// A database of known dumps, used to fix the many iNES files that carry
+// the wrong mapper, mirroring or RAM sizes in their header. Entries use the
+// NES 2.0 XML format of nes20db, one <game> element per dump:
+//
+//     <game>
+//       <rom size="40960" crc32="..." sha1="..."/>
+//       <prgrom size="32768" crc32="..." sha1="..."/>
+//       <prgram size="8192"/>
+//       <pcb mapper="1" submapper="0" mirroring="H" battery="1"/>
+//       <console type="0" region="0"/>
+//     </game>
+//
+// <rom> hashes the PRG and CHR ROM back to back, without the header or the
+// trainer. Entries without it get matched on <prgrom> alone.
+
+const EMBEDDED: &str = include_str!("gamedb.xml");
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+struct Hash {
+    crc32: Option<u32>,
+    sha1: Option<[u8; 20]>,
+}
+
+impl Hash {
+    // SHA-1 wins when the entry has it, CRC-32 alone can collide
+    fn matches(&self, crc32: u32, sha1: &[u8; 20]) -> bool {
+        match (self.sha1, self.crc32) {
+            (Some(expected), _) => expected == *sha1,
+            (None, Some(expected)) => expected == crc32,
+            (None, None) => false,
+        }
+    }
+}
+
+// Everything in the database is a complete description of the board, so
+// RAM a game doesn't list means it has none
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct GameEntry {
+    rom: Option<Hash>,
+    prg_rom: Option<Hash>,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub region: Option<Region>,
+}
+
+// One header field the database disagreed with
+#[derive(Debug, PartialEq)]
+pub struct Correction {
+    pub field: &'static str,
+    pub header: String,
+    pub database: String,
+}
+
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.field, self.header, self.database)
+    }
+}
+
+pub struct GameDb {
+    entries: Vec<GameEntry>,
+}
+
+impl GameDb {
+    pub fn embedded() -> GameDb {
+        GameDb::parse(EMBEDDED).expect("the embedded game database is malformed")
+    }
+
+    pub fn parse(text: &str) -> Result<GameDb, String> {
+        let mut entries = Vec::new();
+        let mut game: Option<GameEntry> = None;
+
+        for tag in Tags::new(text) {
+            let tag = tag?;
+
+            match (tag.name, &mut game) {
+                ("game", None) if !tag.closing => game = Some(GameEntry::default()),
+                ("game", Some(_)) if tag.closing => entries.extend(game.take()),
+                ("game", _) => return Err("<game> elements can't be nested".to_string()),
+                (_, Some(entry)) => entry.read_tag(&tag)?,
+                // The root element and anything else outside a game
+                (_, None) => {}
+            }
+        }
+
+        if game.is_some() {
+            return Err("unterminated <game> element".to_string());
+        }
+
+        Ok(GameDb { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Adds the entries of another database, which take priority over the
+    // ones already here
+    pub fn extend(&mut self, other: GameDb) {
+        let mut entries = other.entries;
+        entries.append(&mut self.entries);
+        self.entries = entries;
+    }
+
+    pub fn lookup(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<&GameEntry> {
+        let mut rom = prg_rom.to_vec();
+        rom.extend_from_slice(chr_rom);
+        let (rom_crc32, rom_sha1) = (crc32(&rom), sha1(&rom));
+        let (prg_crc32, prg_sha1) = (crc32(prg_rom), sha1(prg_rom));
+
+        self.entries
+            .iter()
+            .find(|entry| match (entry.rom, entry.prg_rom) {
+                (Some(hash), _) => hash.matches(rom_crc32, &rom_sha1),
+                (None, Some(hash)) => hash.matches(prg_crc32, &prg_sha1),
+                (None, None) => false,
+            })
+    }
+
+    // Overwrites whatever the header got wrong with the database's entry
+    // for the dump and lists what changed
+    pub fn correct(&self, cartridge: &mut Cartridge) -> Vec<Correction> {
+        let mut corrections = Vec::new();
+        let entry = match self.lookup(&cartridge.prg_rom, &cartridge.chr_rom) {
+            Some(entry) => entry.clone(),
+            None => return corrections,
+        };
+
+        fn fix<T: PartialEq + fmt::Debug>(
+            corrections: &mut Vec<Correction>,
+            field: &'static str,
+            value: &mut T,
+            database: Option<T>,
+        ) {
+            if let Some(database) = database {
+                if *value != database {
+                    corrections.push(Correction {
+                        field,
+                        header: format!("{:?}", value),
+                        database: format!("{:?}", database),
+                    });
+                    *value = database;
+                }
+            }
+        }
+
+        let c = &mut corrections;
+        fix(c, "mapper", &mut cartridge.mapper, entry.mapper);
+        fix(c, "submapper", &mut cartridge.submapper, entry.submapper);
+        fix(c, "mirroring", &mut cartridge.mirroring, entry.mirroring);
+        fix(c, "battery", &mut cartridge.battery, entry.battery);
+        let prg_ram = Some(entry.prg_ram_size);
+        fix(c, "PRG-RAM", &mut cartridge.prg_ram_size, prg_ram);
+        let prg_nvram = Some(entry.prg_nvram_size);
+        fix(c, "PRG-NVRAM", &mut cartridge.prg_nvram_size, prg_nvram);
+        // Boards with CHR-ROM never get CHR-RAM from the iNES defaults, so
+        // only fill it in when the database has some
+        if entry.chr_ram_size != 0 || cartridge.chr_rom.is_empty() {
+            let chr_ram = Some(entry.chr_ram_size);
+            fix(c, "CHR-RAM", &mut cartridge.chr_ram_size, chr_ram);
+        }
+        fix(c, "region", &mut cartridge.region, entry.region);
+
+        corrections
+    }
+}
+
+impl GameEntry {
+    fn read_tag(&mut self, tag: &Tag) -> Result<(), String> {
+        match tag.name {
+            "rom" => self.rom = Some(tag.hash()?),
+            "prgrom" => self.prg_rom = Some(tag.hash()?),
+            "prgram" => self.prg_ram_size = tag.number("size")?.unwrap_or(0),
+            "prgnvram" => self.prg_nvram_size = tag.number("size")?.unwrap_or(0),
+            "chrram" => self.chr_ram_size = tag.number("size")?.unwrap_or(0),
+            "pcb" => {
+                self.mapper = tag.number("mapper")?;
+                self.submapper = tag.number("submapper")?;
+                self.battery = tag.number::<u8>("battery")?.map(|battery| battery != 0);
+                self.mirroring = match tag.attribute("mirroring") {
+                    Some("H") => Some(Mirroring::Horizontal),
+                    Some("V") => Some(Mirroring::Vertical),
+                    Some("4") => Some(Mirroring::FourScreen),
+                    // Mapper controlled, the header bit doesn't matter
+                    _ => None,
+                };
+            }
+            "console" => {
+                self.region = match tag.number::<u8>("region")? {
+                    Some(1) => Some(Region::Pal),
+                    Some(3) => Some(Region::Dendy),
+                    // Multi region games run on the NTSC timing
+                    Some(_) => Some(Region::Ntsc),
+                    None => None,
+                };
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+// Just enough of an XML reader for the database: element tags with their
+// attributes, skipping text, comments and declarations
+struct Tag<'a> {
+    name: &'a str,
+    closing: bool,
+    attributes: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Tag<'a> {
+    fn parse(body: &'a str) -> Result<Tag<'a>, String> {
+        let (closing, body) = match body.strip_prefix('/') {
+            Some(body) => (true, body),
+            None => (false, body.strip_suffix('/').unwrap_or(body)),
+        };
+
+        let body = body.trim();
+        let name_end = body.find(char::is_whitespace).unwrap_or(body.len());
+        let name = &body[..name_end];
+        let mut rest = body[name_end..].trim_start();
+        let mut attributes = Vec::new();
+
+        while !rest.is_empty() {
+            let malformed = || format!("malformed attributes in <{}>", body);
+            let (key, value) = rest.split_once('=').ok_or_else(malformed)?;
+            let value = value.trim_start();
+            let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'');
+            let quote = quote.ok_or_else(malformed)?;
+            let end = value[1..].find(quote).ok_or_else(malformed)? + 1;
+
+            attributes.push((key.trim(), &value[1..end]));
+            rest = value[end + 1..].trim_start();
+        }
+
+        Ok(Tag {
+            name,
+            closing,
+            attributes,
+        })
+    }
+
+    fn attribute(&self, key: &str) -> Option<&'a str> {
+        self.attributes
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| *value)
+    }
+
+    fn number<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        self.attribute(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("bad {} \"{}\" in <{}>", key, value, self.name))
+            })
+            .transpose()
+    }
+
+    fn hash(&self) -> Result<Hash, String> {
+        let bad = |value: &str| format!("bad hash \"{}\" in <{}>", value, self.name);
+
+        let crc32 = self
+            .attribute("crc32")
+            .map(|value| u32::from_str_radix(value, 16).map_err(|_| bad(value)))
+            .transpose()?;
+        let sha1 = self
+            .attribute("sha1")
+            .map(|value| parse_sha1(value).ok_or_else(|| bad(value)))
+            .transpose()?;
+
+        Ok(Hash { crc32, sha1 })
+    }
+}
+
+fn parse_sha1(value: &str) -> Option<[u8; 20]> {
+    if value.len() != 40 || !value.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+struct Tags<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tags<'a> {
+    fn new(text: &'a str) -> Tags<'a> {
+        Tags { rest: text }
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Result<Tag<'a>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.rest.find('<')?;
+            self.rest = &self.rest[start..];
+
+            let end = if self.rest.starts_with("<!--") {
+                self.rest.find("-->").map(|end| end + 3)
+            } else {
+                self.rest.find('>').map(|end| end + 1)
+            };
+            let end = match end {
+                Some(end) => end,
+                None => {
+                    self.rest = "";
+                    return Some(Err("unterminated tag".to_string()));
+                }
+            };
+
+            let tag = &self.rest[1..end - 1];
+            self.rest = &self.rest[end..];
+
+            if !tag.starts_with('!') && !tag.starts_with('?') {
+                return Some(Tag::parse(tag));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(digest: [u8; 20]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // An MMC1 game whose header claims NROM with vertical mirroring and
+    // no battery
+    fn bad_header() -> Cartridge {
+        let mut cartridge = Cartridge::from_program(&[0xa9, 0x01]);
+        cartridge.chr_rom = vec![0x22; 0x2000];
+        cartridge.chr_ram_size = 0;
+        cartridge.mirroring = Mirroring::Vertical;
+        cartridge
+    }
+
+    fn database_for(cartridge: &Cartridge) -> String {
+        let mut rom = cartridge.prg_rom.clone();
+        rom.extend_from_slice(&cartridge.chr_rom);
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db>
+  <game>
+    <!-- Some Game (USA).nes -->
+    <rom size="{}" crc32="{:08X}" sha1="{}"/>
+    <prgnvram size="8192"/>
+    <pcb mapper="1" submapper="0" mirroring="H" battery="1"/>
+    <console type="0" region="1"/>
+  </game>
+</nes20db>"#,
+            rom.len(),
+            crc32(&rom),
+            hex(sha1(&rom)).to_uppercase()
+        )
+    }
+
+    #[test]
+    fn test_embedded_database_parses() {
+        GameDb::embedded();
+    }
+
+    #[test]
+    fn test_corrects_header() {
+        let mut cartridge = bad_header();
+        let database = GameDb::parse(&database_for(&cartridge)).unwrap();
+        assert_eq!(database.len(), 1);
+
+        let corrections = database.correct(&mut cartridge);
+        let report: Vec<String> = corrections.iter().map(|c| c.to_string()).collect();
+
+        assert_eq!(
+            report,
+            [
+                "mapper 0 -> 1",
+                "mirroring Vertical -> Horizontal",
+                "battery false -> true",
+                "PRG-RAM 8192 -> 0",
+                "PRG-NVRAM 0 -> 8192",
+                "region Ntsc -> Pal",
+            ]
+        );
+        assert_eq!(cartridge.mapper, 1);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+
+        // A second pass finds nothing left to fix
+        assert!(database.correct(&mut cartridge).is_empty());
+    }
+
+    #[test]
+    fn test_unknown_dump_is_untouched() {
+        let database = GameDb::parse(&database_for(&bad_header())).unwrap();
+        let mut cartridge = bad_header();
+        cartridge.prg_rom[0] = 0xea;
+
+        assert!(database.correct(&mut cartridge).is_empty());
+        assert_eq!(cartridge.mapper, 0);
+    }
+
+    #[test]
+    fn test_crc32_and_prg_only_entries() {
+        let cartridge = bad_header();
+        let text = format!(
+            "<game><prgrom crc32=\"{:08x}\"/><pcb mapper=\"4\"/></game>\
+             <game><rom crc32='00000000'/><pcb mapper=\"2\"/></game>",
+            crc32(&cartridge.prg_rom)
+        );
+        let database = GameDb::parse(&text).unwrap();
+
+        let entry = database
+            .lookup(&cartridge.prg_rom, &cartridge.chr_rom)
+            .unwrap();
+        assert_eq!(entry.mapper, Some(4));
+        assert_eq!(entry.mirroring, None);
+    }
+
+    #[test]
+    fn test_malformed_database() {
+        assert!(GameDb::parse("<game><pcb mapper=\"x\"/></game>").is_err());
+        assert!(GameDb::parse("<game><rom sha1=\"1234\"/></game>").is_err());
+        assert!(GameDb::parse("<game><pcb mapper=4/></game>").is_err());
+        assert!(GameDb::parse("<game>").is_err());
+        assert!(GameDb::parse("<game><pcb").is_err());
+    }
+}
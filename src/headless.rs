@@ -0,0 +1,865 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use crate::apu::{Apu, Channel, MixerChannel};
+use crate::blargg;
+use crate::cartridge::Cartridge;
+use crate::cpu::CPU;
+use crate::frame::{HEIGHT, WIDTH};
+use crate::gamedb::{Correction, GameDb};
+use crate::input::{AdapterKind, InputScript, Zapper};
+use crate::mapper::NsfMapper;
+use crate::nsf::{Nsf, NsfPlayer};
+use crate::ntsc::{NtscFilter, NtscSettings};
+use crate::png;
+use crate::region::Region;
+use crate::save::SaveFile;
+use crate::video::{RawWriter, Y4mWriter};
+use crate::wav::WavWriter;
+
+// Runs a ROM without any window for a fixed number of frames, dumping the
+// frames asked for as PNG files and optionally the whole run as a video
+// stream. NSF and NSFe files get played instead, which together with --wav
+// renders a track to a file.
+
+const USAGE: &str = "usage: nemulator --headless <rom> [--frames N] [--png N,N,...] \
+[--png-dir DIR] [--raw FILE] [--y4m FILE] [--ntsc] [--region ntsc|pal|dendy] [--blargg] \
+[--gamedb FILE] [--no-gamedb] [--wav FILE] [--wav-stems] [--sample-rate HZ] [--track N] \
+[--mute CH,CH,...] \
+[--solo CH,CH,...] [--volume CH=LEVEL,...] [--input FILE] \
+[--four-player fourscore|hori] [--zapper]";
+
+pub struct Options {
+    pub rom: PathBuf,
+    pub frames: u64,
+    pub png_frames: Vec<u64>,
+    pub png_dir: PathBuf,
+    pub raw: Option<PathBuf>,
+    pub y4m: Option<PathBuf>,
+    pub ntsc: bool,
+    pub region: Option<Region>,
+    // Reads the result of a blargg test ROM instead of dumping frames
+    pub blargg: bool,
+    // An nes20db XML file checked before the embedded game database
+    pub gamedb: Option<PathBuf>,
+    // Trusts the iNES header as is
+    pub no_gamedb: bool,
+    pub wav: Option<PathBuf>,
+    // Records every sound channel to its own file next to the WAV as well
+    pub wav_stems: bool,
+    // Output rate of the recording instead of the APU's default
+    pub sample_rate: Option<u32>,
+    // One based track of an NSF to play instead of its default one
+    pub track: Option<u8>,
+    // Mixer channels by name, see Apu::find_channel. --volume also takes
+    // master.
+    pub mute: Vec<String>,
+    pub solo: Vec<String>,
+    pub volumes: Vec<(String, f32)>,
+    // A script of the buttons held on the controllers, see InputScript
+    pub input: Option<PathBuf>,
+    // Plugs a four player adapter in instead of two controllers
+    pub four_player: Option<AdapterKind>,
+    // Plugs a Zapper into port 2
+    pub zapper: bool,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            rom: PathBuf::new(),
+            frames: 60,
+            png_frames: Vec::new(),
+            png_dir: PathBuf::from("."),
+            raw: None,
+            y4m: None,
+            ntsc: false,
+            region: None,
+            blargg: false,
+            gamedb: None,
+            no_gamedb: false,
+            wav: None,
+            wav_stems: false,
+            sample_rate: None,
+            track: None,
+            mute: Vec::new(),
+            solo: Vec::new(),
+            volumes: Vec::new(),
+            input: None,
+            four_player: None,
+            zapper: false,
+        };
+        let mut rom = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or(format!("{} needs a value\n{}", arg, USAGE))
+            };
+
+            match arg.as_str() {
+                "--frames" => options.frames = parse_number(value()?)?,
+                "--png" => {
+                    for frame in value()?.split(',') {
+                        options.png_frames.push(parse_number(frame)?);
+                    }
+                }
+                "--png-dir" => options.png_dir = PathBuf::from(value()?),
+                "--raw" => options.raw = Some(PathBuf::from(value()?)),
+                "--y4m" => options.y4m = Some(PathBuf::from(value()?)),
+                "--ntsc" => options.ntsc = true,
+                "--blargg" => options.blargg = true,
+                "--gamedb" => options.gamedb = Some(PathBuf::from(value()?)),
+                "--no-gamedb" => options.no_gamedb = true,
+                "--wav" => options.wav = Some(PathBuf::from(value()?)),
+                "--wav-stems" => options.wav_stems = true,
+                "--sample-rate" => {
+                    let rate = value()?;
+                    match parse_number(rate)? {
+                        rate @ 1..=384_000 => options.sample_rate = Some(rate as u32),
+                        _ => return Err(format!("{} is not a valid sample rate", rate)),
+                    }
+                }
+                "--track" => {
+                    let track = value()?;
+                    match parse_number(track)? {
+                        track @ 1..=255 => options.track = Some(track as u8),
+                        _ => return Err(format!("{} is not a valid track", track)),
+                    }
+                }
+                "--input" => options.input = Some(PathBuf::from(value()?)),
+                "--four-player" => {
+                    let name = value()?;
+                    let kind = AdapterKind::from_name(name)
+                        .ok_or(format!("unknown adapter {}\n{}", name, USAGE))?;
+                    options.four_player = Some(kind);
+                }
+                "--zapper" => options.zapper = true,
+                "--mute" => options.mute.extend(value()?.split(',').map(str::to_string)),
+                "--solo" => options.solo.extend(value()?.split(',').map(str::to_string)),
+                "--volume" => {
+                    for setting in value()?.split(',') {
+                        let (name, level) = setting
+                            .split_once('=')
+                            .ok_or(format!("{} should be CHANNEL=LEVEL", setting))?;
+                        let level = level
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("{} is not a valid volume", level))?;
+                        options.volumes.push((name.to_string(), level));
+                    }
+                }
+                "--region" => {
+                    let name = value()?;
+                    let region = Region::from_name(name)
+                        .ok_or(format!("unknown region {}\n{}", name, USAGE))?;
+                    options.region = Some(region);
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(format!("unknown option {}\n{}", arg, USAGE));
+                }
+                _ => rom = Some(PathBuf::from(arg)),
+            }
+        }
+
+        options.rom = rom.ok_or(USAGE.to_string())?;
+        Ok(options)
+    }
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} is not a valid number", value))
+}
+
+// Battery saves get written out every this many frames on top of when the
+// run ends, so a crash loses at most a few seconds of progress
+const SAVE_FLUSH_FRAMES: u64 = 300;
+
+type WavFile = WavWriter<BufWriter<File>>;
+
+// An audio recording in progress, the stems in Channel::ALL order
+struct Recording {
+    path: PathBuf,
+    mix: WavFile,
+    stems: Vec<WavFile>,
+}
+
+// Stems go next to the mix, as music.wav's music.pulse1.wav and so on
+pub fn stem_path(path: &Path, channel: Channel) -> PathBuf {
+    path.with_extension(format!("{}.wav", channel.name()))
+}
+
+// The console as the headless runs see it
+pub struct Headless {
+    pub cpu: CPU,
+    // The APU's output over the last frame
+    pub audio: Vec<f32>,
+    // Header fields the game database overrode
+    pub corrections: Vec<Correction>,
+    // Set when an NSF is playing rather than a game running
+    pub nsf: Option<NsfPlayer>,
+    save: Option<SaveFile>,
+    recording: Option<Recording>,
+}
+
+impl Headless {
+    pub fn boot(
+        rom: &Path,
+        region: Option<Region>,
+        database: Option<&GameDb>,
+    ) -> Result<Headless, String> {
+        let bytes =
+            fs::read(rom).map_err(|err| format!("couldn't read {}: {}", rom.display(), err))?;
+        if Nsf::is_nsf(&bytes) || Nsf::is_nsfe(&bytes) {
+            return Headless::boot_nsf(rom, &bytes, region);
+        }
+
+        // Anything that isn't an iNES or UNIF file gets treated as a bare
+        // program image for $8000
+        let dump = Cartridge::is_ines(&bytes) || Cartridge::is_unif(&bytes);
+        let mut cartridge = if Cartridge::is_ines(&bytes) {
+            Cartridge::from_ines(&bytes).map_err(|err| format!("{}: {}", rom.display(), err))?
+        } else if Cartridge::is_unif(&bytes) {
+            Cartridge::from_unif(&bytes).map_err(|err| format!("{}: {}", rom.display(), err))?
+        } else if bytes.len() <= 0x7ffc {
+            Cartridge::from_program(&bytes)
+        } else {
+            return Err(format!(
+                "{} is too big, only program images up to 32K can be loaded",
+                rom.display()
+            ));
+        };
+        let corrections = match database {
+            Some(database) if dump => database.correct(&mut cartridge),
+            _ => Vec::new(),
+        };
+        let mut mapper = cartridge
+            .create_mapper()
+            .map_err(|err| format!("{}: {}", rom.display(), err))?;
+
+        let save = if cartridge.battery {
+            let path = SaveFile::path_for(rom);
+            let save = SaveFile::open(path.clone(), mapper.as_mut())
+                .map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+            Some(save)
+        } else {
+            None
+        };
+
+        let mut cpu = CPU::new();
+        cpu.set_region(Region::select(cartridge.region, region));
+        cpu.insert_cartridge(mapper);
+        cpu.reset();
+
+        Ok(Headless {
+            cpu,
+            audio: Vec::new(),
+            corrections,
+            nsf: None,
+            save,
+            recording: None,
+        })
+    }
+
+    // Loads an NSF or NSFe and starts its default track
+    fn boot_nsf(rom: &Path, bytes: &[u8], region: Option<Region>) -> Result<Headless, String> {
+        let nsf = Nsf::parse(bytes).map_err(|err| format!("{}: {}", rom.display(), err))?;
+
+        let mut cpu = CPU::new();
+        cpu.set_region(Region::select(nsf.region, region));
+        cpu.insert_cartridge(Box::new(NsfMapper::new(&nsf)));
+
+        let start_track = nsf.start_track;
+        let mut player = NsfPlayer::new(nsf);
+        player
+            .start_track(&mut cpu, start_track)
+            .map_err(|err| format!("{}: {}", rom.display(), err))?;
+
+        Ok(Headless {
+            cpu,
+            audio: Vec::new(),
+            corrections: Vec::new(),
+            nsf: Some(player),
+            save: None,
+            recording: None,
+        })
+    }
+
+    // Switches the NSF being played to another track, zero based
+    pub fn select_track(&mut self, track: u8) -> Result<(), String> {
+        match &mut self.nsf {
+            Some(player) => player.start_track(&mut self.cpu, track),
+            None => Err("only NSF files have tracks to select".to_string()),
+        }
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), String> {
+        if let Some(player) = &mut self.nsf {
+            player.run_frame(&mut self.cpu)?;
+        } else if !self.cpu.run_frame() {
+            return Err(format!(
+                "CPU stopped on an unsupported opcode at ${:04x} during frame {}",
+                self.cpu.pc.wrapping_sub(1),
+                self.cpu.frame_count
+            ));
+        }
+        // Taking the mix ends the block the stems are in too
+        self.audio = self.cpu.apu.take_samples();
+        let stems = self.cpu.apu.take_stem_samples();
+
+        if let Some(recording) = &mut self.recording {
+            let write_error = |err| format!("couldn't write {}: {}", recording.path.display(), err);
+            recording
+                .mix
+                .write_samples(&self.audio)
+                .map_err(write_error)?;
+            for (stem, samples) in recording.stems.iter_mut().zip(&stems) {
+                stem.write_samples(samples).map_err(write_error)?;
+            }
+        }
+
+        if self.cpu.frame_count.is_multiple_of(SAVE_FLUSH_FRAMES) {
+            self.flush_save()?;
+        }
+
+        Ok(())
+    }
+
+    // Records the audio from the next frame on to a WAV file, and each
+    // channel to its own one next to it if stems are asked for
+    pub fn start_recording(&mut self, path: &Path, stems: bool) -> Result<(), String> {
+        self.stop_recording()?;
+
+        let rate = self.cpu.apu.sample_rate();
+        let open = |path: &Path| {
+            WavWriter::new(BufWriter::new(create(path)?), rate)
+                .map_err(|err| format!("couldn't write {}: {}", path.display(), err))
+        };
+
+        let mix = open(path)?;
+        let stems = if stems {
+            Channel::ALL
+                .iter()
+                .map(|channel| open(&stem_path(path, *channel)))
+                .collect::<Result<Vec<_>, String>>()?
+        } else {
+            Vec::new()
+        };
+
+        // Whatever was buffered belongs before the recording started
+        self.cpu.apu.set_stems(!stems.is_empty());
+        self.cpu.apu.take_samples();
+        self.cpu.apu.take_stem_samples();
+        self.recording = Some(Recording {
+            path: path.to_path_buf(),
+            mix,
+            stems,
+        });
+
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        if let Some(recording) = self.recording.take() {
+            self.cpu.apu.set_stems(false);
+
+            let write_error = |err| format!("couldn't write {}: {}", recording.path.display(), err);
+            recording.mix.finish().map_err(write_error)?;
+            for stem in recording.stems {
+                stem.finish().map_err(write_error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the battery backed memory to its .sav file if it changed
+    pub fn flush_save(&mut self) -> Result<(), String> {
+        if let (Some(save), Some(mapper)) = (&mut self.save, self.cpu.mapper()) {
+            save.flush(mapper)
+                .map_err(|err| format!("couldn't write {}: {}", save.path().display(), err))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn run(options: &Options) -> Result<(), String> {
+    let database = if options.no_gamedb {
+        None
+    } else {
+        Some(load_gamedb(options.gamedb.as_deref())?)
+    };
+    let mut console = Headless::boot(&options.rom, options.region, database.as_ref())?;
+    for correction in &console.corrections {
+        eprintln!(
+            "{}: game database corrected {}",
+            options.rom.display(),
+            correction
+        );
+    }
+
+    if let Some(track) = options.track {
+        console.select_track(track - 1)?;
+    }
+    if let Some(player) = &console.nsf {
+        print_nsf_info(player, &options.rom);
+    }
+    apply_mixer(&mut console, options)?;
+    if let Some(kind) = options.four_player {
+        console.cpu.input.connect_four_player(kind);
+    }
+    if options.zapper {
+        console.cpu.input.connect(1, Some(Box::new(Zapper::new())));
+    }
+
+    if let Some(rate) = options.sample_rate {
+        console.cpu.apu.set_sample_rate(rate);
+    }
+    if let Some(path) = &options.wav {
+        console.start_recording(path, options.wav_stems)?;
+    }
+
+    // The save and the recording get written even when the run fails
+    // halfway
+    let result = run_console(&mut console, options);
+    let recording = console.stop_recording();
+    console.flush_save()?;
+    result.and(recording)
+}
+
+fn apply_mixer(console: &mut Headless, options: &Options) -> Result<(), String> {
+    let apu = &mut console.cpu.apu;
+    let find = |apu: &Apu, name: &str| -> Result<MixerChannel, String> {
+        apu.find_channel(name).ok_or_else(|| {
+            let names: Vec<&str> = Channel::ALL
+                .iter()
+                .map(|channel| channel.name())
+                .chain(apu.expansion_channels().iter().copied())
+                .collect();
+            format!("unknown channel {}, there are {}", name, names.join(", "))
+        })
+    };
+
+    for name in &options.mute {
+        let channel = find(apu, name)?;
+        apu.set_muted(channel, true);
+    }
+    for name in &options.solo {
+        let channel = find(apu, name)?;
+        apu.set_solo(channel, true);
+    }
+    for (name, level) in &options.volumes {
+        if name == "master" {
+            apu.set_master_volume(*level);
+        } else {
+            let channel = find(apu, name)?;
+            apu.set_volume(channel, *level);
+        }
+    }
+
+    if !options.mute.is_empty() || !options.solo.is_empty() || !options.volumes.is_empty() {
+        println!("mix: {}", describe_mix(apu));
+    }
+    Ok(())
+}
+
+// The channels whose controls aren't at their defaults, and the master
+// volume
+fn describe_mix(apu: &Apu) -> String {
+    let names = Channel::ALL
+        .iter()
+        .map(|channel| channel.name())
+        .chain(apu.expansion_channels().iter().copied());
+    let mut settings = Vec::new();
+
+    for name in names {
+        let Some(channel) = apu.find_channel(name) else {
+            continue;
+        };
+        let control = apu.control(channel);
+        if control.muted {
+            settings.push(format!("{} muted", name));
+        }
+        if control.solo {
+            settings.push(format!("{} solo", name));
+        }
+        if control.volume != 1.0 {
+            settings.push(format!("{} {:.2}", name, control.volume));
+        }
+    }
+    settings.push(format!("master {:.2}", apu.master_volume()));
+
+    settings.join(", ")
+}
+
+fn print_nsf_info(player: &NsfPlayer, path: &Path) {
+    let nsf = player.nsf();
+    println!("{} - {} ({})", nsf.title, nsf.artist, nsf.copyright);
+    if !nsf.ripper.is_empty() {
+        println!("ripped by {}", nsf.ripper);
+    }
+    let chips = nsf.chip_names();
+    if !chips.is_empty() {
+        println!("expansion audio: {}", chips.join(", "));
+    }
+
+    let track = &nsf.tracks[player.track() as usize];
+    let mut line = format!("track {}/{}", player.track() as u16 + 1, nsf.songs());
+    if let Some(name) = &track.name {
+        line += &format!(": {}", name);
+    }
+    if let Some(length) = track.length {
+        line += &format!(" [{}:{:02}]", length / 60_000, length / 1000 % 60);
+    }
+    println!("{}", line);
+
+    for chip in nsf.unsupported_chips() {
+        eprintln!(
+            "{}: {} audio isn't supported and stays silent",
+            path.display(),
+            chip
+        );
+    }
+}
+
+// The embedded database, with the entries of an extra file on top
+fn load_gamedb(path: Option<&Path>) -> Result<GameDb, String> {
+    let mut database = GameDb::embedded();
+
+    if let Some(path) = path {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+        let extra = GameDb::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+        // Any XML parses, a file without a single game is most likely not a
+        // game database at all
+        if extra.len() == 0 {
+            return Err(format!("{}: no <game> entries", path.display()));
+        }
+        database.extend(extra);
+    }
+
+    Ok(database)
+}
+
+fn run_console(console: &mut Headless, options: &Options) -> Result<(), String> {
+    if options.blargg {
+        let result = blargg::run_test(console, options.frames)?;
+        println!("{}", result.message);
+
+        if !result.passed() {
+            return Err(format!("test failed with code {}", result.code));
+        }
+        return Ok(());
+    }
+
+    let mut filter = NtscFilter::new(NtscSettings::new());
+    let palette = filter.palette();
+
+    let mut raw = match &options.raw {
+        Some(path) => Some(RawWriter::new(BufWriter::new(create(path)?))),
+        None => None,
+    };
+    let mut y4m = match &options.y4m {
+        Some(path) => Some(
+            Y4mWriter::new(BufWriter::new(create(path)?), console.cpu.region)
+                .map_err(|err| format!("couldn't write {}: {}", path.display(), err))?,
+        ),
+        None => None,
+    };
+
+    let script = match &options.input {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+            Some(InputScript::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?)
+        }
+        None => None,
+    };
+
+    if !options.png_frames.is_empty() {
+        fs::create_dir_all(&options.png_dir)
+            .map_err(|err| format!("couldn't create {}: {}", options.png_dir.display(), err))?;
+    }
+
+    for n in 1..=options.frames {
+        if let Some(state) = script.as_ref().and_then(|script| script.change_at(n)) {
+            console.cpu.input.set_state(state);
+        }
+        console.run_frame()?;
+
+        let rgb = if options.ntsc {
+            filter.apply(&console.cpu.ppu.frame)
+        } else {
+            console.cpu.ppu.frame.to_rgb(&palette)
+        };
+
+        if options.png_frames.contains(&n) {
+            let path = options.png_dir.join(format!("frame_{:05}.png", n));
+            png::write(&path, WIDTH as u32, HEIGHT as u32, &rgb)
+                .map_err(|err| format!("couldn't write {}: {}", path.display(), err))?;
+        }
+        if let Some(raw) = &mut raw {
+            raw.write_frame(&rgb).map_err(|err| err.to_string())?;
+        }
+        if let Some(y4m) = &mut y4m {
+            y4m.write_frame(&rgb).map_err(|err| err.to_string())?;
+        }
+    }
+
+    if let Some(raw) = raw {
+        raw.finish().map_err(|err| err.to_string())?;
+    }
+    if let Some(y4m) = y4m {
+        y4m.finish().map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+fn create(path: &Path) -> Result<File, String> {
+    File::create(path).map_err(|err| format!("couldn't create {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = Options::parse(&args(&[
+            "game.nes",
+            "--frames",
+            "120",
+            "--png",
+            "1,60, 120",
+            "--y4m",
+            "out.y4m",
+            "--ntsc",
+            "--region",
+            "pal",
+            "--blargg",
+            "--gamedb",
+            "nes20db.xml",
+            "--mute",
+            "pulse1,noise",
+            "--volume",
+            "master=0.5,dmc=2",
+            "--sample-rate",
+            "48000",
+            "--four-player",
+            "Hori",
+            "--zapper",
+        ]))
+        .unwrap();
+
+        assert_eq!(options.rom, PathBuf::from("game.nes"));
+        assert_eq!(options.frames, 120);
+        assert_eq!(options.png_frames, vec![1, 60, 120]);
+        assert_eq!(options.y4m, Some(PathBuf::from("out.y4m")));
+        assert!(options.raw.is_none());
+        assert!(options.ntsc);
+        assert_eq!(options.region, Some(Region::Pal));
+        assert!(options.blargg);
+        assert_eq!(options.gamedb, Some(PathBuf::from("nes20db.xml")));
+        assert!(!options.no_gamedb);
+        assert_eq!(options.mute, ["pulse1", "noise"]);
+        assert!(options.solo.is_empty());
+        assert_eq!(options.four_player, Some(AdapterKind::Hori));
+        assert!(options.zapper);
+        assert_eq!(options.sample_rate, Some(48_000));
+        assert_eq!(
+            options.volumes,
+            [("master".to_string(), 0.5), ("dmc".to_string(), 2.0)]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Options::parse(&args(&[])).is_err());
+        assert!(Options::parse(&args(&["game.nes", "--frames"])).is_err());
+        assert!(Options::parse(&args(&["game.nes", "--frames", "ten"])).is_err());
+        assert!(Options::parse(&args(&["game.nes", "--bogus"])).is_err());
+        assert!(Options::parse(&args(&["game.nes", "--sample-rate", "0"])).is_err());
+        assert!(Options::parse(&args(&["music.nsf", "--track", "0"])).is_err());
+        assert!(Options::parse(&args(&["music.nsf", "--volume", "dmc"])).is_err());
+        assert!(Options::parse(&args(&["music.nsf", "--volume", "dmc=loud"])).is_err());
+    }
+
+    #[test]
+    fn test_boot_picks_region() {
+        let dir = std::env::temp_dir().join(format!("nemulator_region_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // An NES 2.0 header asking for PAL timing
+        let mut bytes = b"NES\x1a\x01\x00\x00\x08\x00\x00\x00\x00\x01\x00\x00\x00".to_vec();
+        bytes.resize(16 + 0x4000, 0);
+        let rom = dir.join("pal.nes");
+        fs::write(&rom, &bytes).unwrap();
+
+        let console = Headless::boot(&rom, None, None).unwrap();
+        assert_eq!(console.cpu.region, Region::Pal);
+        let console = Headless::boot(&rom, Some(Region::Dendy), None).unwrap();
+        assert_eq!(console.cpu.region, Region::Dendy);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_describe_mix() {
+        let mut apu = Apu::new(Region::Ntsc);
+        assert_eq!(describe_mix(&apu), "master 1.00");
+
+        apu.set_muted(MixerChannel::Apu(Channel::Pulse1), true);
+        apu.set_volume(MixerChannel::Apu(Channel::Triangle), 0.5);
+        apu.set_master_volume(0.8);
+        assert_eq!(
+            describe_mix(&apu),
+            "pulse1 muted, triangle 0.50, master 0.80"
+        );
+    }
+
+    #[test]
+    fn test_gamedb_without_games() {
+        let dir = std::env::temp_dir().join(format!("nemulator_gamedb_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nes20db.xml");
+
+        fs::write(&path, "<nes20db></nes20db>").unwrap();
+        assert!(matches!(load_gamedb(Some(&path)), Err(err) if err.contains("no <game> entries")));
+        fs::write(&path, "<nes20db><game></game></nes20db>").unwrap();
+        assert!(load_gamedb(Some(&path)).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_boot_reports_gamedb_corrections() {
+        let dir = std::env::temp_dir().join(format!("nemulator_correct_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // A UxROM game whose header says NROM with horizontal mirroring
+        let mut bytes = vec![b'N', b'E', b'S', 0x1a, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let prg_rom: Vec<u8> = (0..0x8000).map(|n| (n / 0x4000) as u8).collect();
+        bytes.extend(&prg_rom);
+        let rom = dir.join("game.nes");
+        fs::write(&rom, bytes).unwrap();
+        let path = dir.join("nes20db.xml");
+        fs::write(
+            &path,
+            format!(
+                "<nes20db><game><prgrom crc32=\"{:08X}\"/>\
+                 <chrram size=\"8192\"/><pcb mapper=\"2\" mirroring=\"V\"/></game></nes20db>",
+                crate::crc32::crc32(&prg_rom)
+            ),
+        )
+        .unwrap();
+
+        let database = load_gamedb(Some(&path)).unwrap();
+        let console = Headless::boot(&rom, None, Some(&database)).unwrap();
+        let report: Vec<String> = console.corrections.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            report,
+            [
+                "mapper 0 -> 2",
+                "mirroring Horizontal -> Vertical",
+                "PRG-RAM 8192 -> 0",
+            ]
+        );
+        // The mapper the database asked for is the one running
+        assert_eq!(console.cpu.peek(0xc000), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_dumps_frames() {
+        let dir = std::env::temp_dir().join(format!("nemulator_headless_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // LDA isn't wired up yet, so loop on ADC #$01 followed by a BRK,
+        // which jumps back to the start through the reset vector
+        let rom = dir.join("adc.bin");
+        fs::write(&rom, [0x69, 0x01, 0x00]).unwrap();
+
+        let mut options = Options::parse(&args(&[rom.to_str().unwrap(), "--frames", "2"])).unwrap();
+        options.png_frames = vec![2];
+        options.png_dir = dir.clone();
+        options.raw = Some(dir.join("out.rgb"));
+        options.wav = Some(dir.join("out.wav"));
+        options.wav_stems = true;
+        let input = dir.join("input.txt");
+        fs::write(&input, "1 start\n2 - a,b - up\n").unwrap();
+        options.input = Some(input.clone());
+        options.four_player = Some(AdapterKind::FourScore);
+        run(&options).unwrap();
+
+        let png = fs::read(dir.join("frame_00002.png")).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+        let raw = fs::read(dir.join("out.rgb")).unwrap();
+        assert_eq!(raw.len(), 2 * WIDTH * HEIGHT * 3);
+        // Two frames of 44.1 kHz audio
+        let wav = fs::read(dir.join("out.wav")).unwrap();
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert!((1460..1480).contains(&((wav.len() - 44) / 2)));
+        let stem = fs::read(stem_path(&dir.join("out.wav"), Channel::Dmc)).unwrap();
+        assert_eq!(stem.len(), wav.len());
+
+        fs::write(&input, "1 jump\n").unwrap();
+        assert!(run(&options).unwrap_err().contains("unknown button jump"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_renders_nsf() {
+        let dir = std::env::temp_dir().join(format!("nemulator_nsf_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Two tracks, INIT and PLAY both a bare RTS at $8000
+        let mut nsf = vec![0; 0x80];
+        nsf[..5].copy_from_slice(b"NESM\x1a");
+        nsf[0x06] = 2;
+        nsf[0x07] = 1;
+        nsf[0x08..0x0e].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        nsf[0x6e..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        nsf.push(0x60);
+        let rom = dir.join("music.nsf");
+        fs::write(&rom, &nsf).unwrap();
+
+        let options = Options::parse(&args(&[
+            rom.to_str().unwrap(),
+            "--track",
+            "2",
+            "--frames",
+            "2",
+            "--wav",
+            dir.join("music.wav").to_str().unwrap(),
+        ]))
+        .unwrap();
+        run(&options).unwrap();
+
+        let wav = fs::read(dir.join("music.wav")).unwrap();
+        assert!((1460..1480).contains(&((wav.len() - 44) / 2)));
+
+        let mut options = options;
+        options.solo = vec!["bogus".to_string()];
+        assert!(run(&options).unwrap_err().contains("unknown channel bogus"));
+        options.solo.clear();
+        options.track = Some(3);
+        assert!(run(&options).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
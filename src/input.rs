@@ -0,0 +1,693 @@
+// The controller ports at $4016 and $4017. Writing bit 0 of $4016 drives
+// the strobe line of both ports at once, which has the devices latch their
+// state, and every read of $4016 or $4017 clocks the next bit out of port 1
+// or port 2. Devices only drive D0-D4, the upper bits of a read are left
+// to the open bus.
+//
+// Frontends don't talk to the devices directly. They fill in an InputState
+// for every frame and each device takes what it needs from it.
+
+use crate::frame::{Frame, HEIGHT, WIDTH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    // In the order the standard controller shifts them out
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Button::A => "a",
+            Button::B => "b",
+            Button::Select => "select",
+            Button::Start => "start",
+            Button::Up => "up",
+            Button::Down => "down",
+            Button::Left => "left",
+            Button::Right => "right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        let name = name.to_ascii_lowercase();
+        Button::ALL
+            .iter()
+            .find(|button| button.name() == name)
+            .copied()
+    }
+}
+
+// The buttons held on one controller, bit 0 being A like in the order they
+// get read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Buttons(pub u8);
+
+impl Buttons {
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        let bit = 1 << button as u8;
+        if pressed {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.0 & (1 << button as u8) != 0
+    }
+
+    // A comma separated list of button names, or - for none
+    pub fn parse(text: &str) -> Result<Buttons, String> {
+        let mut buttons = Buttons::default();
+        if text == "-" {
+            return Ok(buttons);
+        }
+
+        for name in text.split(',') {
+            let button =
+                Button::from_name(name.trim()).ok_or(format!("unknown button {}", name.trim()))?;
+            buttons.set(button, true);
+        }
+        Ok(buttons)
+    }
+}
+
+// Everything the player is doing during a frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputState {
+    // Controllers 1 to 4. The last two only reach the console through a
+    // four player adapter.
+    pub controllers: [Buttons; 4],
+    pub zapper: ZapperState,
+}
+
+// Where the Zapper points and whether its trigger is pulled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ZapperState {
+    // A pixel of the frame, or None when aimed away from the screen
+    pub pointer: Option<(usize, usize)>,
+    pub trigger: bool,
+}
+
+impl ZapperState {
+    // X,Y with an optional ,fire for the trigger. - in place of the
+    // coordinates aims off the screen.
+    pub fn parse(text: &str) -> Result<ZapperState, String> {
+        let mut fields = text.split(',');
+        let mut state = ZapperState::default();
+
+        let x = fields.next().unwrap_or("");
+        if x != "-" {
+            let y = fields.next().unwrap_or("");
+            let coordinate = |text: &str, size: usize| {
+                text.parse::<usize>()
+                    .ok()
+                    .filter(|value| *value < size)
+                    .ok_or(format!("{},{} is not on the screen", x, y))
+            };
+            state.pointer = Some((coordinate(x, WIDTH)?, coordinate(y, HEIGHT)?));
+        }
+
+        match fields.next() {
+            Some("fire") => state.trigger = true,
+            Some(field) => return Err(format!("unknown zapper field {}", field)),
+            None => {}
+        }
+        if let Some(field) = fields.next() {
+            return Err(format!("unknown zapper field {}", field));
+        }
+        Ok(state)
+    }
+}
+
+// Something plugged into a controller port
+pub trait Device {
+    // Takes the part of the input state meant for the device, port being 0
+    // for $4016 and 1 for $4017
+    fn update(&mut self, state: &InputState, port: usize);
+
+    fn write_strobe(&mut self, strobe: bool);
+
+    // D0-D4 of a read of the port
+    fn read(&mut self) -> u8;
+
+    // The picture as far as the PPU has drawn it and where it's drawing at
+    // the moment of a read, for light guns
+    fn set_beam(&mut self, _frame: &Frame, _scanline: u32, _dot: u32) {}
+}
+
+// The standard joypad, a 4021 shift register loaded with the eight buttons.
+// While the strobe is high it keeps reloading, so reads only ever see A.
+// Once all eight buttons have been read it returns 1s.
+#[derive(Default)]
+pub struct StandardController {
+    buttons: Buttons,
+    strobe: bool,
+    shift: u8,
+}
+
+impl StandardController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for StandardController {
+    fn update(&mut self, state: &InputState, port: usize) {
+        self.buttons = state.controllers[port];
+        if self.strobe {
+            self.shift = self.buttons.0;
+        }
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.shift = self.buttons.0;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        // With the strobe held the shift register keeps reloading, so only
+        // A ever comes out
+        if self.strobe {
+            return self.buttons.is_pressed(Button::A) as u8;
+        }
+
+        let bit = self.shift & 0x01;
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdapterKind {
+    // The NES Four Score, in its 4 player setting
+    FourScore,
+    // The Famicom's Hori 4 Players Adapter, which answers on D1 through the
+    // expansion port
+    Hori,
+}
+
+impl AdapterKind {
+    pub fn from_name(name: &str) -> Option<AdapterKind> {
+        match name.to_ascii_lowercase().as_str() {
+            "fourscore" | "four-score" => Some(AdapterKind::FourScore),
+            "hori" => Some(AdapterKind::Hori),
+            _ => None,
+        }
+    }
+}
+
+// One port's half of a four player adapter. It chains two controllers'
+// shift registers and a signature byte into a 24 bit sequence: controller
+// 1 or 2, then 3 or 4, then the signature games check to see the adapter
+// is there. The Four Score signs $4016 with $10 and $4017 with $20, the
+// Hori adapter the other way around. Reads past the end return 1s.
+pub struct FourPlayerAdapter {
+    kind: AdapterKind,
+    latch: u32,
+    strobe: bool,
+    shift: u32,
+}
+
+impl FourPlayerAdapter {
+    pub fn new(kind: AdapterKind) -> Self {
+        Self {
+            kind,
+            latch: 0,
+            strobe: false,
+            shift: 0,
+        }
+    }
+
+    fn signature(&self, port: usize) -> u32 {
+        match (self.kind, port) {
+            (AdapterKind::FourScore, 0) | (AdapterKind::Hori, 1) => 0x10,
+            _ => 0x20,
+        }
+    }
+}
+
+impl Device for FourPlayerAdapter {
+    fn update(&mut self, state: &InputState, port: usize) {
+        self.latch = state.controllers[port].0 as u32
+            | (state.controllers[port + 2].0 as u32) << 8
+            | self.signature(port) << 16;
+        if self.strobe {
+            self.shift = self.latch;
+        }
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.shift = self.latch;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let bit = if self.strobe {
+            self.latch & 0x01
+        } else {
+            let bit = self.shift & 0x01;
+            self.shift = (self.shift >> 1) | 0x80_0000;
+            bit
+        };
+
+        match self.kind {
+            AdapterKind::FourScore => bit as u8,
+            AdapterKind::Hori => (bit as u8) << 1,
+        }
+    }
+}
+
+// Colours the Zapper's photodiode picks up. Only the light greys, white
+// and the two brightest rows of colours are bright enough, the darker
+// rows and the blacks in columns $D-$F never are. Emphasis is ignored.
+fn is_bright(pixel: u16) -> bool {
+    let level = (pixel >> 4) & 0x03;
+    let hue = pixel & 0x0f;
+    (level >= 2 && hue <= 0x0c) || pixel & 0x3f == 0x3d
+}
+
+// How far around the pointer the Zapper sees, in pixels
+const ZAPPER_RADIUS: usize = 3;
+// How many scanlines the light sense line stays on after the beam drew
+// something bright in view
+const ZAPPER_LIGHT_SCANLINES: u32 = 20;
+
+// The Zapper light gun. It reports its trigger on D4 and the photodiode
+// on D3, which reads 0 while the diode sees light. The diode only sees
+// the small area of the screen it's aimed at, and only right after the
+// beam lit it up, so games have to poll it while the frame is drawn.
+#[derive(Default)]
+pub struct Zapper {
+    state: ZapperState,
+    // Whether the diode saw light at the last beam position
+    light: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Whether a bright pixel in view was drawn in the last few scanlines.
+    // The rest of the frame still holds the previous picture, which the
+    // diode can't see any more.
+    fn sees_light(&self, frame: &Frame, scanline: u32, dot: u32) -> bool {
+        let Some((x, y)) = self.state.pointer else {
+            return false;
+        };
+
+        let rows = y.saturating_sub(ZAPPER_RADIUS)..(y + ZAPPER_RADIUS + 1).min(HEIGHT);
+        let columns = x.saturating_sub(ZAPPER_RADIUS)..(x + ZAPPER_RADIUS + 1).min(WIDTH);
+        rows.into_iter().any(|py| {
+            columns.clone().any(|px| {
+                let inside =
+                    px.abs_diff(x).pow(2) + py.abs_diff(y).pow(2) <= ZAPPER_RADIUS * ZAPPER_RADIUS;
+                let (line, column) = (py as u32, px as u32);
+                // Pixel x comes out on dot x + 1
+                let drawn = line < scanline || line == scanline && column < dot;
+                inside
+                    && drawn
+                    && scanline - line < ZAPPER_LIGHT_SCANLINES
+                    && is_bright(frame.get(px, py))
+            })
+        })
+    }
+}
+
+impl Device for Zapper {
+    fn update(&mut self, state: &InputState, _port: usize) {
+        self.state = state.zapper;
+    }
+
+    // The Zapper has no shift register, it ignores the strobe
+    fn write_strobe(&mut self, _strobe: bool) {}
+
+    fn read(&mut self) -> u8 {
+        let trigger = if self.state.trigger { 0x10 } else { 0x00 };
+        let light = if self.light { 0x00 } else { 0x08 };
+        trigger | light
+    }
+
+    fn set_beam(&mut self, frame: &Frame, scanline: u32, dot: u32) {
+        self.light = self.sees_light(frame, scanline, dot);
+    }
+}
+
+pub struct ControllerPorts {
+    ports: [Option<Box<dyn Device>>; 2],
+}
+
+impl ControllerPorts {
+    // A standard controller in each port, like the console comes with
+    pub fn new() -> Self {
+        Self {
+            ports: [
+                Some(Box::new(StandardController::new())),
+                Some(Box::new(StandardController::new())),
+            ],
+        }
+    }
+
+    // Plugs a device into port 0 or 1, or leaves the port empty
+    pub fn connect(&mut self, port: usize, device: Option<Box<dyn Device>>) {
+        self.ports[port] = device;
+    }
+
+    // Puts a four player adapter on both ports
+    pub fn connect_four_player(&mut self, kind: AdapterKind) {
+        for port in 0..2 {
+            self.connect(port, Some(Box::new(FourPlayerAdapter::new(kind))));
+        }
+    }
+
+    pub fn set_beam(&mut self, frame: &Frame, scanline: u32, dot: u32) {
+        for device in self.ports.iter_mut().flatten() {
+            device.set_beam(frame, scanline, dot);
+        }
+    }
+
+    pub fn set_state(&mut self, state: &InputState) {
+        for (port, device) in self.ports.iter_mut().enumerate() {
+            if let Some(device) = device {
+                device.update(state, port);
+            }
+        }
+    }
+
+    // $4016 writes
+    pub fn write(&mut self, data: u8) {
+        for device in self.ports.iter_mut().flatten() {
+            device.write_strobe(data & 0x01 != 0);
+        }
+    }
+
+    // D0-D4 of a $4016 or $4017 read, an empty port pulls nothing up
+    pub fn read(&mut self, port: usize) -> u8 {
+        match &mut self.ports[port] {
+            Some(device) => device.read() & 0x1f,
+            None => 0,
+        }
+    }
+}
+
+// Input for a headless run, as lines of a frame number followed by the
+// buttons held on each controller from that frame on:
+//
+//   # frame  1             2     3     4
+//   60       start         -
+//   64       -             -
+//   120      a,right       b     -     up
+//
+// Controllers left off a line keep nothing pressed. A zapper=X,Y field
+// anywhere on the line aims the Zapper at a pixel, zapper=X,Y,fire pulls
+// the trigger too and zapper=-,fire shoots away from the screen.
+pub struct InputScript {
+    // Sorted by frame
+    changes: Vec<(u64, InputState)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut changes: Vec<(u64, InputState)> = Vec::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", n + 1, message);
+
+            let mut fields = line.split_whitespace();
+            let frame = fields.next().unwrap_or("");
+            let frame: u64 = frame
+                .parse()
+                .map_err(|_| error(format!("{} is not a valid frame", frame)))?;
+            if changes.last().is_some_and(|(last, _)| *last >= frame) {
+                return Err(error("frames have to go up from line to line".to_string()));
+            }
+
+            let mut state = InputState::default();
+            let ports = state.controllers.len();
+            let mut port = 0;
+            for field in fields {
+                if let Some(zapper) = field.strip_prefix("zapper=") {
+                    state.zapper = ZapperState::parse(zapper).map_err(error)?;
+                    continue;
+                }
+                let controller = state
+                    .controllers
+                    .get_mut(port)
+                    .ok_or(error(format!("there are only {} controllers", ports)))?;
+                *controller = Buttons::parse(field).map_err(error)?;
+                port += 1;
+            }
+            changes.push((frame, state));
+        }
+
+        Ok(InputScript { changes })
+    }
+
+    // The input for a frame, if the script changes it on that frame
+    pub fn change_at(&self, frame: u64) -> Option<&InputState> {
+        self.changes
+            .binary_search_by_key(&frame, |(frame, _)| *frame)
+            .ok()
+            .map(|index| &self.changes[index].1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn buttons(list: &[Button]) -> Buttons {
+        let mut buttons = Buttons::default();
+        for button in list {
+            buttons.set(*button, true);
+        }
+        buttons
+    }
+
+    fn read_all(ports: &mut ControllerPorts, port: usize, count: usize) -> Vec<u8> {
+        (0..count).map(|_| ports.read(port)).collect()
+    }
+
+    #[test]
+    fn test_buttons_shift_out_in_order() {
+        let mut ports = ControllerPorts::new();
+        let mut state = InputState::default();
+        state.controllers[0] = buttons(&[Button::A, Button::Start, Button::Left]);
+        state.controllers[1] = buttons(&[Button::B, Button::Right]);
+        ports.set_state(&state);
+
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(read_all(&mut ports, 0, 10), [1, 0, 0, 1, 0, 0, 1, 0, 1, 1]);
+        assert_eq!(read_all(&mut ports, 1, 10), [0, 1, 0, 0, 0, 0, 0, 1, 1, 1]);
+
+        // Only a new strobe picks up changed buttons
+        state.controllers[0] = Buttons::default();
+        ports.set_state(&state);
+        assert_eq!(ports.read(0), 1);
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(read_all(&mut ports, 0, 8), [0; 8]);
+    }
+
+    #[test]
+    fn test_strobe_high_keeps_reading_a() {
+        let mut ports = ControllerPorts::new();
+        let mut state = InputState::default();
+        state.controllers[0] = buttons(&[Button::B]);
+        ports.set_state(&state);
+
+        ports.write(1);
+        assert_eq!(read_all(&mut ports, 0, 3), [0, 0, 0]);
+        state.controllers[0] = buttons(&[Button::A]);
+        ports.set_state(&state);
+        assert_eq!(read_all(&mut ports, 0, 3), [1, 1, 1]);
+    }
+
+    #[test]
+    fn test_empty_port() {
+        let mut ports = ControllerPorts::new();
+        ports.connect(1, None);
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(read_all(&mut ports, 1, 10), [0; 10]);
+    }
+
+    // The 24 bits each port reads
+    fn sequence(ports: &mut ControllerPorts, port: usize, line: u8) -> u32 {
+        (0..24).fold(0, |bits, n| {
+            bits | (((ports.read(port) >> line) & 0x01) as u32) << n
+        })
+    }
+
+    #[test]
+    fn test_four_score_sequence() {
+        let mut ports = ControllerPorts::new();
+        ports.connect_four_player(AdapterKind::FourScore);
+        let state = InputState {
+            controllers: [
+                buttons(&[Button::A]),
+                buttons(&[Button::B]),
+                buttons(&[Button::Start]),
+                buttons(&[Button::Right]),
+            ],
+            ..Default::default()
+        };
+        ports.set_state(&state);
+
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(sequence(&mut ports, 0, 0), 0x10_08_01);
+        assert_eq!(sequence(&mut ports, 1, 0), 0x20_80_02);
+        assert_eq!(ports.read(0), 1);
+    }
+
+    #[test]
+    fn test_hori_adapter_uses_d1() {
+        let mut ports = ControllerPorts::new();
+        ports.connect_four_player(AdapterKind::Hori);
+        let mut state = InputState::default();
+        state.controllers[2] = buttons(&[Button::Up]);
+        ports.set_state(&state);
+
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(sequence(&mut ports, 0, 1), 0x20_10_00);
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(sequence(&mut ports, 0, 0), 0);
+        assert_eq!(sequence(&mut ports, 1, 1), 0x10_00_00);
+    }
+
+    #[test]
+    fn test_input_script() {
+        let script = InputScript::parse(
+            "# frame  port 1  port 2\n\
+             60 start -\n\
+             \n\
+             64 - # let go\n\
+             120 A,right b\n",
+        )
+        .unwrap();
+
+        assert_eq!(script.change_at(59), None);
+        assert_eq!(
+            script.change_at(60).unwrap().controllers,
+            [
+                buttons(&[Button::Start]),
+                Buttons::default(),
+                Buttons::default(),
+                Buttons::default()
+            ]
+        );
+        assert_eq!(script.change_at(64), Some(&InputState::default()));
+        assert_eq!(
+            script.change_at(120).unwrap().controllers,
+            [
+                buttons(&[Button::A, Button::Right]),
+                buttons(&[Button::B]),
+                Buttons::default(),
+                Buttons::default()
+            ]
+        );
+
+        assert!(InputScript::parse("ten a").is_err());
+        assert!(InputScript::parse("10 a\n5 b").is_err());
+        assert!(InputScript::parse("10 jump").is_err());
+        assert_eq!(
+            InputScript::parse("10 - - - up")
+                .unwrap()
+                .change_at(10)
+                .unwrap()
+                .controllers[3],
+            buttons(&[Button::Up])
+        );
+        assert!(InputScript::parse("10 a b c d e").is_err());
+    }
+
+    #[test]
+    fn test_zapper_sees_light_after_the_beam() {
+        let mut ports = ControllerPorts::new();
+        ports.connect(1, Some(Box::new(Zapper::new())));
+        let mut frame = Frame::new();
+        frame.set(100, 50, 0x30);
+
+        let mut state = InputState::default();
+        state.zapper.pointer = Some((101, 51));
+        ports.set_state(&state);
+
+        // Before the beam gets to the white pixel, and right after it
+        ports.set_beam(&frame, 50, 100);
+        assert_eq!(ports.read(1), 0x08);
+        ports.set_beam(&frame, 50, 101);
+        assert_eq!(ports.read(1), 0x00);
+        // The diode lets go after a few scanlines
+        ports.set_beam(&frame, 50 + ZAPPER_LIGHT_SCANLINES, 0);
+        assert_eq!(ports.read(1), 0x08);
+
+        // Dark colours and a pointer too far away don't count
+        frame.set(100, 50, 0x0f);
+        ports.set_beam(&frame, 55, 0);
+        assert_eq!(ports.read(1), 0x08);
+        frame.set(100, 50, 0x30);
+        state.zapper = ZapperState {
+            pointer: Some((110, 50)),
+            trigger: true,
+        };
+        ports.set_state(&state);
+        ports.set_beam(&frame, 55, 0);
+        assert_eq!(ports.read(1), 0x18);
+    }
+
+    #[test]
+    fn test_input_script_zapper() {
+        let script = InputScript::parse("1 a zapper=12,34 b\n2 zapper=-,fire").unwrap();
+
+        let state = script.change_at(1).unwrap();
+        assert_eq!(state.controllers[1], buttons(&[Button::B]));
+        assert_eq!(
+            state.zapper,
+            ZapperState {
+                pointer: Some((12, 34)),
+                trigger: false,
+            }
+        );
+        assert_eq!(
+            script.change_at(2).unwrap().zapper,
+            ZapperState {
+                pointer: None,
+                trigger: true,
+            }
+        );
+        assert!(InputScript::parse("1 zapper=256,0").is_err());
+        assert!(InputScript::parse("1 zapper=1,2,shoot").is_err());
+    }
+}
@@ -1,6 +1,37 @@
 
+mod apu;
+mod blargg;
+mod cartridge;
 mod cpu;
+mod crc32;
+mod frame;
+mod gamedb;
+mod headless;
+mod input;
+mod mapper;
+mod nsf;
+mod ntsc;
+mod png;
+mod ppu;
+mod region;
+mod save;
+#[cfg(test)]
+mod screenshot_tests;
+mod sha1;
+mod video;
+mod wav;
+
+use std::{env, process};
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().map(|arg| arg.as_str()) == Some("--headless") {
+        let result = headless::Options::parse(&args[1..]).and_then(|options| headless::run(&options));
 
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,202 @@
+use super::{bank_offset, ChrMemory, Eeprom24C02, Mapper, Mirroring};
+use crate::cartridge::Cartridge;
+
+// Mapper 16, Bandai's FCG boards with the LZ93D50 and a 24C02 EEPROM to
+// save to. The registers repeat every 16 bytes from $6000 to $FFFF: eight
+// 1K CHR banks, a 16K PRG bank at $8000 in front of the fixed last one,
+// mirroring, a 16 bit IRQ counter that counts down every CPU cycle and the
+// lines of the EEPROM's serial bus, which reads back on bit 4 of $6000-$7FFF.
+// The older FCG-1/2 boards without an EEPROM and mapper 159's 24C01 aren't
+// covered.
+pub struct BandaiFcg {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+
+    chr_banks: [u8; 8],
+    prg_bank: u8,
+    mirroring: u8,
+    irq_enabled: bool,
+    irq_latch: u16,
+    irq_counter: u16,
+    irq_pending: bool,
+    eeprom: Eeprom24C02,
+}
+
+impl BandaiFcg {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            chr: ChrMemory::new(cartridge),
+            chr_banks: [0; 8],
+            prg_bank: 0,
+            mirroring: 0,
+            irq_enabled: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_pending: false,
+            eeprom: Eeprom24C02::new(),
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize & 0x1fff) / 0x400];
+        bank_offset(self.chr.len(), 0x400, bank as usize, addr)
+    }
+}
+
+impl Mapper for BandaiFcg {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        let len = self.prg_rom.len();
+
+        match addr {
+            0x6000..=0x7fff => Some((self.eeprom.sda() as u8) << 4),
+            0x8000..=0xbfff => {
+                Some(self.prg_rom[bank_offset(len, 0x4000, self.prg_bank as usize, addr)])
+            }
+            0xc000..=0xffff => {
+                let last = (len / 0x4000).max(1) - 1;
+                Some(self.prg_rom[bank_offset(len, 0x4000, last, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x6000 {
+            return;
+        }
+
+        match addr & 0x0f {
+            0x0..=0x7 => self.chr_banks[addr as usize & 0x07] = data,
+            0x8 => self.prg_bank = data & 0x0f,
+            0x9 => self.mirroring = data & 0x03,
+            0xa => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter = self.irq_latch;
+                self.irq_pending = false;
+            }
+            0xb => self.irq_latch = (self.irq_latch & 0xff00) | data as u16,
+            0xc => self.irq_latch = (self.irq_latch & 0xff) | (data as u16) << 8,
+            0xd => self.eeprom.write_lines(data & 0x20 != 0, data & 0x40 != 0),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr.write(offset, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    // The board has no PRG-RAM, its saves live in the EEPROM
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(self.eeprom.data())
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.eeprom.data_mut())
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+
+        if self.irq_counter == 0 {
+            self.irq_pending = true;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::eeprom::test::{receive, send, start, stop};
+    use super::super::test::banked_cartridge;
+    use super::*;
+
+    fn cartridge() -> Cartridge {
+        let mut cartridge = banked_cartridge(16, 0, 8, 0x4000, 256, 0x400);
+        cartridge.battery = true;
+        cartridge
+    }
+
+    fn bus(bandai: &mut BandaiFcg) -> impl FnMut(bool, bool) -> bool + '_ {
+        |scl, sda| {
+            bandai.cpu_write(0x800d, (scl as u8) << 5 | (sda as u8) << 6);
+            bandai.cpu_read(0x6000).unwrap() & 0x10 != 0
+        }
+    }
+
+    #[test]
+    fn test_banks() {
+        let mut bandai = BandaiFcg::new(&cartridge());
+        bandai.cpu_write(0x8008, 3);
+        bandai.cpu_write(0x6003, 0x42);
+        bandai.cpu_write(0x8009, 1);
+
+        assert_eq!(bandai.cpu_read(0x8000), Some(3));
+        assert_eq!(bandai.cpu_read(0xc000), Some(7));
+        assert_eq!(bandai.ppu_read(0x0c00), 0x42);
+        assert_eq!(bandai.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_irq_counts_cpu_cycles() {
+        let mut bandai = BandaiFcg::new(&cartridge());
+        bandai.cpu_write(0x800b, 0x02);
+        bandai.cpu_write(0x800c, 0x00);
+        bandai.cpu_write(0x800a, 0x01);
+
+        for _ in 0..2 {
+            bandai.cpu_clock();
+        }
+        assert!(!bandai.irq());
+        bandai.cpu_clock();
+        assert!(bandai.irq());
+
+        bandai.cpu_write(0x800a, 0x00);
+        assert!(!bandai.irq());
+    }
+
+    #[test]
+    fn test_eeprom_through_registers() {
+        let mut bandai = BandaiFcg::new(&cartridge());
+        {
+            let lines = &mut bus(&mut bandai);
+
+            start(lines);
+            assert!(send(lines, 0xa0));
+            assert!(send(lines, 0x20));
+            assert!(send(lines, 0x5a));
+            stop(lines);
+
+            start(lines);
+            assert!(send(lines, 0xa0));
+            assert!(send(lines, 0x20));
+            start(lines);
+            assert!(send(lines, 0xa1));
+            assert_eq!(receive(lines, false), 0x5a);
+            stop(lines);
+        }
+
+        // The EEPROM is what gets saved
+        assert_eq!(bandai.save_ram().unwrap()[0x20], 0x5a);
+    }
+}
@@ -0,0 +1,286 @@
+use super::{bank_offset, bus_conflicts, ChrMemory, Mapper, Mirroring};
+use crate::cartridge::Cartridge;
+
+// The discrete logic boards: a latch on the data bus and a few gates, no
+// real mapper chip. Writing anywhere in $8000-$FFFF stores the value in the
+// latch, and since the PRG-ROM keeps driving the bus during the write, boards
+// without extra logic to prevent it only see the AND of both values.
+
+// Mapper 2, UNROM/UOROM. A switchable 16K bank at $8000 and the last one
+// fixed at $C000, with 8K of CHR-RAM.
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    bank: usize,
+}
+
+impl Uxrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            chr: ChrMemory::new(cartridge),
+            mirroring: cartridge.mirroring,
+            bus_conflicts: bus_conflicts(cartridge, true),
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        let last = (self.prg_rom.len() / 0x4000).max(1) - 1;
+
+        match addr {
+            0x8000..=0xbfff => {
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), 0x4000, self.bank, addr)])
+            }
+            0xc000..=0xffff => {
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), 0x4000, last, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            let bus_conflicts = self.bus_conflicts;
+            let data = latch_value(self, addr, data, bus_conflicts);
+            self.bank = data as usize;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(addr as usize & 0x1fff)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize & 0x1fff, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// Mapper 3, CNROM. Fixed PRG-ROM like NROM and a switchable 8K CHR-ROM bank.
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    bank: usize,
+}
+
+impl Cnrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            chr: ChrMemory::new(cartridge),
+            mirroring: cartridge.mirroring,
+            bus_conflicts: bus_conflicts(cartridge, true),
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xffff => Some(self.prg_rom[(addr as usize - 0x8000) % self.prg_rom.len()]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            let bus_conflicts = self.bus_conflicts;
+            let data = latch_value(self, addr, data, bus_conflicts);
+            self.bank = data as usize;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr
+            .read(bank_offset(self.chr.len(), 0x2000, self.bank, addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let offset = bank_offset(self.chr.len(), 0x2000, self.bank, addr);
+        self.chr.write(offset, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// Mapper 7, ANROM/AMROM/AOROM. A 32K PRG bank and single screen mirroring,
+// with bit 4 of the latch choosing which nametable is shown.
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    bus_conflicts: bool,
+    bank: usize,
+    upper_nametable: bool,
+}
+
+impl Axrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            chr: ChrMemory::new(cartridge),
+            // Only AMROM has them, and games written for the other boards
+            // don't avoid them
+            bus_conflicts: bus_conflicts(cartridge, false),
+            bank: 0,
+            upper_nametable: false,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xffff => {
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), 0x8000, self.bank, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            let bus_conflicts = self.bus_conflicts;
+            let data = latch_value(self, addr, data, bus_conflicts);
+            self.bank = (data & 0x07) as usize;
+            self.upper_nametable = data & 0x10 != 0;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(addr as usize & 0x1fff)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize & 0x1fff, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.upper_nametable {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        }
+    }
+}
+
+// Value the latch ends up with, taking the ROM fighting over the bus into
+// account
+fn latch_value(mapper: &mut dyn Mapper, addr: u16, data: u8, bus_conflicts: bool) -> u8 {
+    if bus_conflicts {
+        data & mapper.cpu_read(addr).unwrap_or(0xff)
+    } else {
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cartridge(mapper: u16, submapper: u8, prg_banks: usize, chr_banks: usize) -> Cartridge {
+        let mut cartridge = Cartridge::from_program(&[]);
+        cartridge.mapper = mapper;
+        cartridge.submapper = submapper;
+        // Every byte holds the number of its 16K bank
+        cartridge.prg_rom = (0..prg_banks * 0x4000)
+            .map(|n| (n / 0x4000) as u8)
+            .collect();
+        cartridge.chr_rom = (0..chr_banks * 0x2000)
+            .map(|n| (n / 0x2000) as u8)
+            .collect();
+        cartridge
+    }
+
+    #[test]
+    fn test_uxrom_banks() {
+        let mut uxrom = Uxrom::new(&cartridge(2, 1, 8, 0));
+
+        assert_eq!(uxrom.cpu_read(0x8000), Some(0));
+        assert_eq!(uxrom.cpu_read(0xffff), Some(7));
+
+        uxrom.cpu_write(0x8000, 5);
+        assert_eq!(uxrom.cpu_read(0xbfff), Some(5));
+        assert_eq!(uxrom.cpu_read(0xc000), Some(7));
+    }
+
+    #[test]
+    fn test_uxrom_chr_ram() {
+        let mut uxrom = Uxrom::new(&cartridge(2, 0, 8, 0));
+        uxrom.ppu_write(0x0123, 0x99);
+
+        assert_eq!(uxrom.ppu_read(0x0123), 0x99);
+    }
+
+    #[test]
+    fn test_uxrom_bus_conflicts() {
+        // The byte at $8000 holds the current bank number, 0
+        let mut with_conflicts = Uxrom::new(&cartridge(2, 2, 8, 0));
+        with_conflicts.cpu_write(0x8000, 3);
+        assert_eq!(with_conflicts.cpu_read(0x8000), Some(0));
+
+        // Writing over a byte of the fixed bank, which holds 7
+        with_conflicts.cpu_write(0xc000, 0xff);
+        assert_eq!(with_conflicts.cpu_read(0x8000), Some(7));
+
+        let mut without = Uxrom::new(&cartridge(2, 1, 8, 0));
+        without.cpu_write(0x8000, 3);
+        assert_eq!(without.cpu_read(0x8000), Some(3));
+    }
+
+    #[test]
+    fn test_8k_prg() {
+        let mut cartridge = cartridge(2, 0, 0, 0);
+        cartridge.prg_rom = (0..0x2000).map(|n| n as u8).collect();
+
+        // Both banks of UxROM and all of AxROM's 32K window mirror it
+        let uxrom = Uxrom::new(&cartridge);
+        assert_eq!(uxrom.cpu_peek(0x8001), Some(1));
+        assert_eq!(uxrom.cpu_peek(0xfffc), Some(0xfc));
+        let axrom = Axrom::new(&cartridge);
+        assert_eq!(axrom.cpu_peek(0xfffc), Some(0xfc));
+    }
+
+    #[test]
+    fn test_cnrom_chr_banks() {
+        let mut cnrom = Cnrom::new(&cartridge(3, 1, 2, 4));
+
+        assert_eq!(cnrom.ppu_read(0x0000), 0);
+        cnrom.cpu_write(0x8000, 2);
+        assert_eq!(cnrom.ppu_read(0x1fff), 2);
+        assert_eq!(cnrom.cpu_read(0xc000), Some(1));
+    }
+
+    #[test]
+    fn test_axrom_banks_and_mirroring() {
+        let mut axrom = Axrom::new(&cartridge(7, 0, 8, 0));
+
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+
+        axrom.cpu_write(0x8000, 0x12);
+        assert_eq!(axrom.cpu_read(0x8000), Some(4));
+        assert_eq!(axrom.cpu_read(0xc000), Some(5));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_axrom_bus_conflicts_submapper() {
+        let mut amrom = Axrom::new(&cartridge(7, 2, 8, 0));
+        amrom.cpu_write(0x8000, 0x13);
+
+        assert_eq!(amrom.cpu_read(0x8000), Some(0));
+        assert_eq!(amrom.mirroring(), Mirroring::SingleScreenLower);
+    }
+}
@@ -0,0 +1,264 @@
+// The 24C02 serial EEPROM some Bandai boards save to: 256 bytes behind a
+// two wire (I2C) bus. The mapper drives the clock and data lines from a
+// register and reads the data line back, everything else happens here.
+// Bits go most significant first, the receiver samples the data line while
+// the clock is high and the sender changes it while the clock is low. A
+// falling data line with the clock high starts a transfer, a rising one
+// stops it.
+
+const SIZE: usize = 256;
+// Writes wrap around inside pages of this many bytes
+const PAGE_SIZE: u8 = 8;
+// 1010 in the upper nibble of the first byte picks the EEPROM
+const DEVICE_CODE: u8 = 0xa0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mode {
+    // Waiting for a start condition
+    Idle,
+    DeviceAddress,
+    WordAddress,
+    Write,
+    Read,
+}
+
+pub struct Eeprom24C02 {
+    data: Vec<u8>,
+    mode: Mode,
+    address: u8,
+    // Clocks done in the current byte, the ninth one is the acknowledge
+    bit: u8,
+    shift: u8,
+    scl: bool,
+    sda: bool,
+    // The EEPROM's side of the open drain data line, true leaves it high
+    output: bool,
+}
+
+impl Eeprom24C02 {
+    pub fn new() -> Self {
+        Self {
+            data: vec![0xff; SIZE],
+            mode: Mode::Idle,
+            address: 0,
+            bit: 0,
+            shift: 0,
+            scl: false,
+            sda: true,
+            output: true,
+        }
+    }
+
+    // The data line as the mapper reads it back
+    pub fn sda(&self) -> bool {
+        self.output && self.sda
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    // Sets the lines the mapper drives
+    pub fn write_lines(&mut self, scl: bool, sda: bool) {
+        if scl && self.scl && sda != self.sda {
+            self.mode = if sda { Mode::Idle } else { Mode::DeviceAddress };
+            self.bit = 0;
+            self.shift = 0;
+            self.output = true;
+        } else if scl && !self.scl {
+            self.clock_rise(sda);
+        } else if !scl && self.scl {
+            self.clock_fall();
+        }
+
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    fn clock_rise(&mut self, sda: bool) {
+        match self.mode {
+            Mode::Idle => return,
+            // A missing acknowledge from the reader ends the read
+            Mode::Read if self.bit == 8 && sda => self.mode = Mode::Idle,
+            Mode::Read => {}
+            _ if self.bit < 8 => self.shift = self.shift << 1 | sda as u8,
+            _ => {}
+        }
+
+        self.bit += 1;
+    }
+
+    fn clock_fall(&mut self) {
+        match (self.mode, self.bit) {
+            (Mode::Idle, _) => {}
+            (Mode::Read, 8) => {
+                self.address = self.address.wrapping_add(1);
+                self.output = true;
+            }
+            (_, 8) => self.receive(),
+            (mode, 9) => {
+                if mode == Mode::DeviceAddress {
+                    self.mode = Mode::Read;
+                }
+                self.bit = 0;
+                self.shift = 0;
+                self.output =
+                    self.mode != Mode::Read || self.data[self.address as usize] & 0x80 != 0;
+            }
+            (Mode::Read, bit) => {
+                self.output = self.data[self.address as usize] & (0x80 >> bit) != 0;
+            }
+            _ => {}
+        }
+    }
+
+    // Handles a whole byte from the mapper
+    fn receive(&mut self) {
+        let byte = self.shift;
+
+        self.mode = match self.mode {
+            Mode::DeviceAddress if byte & 0xf0 != DEVICE_CODE => Mode::Idle,
+            // Reading only starts after the acknowledge, which is still
+            // the EEPROM's to give
+            Mode::DeviceAddress if byte & 0x01 != 0 => Mode::DeviceAddress,
+            Mode::DeviceAddress => Mode::WordAddress,
+            Mode::WordAddress => {
+                self.address = byte;
+                Mode::Write
+            }
+            _ => {
+                self.data[self.address as usize] = byte;
+                let page = self.address & !(PAGE_SIZE - 1);
+                self.address = page | (self.address.wrapping_add(1) & (PAGE_SIZE - 1));
+                Mode::Write
+            }
+        };
+
+        // Pulling the data line low acknowledges the byte, bytes for other
+        // devices go unanswered
+        self.output = self.mode == Mode::Idle;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // The mapper side of the bus, for driving the EEPROM the way a game
+    // does. The line function sets the clock and data lines and returns the
+    // data line as it reads back.
+    pub type Lines<'a> = &'a mut dyn FnMut(bool, bool) -> bool;
+
+    pub fn start(lines: Lines) {
+        lines(false, true);
+        lines(true, true);
+        lines(true, false);
+        lines(false, false);
+    }
+
+    pub fn stop(lines: Lines) {
+        lines(false, false);
+        lines(true, false);
+        lines(true, true);
+    }
+
+    // Sends a byte and returns whether it was acknowledged
+    pub fn send(lines: Lines, byte: u8) -> bool {
+        for bit in (0..8).rev() {
+            let sda = byte >> bit & 1 != 0;
+            lines(false, sda);
+            lines(true, sda);
+            lines(false, sda);
+        }
+        lines(false, true);
+        let ack = !lines(true, true);
+        lines(false, true);
+        ack
+    }
+
+    pub fn receive(lines: Lines, ack: bool) -> u8 {
+        let mut byte = 0;
+        for _ in 0..8 {
+            lines(false, true);
+            byte = byte << 1 | lines(true, true) as u8;
+            lines(false, true);
+        }
+        lines(false, !ack);
+        lines(true, !ack);
+        lines(false, !ack);
+        byte
+    }
+
+    fn bus(eeprom: &mut Eeprom24C02) -> impl FnMut(bool, bool) -> bool + '_ {
+        |scl, sda| {
+            eeprom.write_lines(scl, sda);
+            eeprom.sda()
+        }
+    }
+
+    #[test]
+    fn test_write_then_random_read() {
+        let mut eeprom = Eeprom24C02::new();
+        {
+            let lines = &mut bus(&mut eeprom);
+
+            start(lines);
+            assert!(send(lines, 0xa0));
+            assert!(send(lines, 0x10));
+            assert!(send(lines, 0x12));
+            assert!(send(lines, 0x34));
+            stop(lines);
+
+            start(lines);
+            assert!(send(lines, 0xa0));
+            assert!(send(lines, 0x10));
+            start(lines);
+            assert!(send(lines, 0xa1));
+            assert_eq!(receive(lines, true), 0x12);
+            assert_eq!(receive(lines, false), 0x34);
+            stop(lines);
+        }
+
+        assert_eq!(&eeprom.data()[0x10..0x12], &[0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_page_write_wraps() {
+        let mut eeprom = Eeprom24C02::new();
+        {
+            let lines = &mut bus(&mut eeprom);
+
+            start(lines);
+            send(lines, 0xa0);
+            send(lines, 0x0e);
+            for byte in 1..=3 {
+                send(lines, byte);
+            }
+            stop(lines);
+        }
+
+        assert_eq!(eeprom.data()[0x0e], 1);
+        assert_eq!(eeprom.data()[0x0f], 2);
+        assert_eq!(eeprom.data()[0x08], 3);
+        assert_eq!(eeprom.data()[0x10], 0xff);
+    }
+
+    #[test]
+    fn test_other_devices_are_ignored() {
+        let mut eeprom = Eeprom24C02::new();
+        {
+            let lines = &mut bus(&mut eeprom);
+
+            start(lines);
+            assert!(!send(lines, 0x50));
+            assert!(!send(lines, 0x00));
+            stop(lines);
+        }
+
+        assert!(eeprom.data().iter().all(|byte| *byte == 0xff));
+    }
+}
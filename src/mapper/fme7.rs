@@ -0,0 +1,240 @@
+use super::{bank_offset, ChrMemory, Mapper, Mirroring, Sunsoft5bAudio};
+use crate::cartridge::Cartridge;
+
+// Mapper 69, Sunsoft's FME-7 and the 5B, which is the same chip with a
+// sound generator added. Registers are picked by writing their number to
+// $8000 and written through $A000: eight 1K CHR banks, an 8K bank of ROM or
+// RAM at $6000, three 8K PRG banks, mirroring and a 16 bit IRQ counter
+// that counts down every CPU cycle.
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    prg_banks: [u8; 4],
+    mirroring: u8,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5bAudio,
+}
+
+impl Fme7 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; ram_size.max(0x2000)],
+            chr: ChrMemory::new(cartridge),
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring: 0,
+            irq_enabled: false,
+            counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x6000..=0x7fff => self.prg_banks[0] & 0x3f,
+            0x8000..=0x9fff => self.prg_banks[1],
+            0xa000..=0xbfff => self.prg_banks[2],
+            0xc000..=0xdfff => self.prg_banks[3],
+            _ => return bank_offset(len, 0x2000, (len / 0x2000).max(1) - 1, addr),
+        };
+
+        bank_offset(len, 0x2000, bank as usize, addr)
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => self.prg_banks[0] = data,
+            0x9..=0xb => self.prg_banks[self.command as usize - 0x8] = data & 0x3f,
+            0xc => self.mirroring = data & 0x03,
+            0xd => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0xff) | (data as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        let ram_selected = self.prg_banks[0] & 0x40 != 0;
+        let ram_enabled = self.prg_banks[0] & 0x80 != 0;
+
+        match addr {
+            0x6000..=0x7fff if ram_selected && ram_enabled => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            // Selected but disabled RAM leaves the bus open
+            0x6000..=0x7fff if ram_selected => None,
+            0x6000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.prg_banks[0] & 0xc0 == 0xc0 => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xdfff => self.audio.write_address(data),
+            0xe000..=0xffff => self.audio.write_data(data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr as usize & 0x1fff) / 0x400];
+        self.chr
+            .read(bank_offset(self.chr.len(), 0x400, bank as usize, addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize & 0x1fff) / 0x400];
+        let offset = bank_offset(self.chr.len(), 0x400, bank as usize, addr);
+        self.chr.write(offset, data);
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xffff && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn audio_channels(&self) -> Vec<&'static str> {
+        Sunsoft5bAudio::CHANNELS.to_vec()
+    }
+
+    fn set_audio_gains(&mut self, gains: &[f32]) {
+        self.audio.set_gains(gains);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::banked_cartridge;
+    use super::*;
+
+    fn cartridge() -> Cartridge {
+        banked_cartridge(69, 0, 16, 0x2000, 256, 0x400)
+    }
+
+    fn write_register(fme7: &mut Fme7, command: u8, data: u8) {
+        fme7.cpu_write(0x8000, command);
+        fme7.cpu_write(0xa000, data);
+    }
+
+    #[test]
+    fn test_banks_and_mirroring() {
+        let mut fme7 = Fme7::new(&cartridge());
+        write_register(&mut fme7, 0x9, 3);
+        write_register(&mut fme7, 0xa, 4);
+        write_register(&mut fme7, 0xb, 5);
+        write_register(&mut fme7, 0x5, 0x55);
+        write_register(&mut fme7, 0xc, 1);
+
+        assert_eq!(fme7.cpu_read(0x8000), Some(3));
+        assert_eq!(fme7.cpu_read(0xa000), Some(4));
+        assert_eq!(fme7.cpu_read(0xc000), Some(5));
+        assert_eq!(fme7.cpu_read(0xe000), Some(15));
+        assert_eq!(fme7.ppu_read(0x1400), 0x55);
+        assert_eq!(fme7.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_rom_or_ram_at_6000() {
+        let mut fme7 = Fme7::new(&cartridge());
+        write_register(&mut fme7, 0x8, 0x07);
+        assert_eq!(fme7.cpu_read(0x6000), Some(7));
+
+        write_register(&mut fme7, 0x8, 0x40);
+        assert_eq!(fme7.cpu_read(0x6000), None);
+
+        write_register(&mut fme7, 0x8, 0xc0);
+        fme7.cpu_write(0x6000, 0x12);
+        assert_eq!(fme7.cpu_read(0x6000), Some(0x12));
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut fme7 = Fme7::new(&cartridge());
+        write_register(&mut fme7, 0xe, 2);
+        write_register(&mut fme7, 0xf, 0);
+        write_register(&mut fme7, 0xd, 0x81);
+
+        fme7.cpu_clock();
+        fme7.cpu_clock();
+        assert!(!fme7.irq());
+        fme7.cpu_clock();
+        assert!(fme7.irq());
+
+        // Writing the control register acknowledges it
+        write_register(&mut fme7, 0xd, 0x80);
+        assert!(!fme7.irq());
+    }
+
+    #[test]
+    fn test_audio_ports() {
+        let mut fme7 = Fme7::new(&cartridge());
+        fme7.cpu_write(0xc000, 0x07);
+        fme7.cpu_write(0xe000, 0b111_110);
+        fme7.cpu_write(0xc000, 0x08);
+        fme7.cpu_write(0xe000, 0x0f);
+
+        let mut peak: f32 = 0.0;
+        for _ in 0..100 {
+            fme7.cpu_clock();
+            peak = peak.max(fme7.audio_output());
+        }
+        assert!(peak > 0.1);
+    }
+}
@@ -0,0 +1,351 @@
+use super::{bank_offset, ChrMemory, Mapper, Mirroring};
+use crate::cartridge::Cartridge;
+
+// Mapper 1, the MMC1 found on the SxROM boards. The registers are loaded
+// one bit at a time through a 5 bit shift register that every write to
+// $8000-$FFFF feeds, and the fifth write copies it into the register picked
+// by address bits 13 and 14.
+//
+// The bigger boards reuse the upper CHR bank bits, since they come with
+// only 8K of CHR-RAM: SUROM and SXROM select the 256K half of their 512K
+// PRG-ROM with bit 4, SOROM and SXROM select the 8K PRG-RAM bank with bits
+// 3 and 2-3.
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+
+    // Writes on the cycle right after another one are dropped, which is
+    // what happens to the second write of read-modify-write instructions
+    cycle: u64,
+    last_write_cycle: Option<u64>,
+    // Which CHR register the board is looking at in 4K mode depends on the
+    // pattern table the PPU fetched from last
+    last_chr_a12: bool,
+}
+
+impl Mmc1 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; ram_size.max(0x2000)],
+            chr: ChrMemory::new(cartridge),
+            shift: 0,
+            shift_count: 0,
+            // Starts in PRG mode 3 with the last bank fixed at $C000
+            control: 0x0c,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_write_cycle: None,
+            last_chr_a12: false,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9fff => self.control = value,
+            0xa000..=0xbfff => self.chr_bank_0 = value,
+            0xc000..=0xdfff => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    // The CHR register whose upper bits currently drive the extra PRG lines
+    fn outer_bank_register(&self) -> u8 {
+        if self.control & 0x10 != 0 && self.last_chr_a12 {
+            self.chr_bank_1
+        } else {
+            self.chr_bank_0
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        // 512K boards pick the 256K half with bit 4 of the CHR register
+        let outer = if self.prg_rom.len() > 0x40000 {
+            (self.outer_bank_register() & 0x10) as usize
+        } else {
+            0
+        };
+        let bank = (self.prg_bank & 0x0f) as usize;
+        let upper_half = addr >= 0xc000;
+
+        let selected = match (self.control >> 2) & 0x03 {
+            0 | 1 => (bank & !1) | upper_half as usize,
+            2 if upper_half => bank,
+            2 => 0,
+            _ if upper_half => 0x0f,
+            _ => bank,
+        };
+
+        bank_offset(self.prg_rom.len(), 0x4000, outer | selected, addr)
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> usize {
+        let bank = match self.prg_ram.len() {
+            // SXROM
+            0x8000 => ((self.outer_bank_register() >> 2) & 0x03) as usize,
+            // SOROM
+            0x4000 => ((self.outer_bank_register() >> 3) & 0x01) as usize,
+            _ => 0,
+        };
+
+        (bank * 0x2000 + (addr as usize & 0x1fff)) % self.prg_ram.len()
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let upper = addr >= 0x1000;
+
+        let bank = if self.control & 0x10 == 0 {
+            (self.chr_bank_0 & 0x1e) as usize | upper as usize
+        } else if upper {
+            self.chr_bank_1 as usize
+        } else {
+            self.chr_bank_0 as usize
+        };
+
+        bank * 0x1000 + (addr as usize & 0x0fff)
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                Some(self.prg_ram[self.prg_ram_offset(addr)])
+            }
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                let offset = self.prg_ram_offset(addr);
+                self.prg_ram[offset] = data;
+            }
+            0x8000..=0xffff => {
+                let consecutive = matches!(self.last_write_cycle,
+                    Some(last) if self.cycle - last < 2);
+                self.last_write_cycle = Some(self.cycle);
+                if consecutive {
+                    return;
+                }
+
+                if data & 0x80 != 0 {
+                    self.shift = 0;
+                    self.shift_count = 0;
+                    self.control |= 0x0c;
+                    return;
+                }
+
+                self.shift |= (data & 0x01) << self.shift_count;
+                self.shift_count += 1;
+
+                if self.shift_count == 5 {
+                    self.write_register(addr, self.shift);
+                    self.shift = 0;
+                    self.shift_count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.last_chr_a12 = addr & 0x1000 != 0;
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.last_chr_a12 = addr & 0x1000 != 0;
+        self.chr.write(self.chr_offset(addr), data);
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cartridge(prg_banks: usize, ram_size: usize) -> Cartridge {
+        let mut cartridge = Cartridge::from_program(&[]);
+        cartridge.mapper = 1;
+        cartridge.prg_rom = (0..prg_banks * 0x4000)
+            .map(|n| (n / 0x4000) as u8)
+            .collect();
+        cartridge.prg_ram_size = ram_size;
+        cartridge
+    }
+
+    fn write_register(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.cpu_write(addr, (value >> bit) & 0x01);
+            mmc1.cpu_clock();
+            mmc1.cpu_clock();
+        }
+    }
+
+    #[test]
+    fn test_power_on_fixes_last_bank() {
+        let mut mmc1 = Mmc1::new(&cartridge(8, 0x2000));
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xc000), Some(7));
+    }
+
+    #[test]
+    fn test_8k_prg_repeats() {
+        let mut cartridge = cartridge(0, 0x2000);
+        cartridge.prg_rom = (0..0x2000).map(|n| n as u8).collect();
+        let mmc1 = Mmc1::new(&cartridge);
+
+        assert_eq!(mmc1.cpu_peek(0xa001), Some(1));
+        assert_eq!(mmc1.cpu_peek(0xfffc), Some(0xfc));
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mmc1 = Mmc1::new(&cartridge(8, 0x2000));
+
+        write_register(&mut mmc1, 0xe000, 5);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(5));
+        assert_eq!(mmc1.cpu_read(0xc000), Some(7));
+
+        // Mode 2 fixes the first bank at $8000 instead
+        write_register(&mut mmc1, 0x8000, 0b01000);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xc000), Some(5));
+
+        // 32K mode ignores the low bit
+        write_register(&mut mmc1, 0x8000, 0b00000);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(4));
+        assert_eq!(mmc1.cpu_read(0xc000), Some(5));
+    }
+
+    #[test]
+    fn test_reset_bit_clears_shift_register() {
+        let mut mmc1 = Mmc1::new(&cartridge(8, 0x2000));
+        write_register(&mut mmc1, 0x8000, 0b00000);
+
+        mmc1.cpu_write(0xe000, 1);
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0xe000, 0x80);
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        write_register(&mut mmc1, 0xe000, 2);
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(2));
+        // and goes back to PRG mode 3
+        assert_eq!(mmc1.cpu_read(0xc000), Some(7));
+    }
+
+    #[test]
+    fn test_consecutive_writes_are_ignored() {
+        let mut mmc1 = Mmc1::new(&cartridge(8, 0x2000));
+
+        // Like an INC on $E000, the second write comes on the next cycle
+        for bit in [1, 1, 0, 0, 0] {
+            mmc1.cpu_write(0xe000, bit);
+            mmc1.cpu_clock();
+            mmc1.cpu_write(0xe000, 0);
+            mmc1.cpu_clock();
+            mmc1.cpu_clock();
+        }
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(3));
+    }
+
+    #[test]
+    fn test_mirroring_and_chr_banks() {
+        let mut cart = cartridge(2, 0x2000);
+        cart.chr_rom = (0..0x8000).map(|n| (n / 0x1000) as u8).collect();
+        let mut mmc1 = Mmc1::new(&cart);
+
+        write_register(&mut mmc1, 0x8000, 0b1_11_10);
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+
+        write_register(&mut mmc1, 0xa000, 3);
+        write_register(&mut mmc1, 0xc000, 6);
+        assert_eq!(mmc1.ppu_read(0x0000), 3);
+        assert_eq!(mmc1.ppu_read(0x1000), 6);
+
+        // 8K mode uses the first register with the low bit ignored
+        write_register(&mut mmc1, 0x8000, 0b0_11_11);
+        assert_eq!(mmc1.mirroring(), Mirroring::Horizontal);
+        assert_eq!(mmc1.ppu_read(0x0000), 2);
+        assert_eq!(mmc1.ppu_read(0x1000), 3);
+    }
+
+    #[test]
+    fn test_prg_ram_disable() {
+        let mut mmc1 = Mmc1::new(&cartridge(2, 0x2000));
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x42));
+
+        write_register(&mut mmc1, 0xe000, 0x10);
+        assert_eq!(mmc1.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_surom_outer_bank() {
+        let mut mmc1 = Mmc1::new(&cartridge(32, 0x2000));
+
+        write_register(&mut mmc1, 0xe000, 1);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(1));
+        assert_eq!(mmc1.cpu_read(0xc000), Some(15));
+
+        write_register(&mut mmc1, 0xa000, 0x10);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(17));
+        assert_eq!(mmc1.cpu_read(0xc000), Some(31));
+    }
+
+    #[test]
+    fn test_sxrom_ram_banks() {
+        let mut mmc1 = Mmc1::new(&cartridge(32, 0x8000));
+
+        mmc1.cpu_write(0x6000, 1);
+        write_register(&mut mmc1, 0xa000, 0b0_1000);
+        mmc1.cpu_write(0x6000, 2);
+
+        assert_eq!(mmc1.cpu_read(0x6000), Some(2));
+        write_register(&mut mmc1, 0xa000, 0);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(1));
+    }
+}
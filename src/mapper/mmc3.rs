@@ -0,0 +1,400 @@
+use super::{bank_offset, ChrMemory, Mapper, Mirroring};
+use crate::cartridge::Cartridge;
+
+// Mapper 4, the MMC3 of the TxROM boards. Eight bank registers are written
+// through a select/data register pair, and a counter clocked by rising
+// edges of PPU A12 raises an IRQ after a programmable number of scanlines,
+// since the PPU fetches background and sprite tiles from opposite pattern
+// tables once per line.
+//
+// The MMC3A (NEC) and the later MMC3B/C (Sharp) chips differ in what
+// happens when the counter reaches zero: the Sharp ones raise an IRQ on
+// every clock that leaves it at zero, the NEC one only when the counter got
+// there by counting down or through a $C001 reload.
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+    four_screen: bool,
+
+    bank_select: u8,
+    banks: [u8; 8],
+    horizontal: bool,
+    prg_ram_protect: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    nec: bool,
+
+    // A12 has to stay low for a few CPU cycles before a rising edge clocks
+    // the counter, which filters out the short drops between the sprite
+    // fetches of a scanline
+    cycle: u64,
+    a12_low_since: Option<u64>,
+}
+
+// CPU cycles A12 has to stay low for the next rise to count
+const A12_FILTER_CYCLES: u64 = 3;
+
+impl Mmc3 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; ram_size.max(0x2000)],
+            chr: ChrMemory::new(cartridge),
+            four_screen: cartridge.mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            horizontal: cartridge.mirroring == Mirroring::Horizontal,
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            nec: cartridge.submapper == 4,
+            cycle: 0,
+            a12_low_since: Some(0),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / 0x2000;
+        let second_last = banks.saturating_sub(2);
+        let swapped = self.bank_select & 0x40 != 0;
+
+        let bank = match (addr >> 13) & 0x03 {
+            0 if swapped => second_last,
+            0 => (self.banks[6] & 0x3f) as usize,
+            1 => (self.banks[7] & 0x3f) as usize,
+            2 if swapped => (self.banks[6] & 0x3f) as usize,
+            2 => second_last,
+            _ => banks.max(1) - 1,
+        };
+
+        bank_offset(self.prg_rom.len(), 0x2000, bank, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // CHR inversion swaps the two pattern tables
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+
+        match addr & 0x1fff {
+            0x0000..=0x07ff => {
+                bank_offset(self.chr.len(), 0x0800, (self.banks[0] >> 1) as usize, addr)
+            }
+            0x0800..=0x0fff => {
+                bank_offset(self.chr.len(), 0x0800, (self.banks[1] >> 1) as usize, addr)
+            }
+            _ => {
+                let register = 2 + ((addr as usize & 0x0fff) >> 10);
+                bank_offset(self.chr.len(), 0x0400, self.banks[register] as usize, addr)
+            }
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect & 0x80 != 0
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_enabled() && self.prg_ram_protect & 0x40 == 0
+    }
+
+    // Follows A12 on the PPU address bus and clocks the counter on the
+    // rising edges that pass the filter
+    fn watch_a12(&mut self, addr: u16) {
+        if addr & 0x1000 == 0 {
+            if self.a12_low_since.is_none() {
+                self.a12_low_since = Some(self.cycle);
+            }
+            return;
+        }
+
+        if let Some(since) = self.a12_low_since.take() {
+            if self.cycle - since >= A12_FILTER_CYCLES {
+                self.clock_irq_counter();
+            }
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let reloaded = self.irq_reload;
+        let before = self.irq_counter;
+
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        let raise = if self.nec {
+            self.irq_counter == 0 && (before != 0 || reloaded)
+        } else {
+            self.irq_counter == 0
+        };
+        if raise && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let even = addr & 0x01 == 0;
+
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_writable() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+            0x8000..=0x9fff if even => self.bank_select = data,
+            0x8000..=0x9fff => self.banks[(self.bank_select & 0x07) as usize] = data,
+            0xa000..=0xbfff if even => self.horizontal = data & 0x01 != 0,
+            0xa000..=0xbfff => self.prg_ram_protect = data,
+            0xc000..=0xdfff if even => self.irq_latch = data,
+            0xc000..=0xdfff => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            0xe000..=0xffff if even => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            0xe000..=0xffff => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.watch_a12(addr);
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.watch_a12(addr);
+        self.chr.write(self.chr_offset(addr), data);
+    }
+
+    fn ppu_address(&mut self, addr: u16) {
+        self.watch_a12(addr);
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.four_screen {
+            Mirroring::FourScreen
+        } else if self.horizontal {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::banked_cartridge;
+    use super::*;
+    use crate::cpu::CPU;
+
+    fn cartridge(submapper: u8) -> Cartridge {
+        banked_cartridge(4, submapper, 16, 0x2000, 64, 0x400)
+    }
+
+    // What the PPU does to A12 over one rendered scanline with background
+    // tiles at $0000 and sprites at $1000
+    fn scanline(mmc3: &mut Mmc3) {
+        mmc3.ppu_read(0x0000);
+        for _ in 0..85 {
+            mmc3.cpu_clock();
+        }
+        mmc3.ppu_read(0x1000);
+        for _ in 0..29 {
+            mmc3.cpu_clock();
+        }
+    }
+
+    fn setup_irq(mmc3: &mut Mmc3, latch: u8) {
+        mmc3.cpu_write(0xc000, latch);
+        mmc3.cpu_write(0xc001, 0);
+        mmc3.cpu_write(0xe001, 0);
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mmc3 = Mmc3::new(&cartridge(0));
+        mmc3.cpu_write(0x8000, 6);
+        mmc3.cpu_write(0x8001, 3);
+        mmc3.cpu_write(0x8000, 7);
+        mmc3.cpu_write(0x8001, 5);
+
+        assert_eq!(mmc3.cpu_read(0x8000), Some(3));
+        assert_eq!(mmc3.cpu_read(0xa000), Some(5));
+        assert_eq!(mmc3.cpu_read(0xc000), Some(14));
+        assert_eq!(mmc3.cpu_read(0xe000), Some(15));
+
+        mmc3.cpu_write(0x8000, 0x40);
+        assert_eq!(mmc3.cpu_read(0x8000), Some(14));
+        assert_eq!(mmc3.cpu_read(0xa000), Some(5));
+        assert_eq!(mmc3.cpu_read(0xc000), Some(3));
+    }
+
+    #[test]
+    fn test_chr_banks_and_inversion() {
+        let mut mmc3 = Mmc3::new(&cartridge(0));
+        for (register, bank) in [(0, 9), (1, 12), (2, 40), (5, 50)] {
+            mmc3.cpu_write(0x8000, register);
+            mmc3.cpu_write(0x8001, bank);
+        }
+
+        // The 2K banks ignore their low bit
+        assert_eq!(mmc3.ppu_read(0x0000), 8);
+        assert_eq!(mmc3.ppu_read(0x0400), 9);
+        assert_eq!(mmc3.ppu_read(0x0800), 12);
+        assert_eq!(mmc3.ppu_read(0x1000), 40);
+        assert_eq!(mmc3.ppu_read(0x1c00), 50);
+
+        mmc3.cpu_write(0x8000, 0x80);
+        assert_eq!(mmc3.ppu_read(0x1000), 8);
+        assert_eq!(mmc3.ppu_read(0x0000), 40);
+    }
+
+    #[test]
+    fn test_mirroring_and_prg_ram_protect() {
+        let mut mmc3 = Mmc3::new(&cartridge(0));
+
+        mmc3.cpu_write(0xa000, 1);
+        assert_eq!(mmc3.mirroring(), Mirroring::Horizontal);
+        mmc3.cpu_write(0xa000, 0);
+        assert_eq!(mmc3.mirroring(), Mirroring::Vertical);
+
+        mmc3.cpu_write(0x6000, 0x11);
+        mmc3.cpu_write(0xa001, 0xc0);
+        mmc3.cpu_write(0x6000, 0x22);
+        assert_eq!(mmc3.cpu_read(0x6000), Some(0x11));
+
+        mmc3.cpu_write(0xa001, 0x00);
+        assert_eq!(mmc3.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc3 = Mmc3::new(&cartridge(0));
+        setup_irq(&mut mmc3, 2);
+
+        // Reload, then two decrements
+        scanline(&mut mmc3);
+        scanline(&mut mmc3);
+        assert!(!mmc3.irq());
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+
+        mmc3.cpu_write(0xe000, 0);
+        assert!(!mmc3.irq());
+    }
+
+    #[test]
+    fn test_a12_filter() {
+        let mut mmc3 = Mmc3::new(&cartridge(0));
+        setup_irq(&mut mmc3, 0);
+        scanline(&mut mmc3);
+        mmc3.cpu_write(0xe000, 0);
+        mmc3.cpu_write(0xe001, 0);
+
+        // Drops shorter than the filter don't count as new edges
+        mmc3.ppu_address(0x0000);
+        mmc3.cpu_clock();
+        mmc3.ppu_read(0x1000);
+        assert!(!mmc3.irq());
+    }
+
+    #[test]
+    fn test_zero_latch_sharp_vs_nec() {
+        let mut sharp = Mmc3::new(&cartridge(0));
+        let mut nec = Mmc3::new(&cartridge(4));
+        setup_irq(&mut sharp, 0);
+        setup_irq(&mut nec, 0);
+
+        // The reload after $C001 raises the IRQ on both
+        scanline(&mut sharp);
+        scanline(&mut nec);
+        assert!(sharp.irq());
+        assert!(nec.irq());
+
+        // but reloading a counter that's already at zero only does on Sharp
+        for mmc3 in [&mut sharp, &mut nec] {
+            mmc3.cpu_write(0xe000, 0);
+            mmc3.cpu_write(0xe001, 0);
+            scanline(mmc3);
+        }
+        assert!(sharp.irq());
+        assert!(!nec.irq());
+    }
+
+    #[test]
+    fn test_rendering_raises_cpu_irq() {
+        let mut cpu = CPU::new();
+        cpu.insert_cartridge(Box::new(Mmc3::new(&cartridge(0))));
+        cpu.reset();
+        // Both the reset and IRQ vectors read $0F0F out of the last bank,
+        // where an ADC #$00 waits
+        cpu.mem_write(0x0f0f, 0x69);
+        cpu.mem_write(0x0f10, 0x00);
+
+        cpu.mem_write(0xc000, 3);
+        cpu.mem_write(0xc001, 0);
+        cpu.mem_write(0xe001, 0);
+        // Background tiles from $0000 and sprites from $1000, so A12 rises
+        // once a line when the sprite fetches start
+        cpu.mem_write(0x2000, 0x08);
+        cpu.mem_write(0x2001, 0x18);
+
+        // Reloaded on line 0, then counted down to zero on line 3
+        while !cpu.irq_line() {
+            cpu.idle(1);
+        }
+        assert_eq!(cpu.ppu.scanline, 3);
+        assert!((261..=270).contains(&cpu.ppu.dot));
+
+        assert!(cpu.step());
+        assert_eq!(cpu.pc, 0x0f11);
+        assert_eq!(cpu.stack_ptr, 0xfd - 3);
+        assert_ne!(cpu.status & 0b0000_0100, 0);
+    }
+}
@@ -0,0 +1,804 @@
+use super::{bank_offset, ChrMemory, Mapper, Mirroring, Mmc5Audio};
+use crate::cartridge::Cartridge;
+
+// Mapper 5, the MMC5 of the ExROM boards. It has four PRG and CHR banking
+// modes, 1K of extra RAM (ExRAM) usable as a nametable, as per tile
+// attributes or as plain RAM, a fill mode nametable, a vertical split
+// screen, a scanline IRQ, a multiplier and its own sound channels.
+//
+// The chip has no view of the PPU's scanline and dot, it works them out
+// from the PPU's fetches instead: three reads of the same nametable address
+// in a row only happen at the start of a scanline, and after that the order
+// of the fetches is fixed, 32 background tiles, 8 sprites with two dummy
+// nametable reads each and the first two tiles of the next line.
+
+// Nametable fetches of a scanline, counted from the one that gets it
+// detected, at which the sprite fetches start and end
+const SPRITE_FETCHES_START: u32 = 32;
+const SPRITE_FETCHES_END: u32 = 48;
+
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+    exram: [u8; 0x400],
+    audio: Mmc5Audio,
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    prg_banks: [u8; 5],
+    // The sprite (A) and background (B) CHR bank sets, with the upper bits
+    // from $5130 already applied
+    chr_banks_a: [u16; 8],
+    chr_banks_b: [u16; 4],
+    chr_upper: u8,
+    last_chr_set_b: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+
+    // What the chip knows about the PPU
+    sprites_8x16: bool,
+    rendering: bool,
+    in_frame: bool,
+    scanline: u8,
+    last_nametable_addr: u16,
+    nametable_repeats: u8,
+    nametable_fetches: u32,
+    idle_cycles: u8,
+
+    // How the tile being fetched gets drawn
+    split_tile: bool,
+    split_y: u16,
+    ext_attribute: Option<u8>,
+}
+
+impl Mmc5 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; ram_size.max(0x2000)],
+            chr: ChrMemory::new(cartridge),
+            exram: [0; 0x400],
+            audio: Mmc5Audio::new(),
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xff],
+            chr_banks_a: [0; 8],
+            chr_banks_b: [0; 4],
+            chr_upper: 0,
+            last_chr_set_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+            sprites_8x16: false,
+            rendering: false,
+            in_frame: false,
+            scanline: 0,
+            last_nametable_addr: 0,
+            nametable_repeats: 0,
+            nametable_fetches: 0,
+            idle_cycles: 0,
+            split_tile: false,
+            split_y: 0,
+            ext_attribute: None,
+        }
+    }
+
+    // Whether ROM is mapped at a CPU address in $8000-$FFFF, and the number
+    // of the 8K ROM or RAM bank
+    fn prg_bank(&self, addr: u16) -> (bool, usize) {
+        let slot = ((addr - 0x8000) >> 13) as usize;
+
+        let (register, size) = match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1, 0 | 1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0 | 1) => (2, 2),
+            (2, slot) => (slot + 1, 1),
+            (_, slot) => (slot + 1, 1),
+        };
+
+        let value = self.prg_banks[register];
+        // $5117 always maps ROM
+        let rom = register == 4 || value & 0x80 != 0;
+        let bank = (value & 0x7f) as usize & !(size - 1) | (slot & (size - 1));
+
+        (rom, bank)
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0] & 0x03 == 0x02 && self.prg_ram_protect[1] & 0x03 == 0x01
+    }
+
+    fn prg_ram_offset(&self, bank: usize, addr: u16) -> usize {
+        bank_offset(self.prg_ram.len(), 0x2000, bank & 0x0f, addr)
+    }
+
+    fn fetching_sprites(&self) -> bool {
+        self.in_frame
+            && (SPRITE_FETCHES_START..SPRITE_FETCHES_END).contains(&self.nametable_fetches)
+    }
+
+    // Which bank set a pattern fetch goes through. 8x16 sprites get their
+    // own set, otherwise the last written set wins.
+    fn uses_chr_set_b(&self) -> bool {
+        if !self.in_frame || !self.sprites_8x16 {
+            return self.last_chr_set_b;
+        }
+
+        !self.fetching_sprites()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let addr = addr & 0x1fff;
+        let slot = addr as usize / 0x400;
+
+        if self.in_frame && !self.fetching_sprites() {
+            if self.split_tile {
+                // The split uses its own fine scroll too
+                let tile = addr as usize & 0x0ff0;
+                let row = (addr & 0x08) as usize | (self.split_y as usize & 0x07);
+                return (self.split_bank as usize * 0x1000 + tile + row) % self.chr.len();
+            }
+            if let Some(ext) = self.ext_attribute {
+                let bank = (ext & 0x3f) as usize | (self.chr_upper as usize & 0x03) << 6;
+                return bank_offset(self.chr.len(), 0x1000, bank, addr);
+            }
+        }
+
+        let (size, bank) = if self.uses_chr_set_b() {
+            match self.chr_mode {
+                0 => (0x2000, self.chr_banks_b[3]),
+                1 => (0x1000, self.chr_banks_b[3]),
+                2 => (0x0800, self.chr_banks_b[((slot / 2) & 1) * 2 + 1]),
+                _ => (0x0400, self.chr_banks_b[slot & 3]),
+            }
+        } else {
+            match self.chr_mode {
+                0 => (0x2000, self.chr_banks_a[7]),
+                1 => (0x1000, self.chr_banks_a[(slot / 4) * 4 + 3]),
+                2 => (0x0800, self.chr_banks_a[(slot / 2) * 2 + 1]),
+                _ => (0x0400, self.chr_banks_a[slot]),
+            }
+        };
+
+        bank_offset(self.chr.len(), size, bank as usize, addr)
+    }
+
+    // Keeps track of where the PPU is from its nametable fetches
+    fn watch_nametable_fetch(&mut self, addr: u16) {
+        self.idle_cycles = 0;
+
+        if addr == self.last_nametable_addr {
+            self.nametable_repeats += 1;
+            if self.nametable_repeats == 2 {
+                self.detect_scanline();
+            }
+        } else {
+            self.last_nametable_addr = addr;
+            self.nametable_repeats = 0;
+        }
+
+        if addr & 0x3ff < 0x3c0 {
+            self.nametable_fetches = self.nametable_fetches.wrapping_add(1);
+        }
+    }
+
+    fn detect_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare {
+                self.irq_pending = true;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+        }
+
+        // The detecting fetch is counted as the first of the line when the
+        // caller's count goes up right after
+        self.nametable_fetches = u32::MAX;
+    }
+
+    // Screen column of the tile being fetched, the first fetch of a line
+    // is the third column since the first two came at the end of the
+    // previous one
+    fn tile_column(&self) -> Option<(u32, bool)> {
+        match self.nametable_fetches {
+            n @ 0..=31 => Some((n + 2, false)),
+            n @ 48..=49 => Some((n - 48, true)),
+            _ => None,
+        }
+    }
+
+    // Whether the split covers the tile being fetched, and if so the tile
+    // and attribute area it reads from ExRAM
+    fn split_position(&self) -> Option<(usize, u16)> {
+        if self.split_control & 0x80 == 0 || self.exram_mode >= 2 {
+            return None;
+        }
+        let (column, next_line) = self.tile_column()?;
+        let column = column.min(31);
+        let threshold = (self.split_control & 0x1f) as u32;
+
+        let inside = if self.split_control & 0x40 != 0 {
+            column >= threshold
+        } else {
+            column < threshold
+        };
+        if !inside {
+            return None;
+        }
+
+        let line = self.scanline as u16 + next_line as u16;
+        let y = (line + self.split_scroll as u16) % 240;
+        Some(((y as usize / 8) * 32 + column as usize, y))
+    }
+
+    fn fetch_nametable(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        let offset = addr as usize & 0x3ff;
+        let attribute = offset >= 0x3c0;
+
+        if self.in_frame {
+            if attribute && self.split_tile {
+                return self.split_attribute();
+            }
+            if let (true, Some(ext)) = (attribute, self.ext_attribute) {
+                return (ext >> 6) * 0x55;
+            }
+        }
+
+        if !attribute && self.in_frame {
+            self.split_tile = false;
+            self.ext_attribute = None;
+
+            if let Some((tile, y)) = self.split_position() {
+                self.split_tile = true;
+                self.split_y = y;
+                return self.exram[tile];
+            }
+            if self.exram_mode == 1 {
+                self.ext_attribute = Some(self.exram[offset]);
+            }
+        }
+
+        let table = (addr as usize >> 10) & 0x03;
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            0 => vram[offset],
+            1 => vram[0x400 + offset],
+            2 if self.exram_mode < 2 => self.exram[offset],
+            2 => 0,
+            _ if attribute => (self.fill_attribute & 0x03) * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    fn split_attribute(&self) -> u8 {
+        let column = self.tile_column().map_or(0, |(column, _)| column.min(31)) as usize;
+        let y = self.split_y as usize;
+        let attribute = self.exram[0x3c0 + (y / 32) * 8 + column / 4];
+        let shift = ((y / 16) & 1) * 4 + ((column / 2) & 1) * 2;
+
+        ((attribute >> shift) & 0x03) * 0x55
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 | 0x5103 => self.prg_ram_protect[(addr - 0x5102) as usize] = data,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = data,
+            0x5120..=0x5127 => {
+                self.chr_banks_a[(addr - 0x5120) as usize] =
+                    data as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_set_b = false;
+            }
+            0x5128..=0x512b => {
+                self.chr_banks_b[(addr - 0x5128) as usize] =
+                    data as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_set_b = true;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        let data = match addr {
+            0x5000..=0x5015 => self.audio.read(addr),
+            _ => self.cpu_peek(addr),
+        };
+
+        // Reading the status acknowledges the IRQ
+        if addr == 0x5204 {
+            self.irq_pending = false;
+        }
+        // Fetching the NMI vector means the frame is over
+        if addr == 0xfffa || addr == 0xfffb {
+            self.in_frame = false;
+            self.last_nametable_addr = 0;
+        }
+        if let Some(data) = data {
+            self.audio.snoop_read(addr, data);
+        }
+
+        data
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5000..=0x5015 => self.audio.peek(addr),
+            0x5204 => Some((self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6),
+            0x5205 => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            0x5c00..=0x5fff if self.exram_mode >= 2 => Some(self.exram[addr as usize - 0x5c00]),
+            0x6000..=0x7fff => {
+                let offset = self.prg_ram_offset(self.prg_banks[0] as usize, addr);
+                Some(self.prg_ram[offset])
+            }
+            0x8000..=0xffff => {
+                let (rom, bank) = self.prg_bank(addr);
+                if rom {
+                    Some(self.prg_rom[bank_offset(self.prg_rom.len(), 0x2000, bank, addr)])
+                } else {
+                    Some(self.prg_ram[self.prg_ram_offset(bank, addr)])
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5015 => {
+                self.audio.write(addr, data);
+            }
+            0x5100..=0x5206 => self.write_register(addr, data),
+            0x5c00..=0x5fff => {
+                let offset = addr as usize - 0x5c00;
+                match self.exram_mode {
+                    // Only written while rendering in the nametable modes
+                    0 | 1 if self.in_frame => self.exram[offset] = data,
+                    0 | 1 => self.exram[offset] = 0,
+                    2 => self.exram[offset] = data,
+                    _ => {}
+                }
+            }
+            0x6000..=0x7fff if self.prg_ram_writable() => {
+                let offset = self.prg_ram_offset(self.prg_banks[0] as usize, addr);
+                self.prg_ram[offset] = data;
+            }
+            0x8000..=0xdfff if self.prg_ram_writable() => {
+                let (rom, bank) = self.prg_bank(addr);
+                if !rom {
+                    let offset = self.prg_ram_offset(bank, addr);
+                    self.prg_ram[offset] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        // Only back to back nametable reads count towards a new scanline
+        self.idle_cycles = 0;
+        self.last_nametable_addr = 0;
+        self.nametable_repeats = 0;
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_offset(addr), data);
+    }
+
+    fn nametable_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        self.watch_nametable_fetch(addr);
+        self.fetch_nametable(addr, vram)
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        let offset = addr as usize & 0x3ff;
+        let table = (addr as usize >> 10) & 0x03;
+
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            0 => vram[offset] = data,
+            1 => vram[0x400 + offset] = data,
+            2 if self.exram_mode < 2 => self.exram[offset] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        match addr & 0x2007 {
+            0x2000 => self.sprites_8x16 = data & 0x20 != 0,
+            0x2001 => {
+                self.rendering = data & 0x18 != 0;
+                if !self.rendering {
+                    self.in_frame = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // The closest fixed layout to the $5105 value, for anything that only
+    // looks at the mirroring
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x44 => Mirroring::Vertical,
+            0x50 => Mirroring::Horizontal,
+            0x55 => Mirroring::SingleScreenUpper,
+            _ => Mirroring::SingleScreenLower,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || self.audio.irq()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.audio.clock();
+
+        // The PPU stops fetching when rendering is off or in vblank
+        if self.idle_cycles < 3 {
+            self.idle_cycles += 1;
+            if self.idle_cycles == 3 {
+                self.in_frame = false;
+                self.last_nametable_addr = 0;
+            }
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn audio_channels(&self) -> Vec<&'static str> {
+        Mmc5Audio::CHANNELS.to_vec()
+    }
+
+    fn set_audio_gains(&mut self, gains: &[f32]) {
+        self.audio.set_gains(gains);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+
+    fn cartridge() -> Cartridge {
+        let mut cartridge = Cartridge::from_program(&[]);
+        cartridge.mapper = 5;
+        // 32 8K PRG banks and 256 1K CHR banks, every byte holding the
+        // number of its bank
+        cartridge.prg_rom = (0..0x40000).map(|n| (n / 0x2000) as u8).collect();
+        cartridge.chr_rom = (0..0x40000).map(|n| (n / 0x400) as u8).collect();
+        cartridge.prg_ram_size = 0x10000;
+        cartridge
+    }
+
+    // The fetches of one rendered scanline as the PPU makes them, with the
+    // nametable reads going through the mapper. Returns the tile and
+    // attribute bytes and the first pattern byte of the 32 tiles.
+    fn scanline(mmc5: &mut Mmc5, vram: &[u8]) -> Vec<(u8, u8, u8)> {
+        let mut tiles = Vec::new();
+
+        for column in 2..34u16 {
+            let tile = mmc5.nametable_read(0x2000 + (column & 31), vram);
+            let attribute = mmc5.nametable_read(0x23c0 + (column & 31) / 4, vram);
+            let pattern = mmc5.ppu_read(tile as u16 * 16);
+            mmc5.ppu_read(tile as u16 * 16 + 8);
+            tiles.push((tile, attribute, pattern));
+        }
+        for _ in 0..8 {
+            mmc5.nametable_read(0x2000, vram);
+            mmc5.nametable_read(0x2000, vram);
+            mmc5.ppu_read(0x1000);
+            mmc5.ppu_read(0x1008);
+        }
+        for column in 0..2 {
+            mmc5.nametable_read(0x2000 + column, vram);
+            mmc5.nametable_read(0x23c0, vram);
+            mmc5.ppu_read(0x0000);
+            mmc5.ppu_read(0x0008);
+        }
+        // The two dummy fetches at the end, which the first fetch of the
+        // next line matches
+        mmc5.nametable_read(0x2002, vram);
+        mmc5.nametable_read(0x2002, vram);
+
+        tiles
+    }
+
+    fn start_frame(mmc5: &mut Mmc5, vram: &[u8]) {
+        mmc5.ppu_register_write(0x2001, 0x18);
+        mmc5.nametable_read(0x2002, vram);
+        mmc5.nametable_read(0x2002, vram);
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+        assert_eq!(mmc5.cpu_read(0xe000), Some(31));
+
+        mmc5.cpu_write(0x5100, 0);
+        mmc5.cpu_write(0x5117, 0x85);
+        assert_eq!(mmc5.cpu_read(0x8000), Some(4));
+        assert_eq!(mmc5.cpu_read(0xe000), Some(7));
+
+        mmc5.cpu_write(0x5100, 1);
+        mmc5.cpu_write(0x5115, 0x83);
+        assert_eq!(mmc5.cpu_read(0x8000), Some(2));
+        assert_eq!(mmc5.cpu_read(0xa000), Some(3));
+        assert_eq!(mmc5.cpu_read(0xc000), Some(4));
+
+        mmc5.cpu_write(0x5100, 3);
+        mmc5.cpu_write(0x5114, 0x89);
+        mmc5.cpu_write(0x5116, 0x8b);
+        assert_eq!(mmc5.cpu_read(0x8000), Some(9));
+        assert_eq!(mmc5.cpu_read(0xa000), Some(3));
+        assert_eq!(mmc5.cpu_read(0xc000), Some(11));
+        assert_eq!(mmc5.cpu_read(0xe000), Some(5));
+    }
+
+    #[test]
+    fn test_prg_ram_banks_and_protect() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+
+        mmc5.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc5.cpu_read(0x6000), Some(0));
+
+        mmc5.cpu_write(0x5102, 0x02);
+        mmc5.cpu_write(0x5103, 0x01);
+        mmc5.cpu_write(0x5113, 0x03);
+        mmc5.cpu_write(0x6000, 0x42);
+
+        // The same RAM bank mapped into $8000 as RAM
+        mmc5.cpu_write(0x5114, 0x03);
+        assert_eq!(mmc5.cpu_read(0x8000), Some(0x42));
+    }
+
+    #[test]
+    fn test_chr_modes() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+
+        mmc5.cpu_write(0x5101, 3);
+        mmc5.cpu_write(0x5130, 1);
+        mmc5.cpu_write(0x5122, 0x05);
+        assert_eq!(mmc5.ppu_read(0x0800), (0x105 % 256) as u8);
+
+        mmc5.cpu_write(0x5130, 0);
+        mmc5.cpu_write(0x5101, 1);
+        mmc5.cpu_write(0x5127, 3);
+        assert_eq!(mmc5.ppu_read(0x1000), 12);
+
+        // Background set last written
+        mmc5.cpu_write(0x512b, 2);
+        assert_eq!(mmc5.ppu_read(0x1400), 9);
+    }
+
+    #[test]
+    fn test_sprite_and_background_sets_in_8x16_mode() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+        let vram = [0; 0x800];
+        mmc5.cpu_write(0x5101, 3);
+        mmc5.cpu_write(0x5120, 10);
+        mmc5.cpu_write(0x5128, 20);
+        mmc5.ppu_register_write(0x2000, 0x20);
+
+        start_frame(&mut mmc5, &vram);
+        let tiles = scanline(&mut mmc5, &vram);
+        assert!(tiles.iter().all(|(_, _, pattern)| *pattern == 20));
+
+        // Background fetches of the next line, then the first sprite
+        for column in 2..34 {
+            mmc5.nametable_read(0x2000 + (column & 31), &vram);
+            mmc5.nametable_read(0x23c0, &vram);
+        }
+        mmc5.nametable_read(0x2000, &vram);
+        assert_eq!(mmc5.ppu_read(0x0000), 10);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+        let vram = [0; 0x800];
+        mmc5.cpu_write(0x5203, 2);
+        mmc5.cpu_write(0x5204, 0x80);
+
+        start_frame(&mut mmc5, &vram);
+        assert_eq!(mmc5.cpu_read(0x5204), Some(0x00));
+        scanline(&mut mmc5, &vram);
+        assert_eq!(mmc5.cpu_read(0x5204), Some(0x40));
+        scanline(&mut mmc5, &vram);
+        assert!(!mmc5.irq());
+        scanline(&mut mmc5, &vram);
+        assert!(mmc5.irq());
+
+        assert_eq!(mmc5.cpu_read(0x5204), Some(0xc0));
+        assert!(!mmc5.irq());
+
+        // Three idle cycles and the frame is over
+        for _ in 0..3 {
+            mmc5.cpu_clock();
+        }
+        assert_eq!(mmc5.cpu_read(0x5204), Some(0x00));
+    }
+
+    #[test]
+    fn test_nametable_mapping_and_fill_mode() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+        let mut vram = [0; 0x800];
+        vram[0x401] = 0x11;
+
+        // CIRAM page 1, ExRAM, fill mode, CIRAM page 0
+        mmc5.cpu_write(0x5105, 0b00_11_10_01);
+        mmc5.cpu_write(0x5106, 0x77);
+        mmc5.cpu_write(0x5107, 0x02);
+        mmc5.nametable_write(0x2401, 0x22, &mut vram);
+
+        assert_eq!(mmc5.nametable_read(0x2001, &vram), 0x11);
+        assert_eq!(mmc5.nametable_read(0x2401, &vram), 0x22);
+        assert_eq!(mmc5.nametable_read(0x2801, &vram), 0x77);
+        assert_eq!(mmc5.nametable_read(0x2bc0, &vram), 0xaa);
+        assert_eq!(mmc5.nametable_read(0x2c00, &vram), 0x00);
+    }
+
+    #[test]
+    fn test_extended_attributes() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+        let vram = [0; 0x800];
+        // Tile at column 4 uses 4K bank 5 and palette 3
+        mmc5.cpu_write(0x5104, 2);
+        mmc5.cpu_write(0x5c04, 0xc5);
+        mmc5.cpu_write(0x5104, 1);
+        start_frame(&mut mmc5, &vram);
+
+        let tiles = scanline(&mut mmc5, &vram);
+
+        assert_eq!(tiles[2].1, 0xff);
+        assert_eq!(tiles[2].2, 20);
+        assert_eq!(tiles[3].1, 0x00);
+    }
+
+    #[test]
+    fn test_split_screen() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+        let vram = [0; 0x800];
+        // Split covers the columns left of 8, scrolled by 16 lines
+        mmc5.cpu_write(0x5200, 0x88);
+        mmc5.cpu_write(0x5201, 16);
+        mmc5.cpu_write(0x5202, 2);
+        mmc5.cpu_write(0x5104, 2);
+        mmc5.cpu_write(0x5c00 + 2 * 32 + 3, 0x09);
+        mmc5.cpu_write(0x5fc0, 0xc0);
+        mmc5.cpu_write(0x5104, 0);
+        start_frame(&mut mmc5, &vram);
+
+        let tiles = scanline(&mut mmc5, &vram);
+
+        // Column 3 of line 0 reads tile row 2 of the split
+        assert_eq!(tiles[1].0, 0x09);
+        assert_eq!(tiles[1].1, 0xff);
+        assert_eq!(tiles[1].2, 8);
+        // Column 8 is outside of it
+        assert_eq!(tiles[6].1, 0x00);
+        assert_eq!(tiles[6].2, 0);
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mmc5 = Mmc5::new(&cartridge());
+        mmc5.cpu_write(0x5205, 200);
+        mmc5.cpu_write(0x5206, 100);
+
+        assert_eq!(mmc5.cpu_read(0x5205), Some((20000 & 0xff) as u8));
+        assert_eq!(mmc5.cpu_read(0x5206), Some((20000 >> 8) as u8));
+    }
+
+    fn console() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.insert_cartridge(Box::new(Mmc5::new(&cartridge())));
+        cpu
+    }
+
+    #[test]
+    fn test_rendering_raises_scanline_irq() {
+        let mut cpu = console();
+        cpu.mem_write(0x5203, 2);
+        cpu.mem_write(0x5204, 0x80);
+        cpu.mem_write(0x2001, 0x08);
+
+        // The first frame starts without a pre-render line, the second one
+        // is detected from its dummy fetches
+        while cpu.ppu.scanline != 241 {
+            cpu.idle(1);
+        }
+        cpu.mem_read(0x5204);
+        let irq = |cpu: &CPU| cpu.mapper().unwrap().irq();
+        assert!(!irq(&cpu));
+
+        while !irq(&cpu) {
+            cpu.idle(1);
+        }
+        assert_eq!(cpu.ppu.scanline, 2);
+        assert!(cpu.ppu.dot <= 8);
+        // Only a real read acknowledges it
+        assert_eq!(cpu.peek(0x5204), 0xc0);
+        assert!(irq(&cpu));
+        assert_eq!(cpu.mem_read(0x5204), 0xc0);
+        assert!(!irq(&cpu));
+    }
+
+    #[test]
+    fn test_fill_mode_on_screen() {
+        let mut cpu = console();
+        // 1K background banks out of the last 1K of CHR, where every byte
+        // is $FF and so every pixel colour 3
+        cpu.mem_write(0x5101, 3);
+        for addr in 0x5128..=0x512b {
+            cpu.mem_write(addr, 0xff);
+        }
+        cpu.mem_write(0x5105, 0xff);
+        cpu.mem_write(0x5106, 0x00);
+        cpu.mem_write(0x5107, 0x02);
+        cpu.mem_write(0x2006, 0x3f);
+        cpu.mem_write(0x2006, 0x0b);
+        cpu.mem_write(0x2007, 0x2c);
+        cpu.mem_write(0x2006, 0x00);
+        cpu.mem_write(0x2006, 0x00);
+        cpu.mem_write(0x2001, 0x0a);
+
+        for _ in 0..2 {
+            cpu.idle(29781);
+        }
+        assert!(cpu.ppu.frame.pixels.iter().all(|pixel| *pixel == 0x2c));
+    }
+}
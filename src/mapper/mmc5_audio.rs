@@ -0,0 +1,290 @@
+// The MMC5's sound: two pulse channels that work like the 2A03's minus the
+// sweep unit, and an 8 bit PCM channel that is either written directly or
+// loaded by reads from $8000-$BFFF. It's kept apart from the mapper so the
+// NSF player can drive it on its own.
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// The envelopes and length counters are clocked by a divider of their own
+// at about 240 Hz instead of the APU frame counter
+const QUARTER_FRAME_CYCLES: u32 = 7457;
+
+#[derive(Default)]
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    timer_period: u16,
+    timer: u16,
+    length: u8,
+    halt: bool,
+    constant_volume: bool,
+    volume: u8,
+    envelope_start: bool,
+    envelope_divider: u8,
+    envelope_decay: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.halt = data & 0x20 != 0;
+                self.constant_volume = data & 0x10 != 0;
+                self.volume = data & 0x0f;
+            }
+            // No sweep unit behind the second register
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0xff) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.duty_step = 0;
+                self.envelope_start = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        if self.envelope_start {
+            self.envelope_start = false;
+            self.envelope_decay = 15;
+            self.envelope_divider = self.volume;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.volume;
+            if self.envelope_decay > 0 {
+                self.envelope_decay -= 1;
+            } else if self.halt {
+                self.envelope_decay = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
+
+        // The MMC5 clocks the length counters at the quarter frame rate too
+        if !self.halt && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || DUTY_CYCLES[self.duty as usize][self.duty_step as usize] == 0 {
+            0
+        } else if self.constant_volume {
+            self.volume
+        } else {
+            self.envelope_decay
+        }
+    }
+}
+
+pub struct Mmc5Audio {
+    pulses: [Pulse; 2],
+    pcm: u8,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq: bool,
+    odd_cycle: bool,
+    quarter_frame_counter: u32,
+    gains: [f32; 3],
+}
+
+impl Mmc5Audio {
+    pub const CHANNELS: [&'static str; 3] = ["mmc5-pulse1", "mmc5-pulse2", "mmc5-pcm"];
+
+    pub fn new() -> Self {
+        Self {
+            pulses: [Pulse::default(), Pulse::default()],
+            pcm: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: false,
+            odd_cycle: false,
+            quarter_frame_counter: 0,
+            gains: [1.0; 3],
+        }
+    }
+
+    // Scales each channel's share of output(), in CHANNELS order
+    pub fn set_gains(&mut self, gains: &[f32]) {
+        for (gain, value) in self.gains.iter_mut().zip(gains) {
+            *gain = *value;
+        }
+    }
+
+    // Handles $5000-$5015, returns false for addresses that aren't audio
+    // registers
+    pub fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x5000..=0x5007 => {
+                self.pulses[(addr as usize - 0x5000) / 4].write(addr & 0x03, data);
+            }
+            0x5010 => {
+                self.pcm_read_mode = data & 0x01 != 0;
+                self.pcm_irq_enabled = data & 0x80 != 0;
+            }
+            0x5011 => {
+                // Zero can't be written, the DAC keeps its value
+                if !self.pcm_read_mode && data != 0 {
+                    self.pcm = data;
+                }
+            }
+            0x5015 => {
+                self.pulses[0].set_enabled(data & 0x01 != 0);
+                self.pulses[1].set_enabled(data & 0x02 != 0);
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    pub fn read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek(addr);
+        // Reading the PCM status acknowledges the IRQ
+        if addr == 0x5010 {
+            self.pcm_irq = false;
+        }
+        data
+    }
+
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 => Some((self.pcm_irq as u8) << 7 | self.pcm_read_mode as u8),
+            0x5015 => {
+                Some((self.pulses[0].length > 0) as u8 | ((self.pulses[1].length > 0) as u8) << 1)
+            }
+            _ => None,
+        }
+    }
+
+    // Sees the CPU reads from $8000-$BFFF, which feed the DAC in read mode
+    pub fn snoop_read(&mut self, addr: u16, data: u8) {
+        if self.pcm_read_mode && (0x8000..=0xbfff).contains(&addr) {
+            if data == 0 {
+                self.pcm_irq = true;
+            } else {
+                self.pcm = data;
+            }
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.pcm_irq && self.pcm_irq_enabled
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        // The timers tick at the APU rate, every other CPU cycle
+        self.odd_cycle = !self.odd_cycle;
+        if self.odd_cycle {
+            for pulse in self.pulses.iter_mut() {
+                pulse.clock_timer();
+            }
+        }
+
+        self.quarter_frame_counter += 1;
+        if self.quarter_frame_counter == QUARTER_FRAME_CYCLES {
+            self.quarter_frame_counter = 0;
+            for pulse in self.pulses.iter_mut() {
+                pulse.clock_quarter_frame();
+            }
+        }
+    }
+
+    // Output on the same scale as the APU mix, the pulses go through the
+    // same non linear mixing as the 2A03's and the PCM channel peaks about
+    // as loud as a full scale DMC
+    pub fn output(&self) -> f32 {
+        let pulses = self.pulses[0].output() as f32 * self.gains[0]
+            + self.pulses[1].output() as f32 * self.gains[1];
+        let pulse_out = if pulses == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulses + 100.0)
+        };
+
+        pulse_out + self.pcm as f32 / 255.0 * 0.42 * self.gains[2]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_outputs_constant_volume() {
+        let mut audio = Mmc5Audio::new();
+        audio.write(0x5015, 0x01);
+        audio.write(0x5000, 0b1011_1010);
+        audio.write(0x5002, 0x10);
+        audio.write(0x5003, 0x08);
+
+        let mut peak: f32 = 0.0;
+        for _ in 0..1000 {
+            audio.clock();
+            peak = peak.max(audio.output());
+        }
+
+        assert!((peak - 95.88 / (8128.0 / 10.0 + 100.0)).abs() < 1e-6);
+        assert_eq!(audio.read(0x5015), Some(0x01));
+    }
+
+    #[test]
+    fn test_length_counter_runs_at_240hz() {
+        let mut audio = Mmc5Audio::new();
+        audio.write(0x5015, 0x02);
+        // Length index 3 loads 2
+        audio.write(0x5007, 0x18);
+
+        for _ in 0..QUARTER_FRAME_CYCLES * 2 {
+            audio.clock();
+        }
+
+        assert_eq!(audio.read(0x5015), Some(0x00));
+    }
+
+    #[test]
+    fn test_pcm_read_mode_irq() {
+        let mut audio = Mmc5Audio::new();
+        audio.write(0x5010, 0x81);
+
+        audio.snoop_read(0x8000, 0x40);
+        assert!(!audio.irq());
+        assert!(audio.output() > 0.0);
+
+        audio.snoop_read(0x8001, 0x00);
+        assert!(audio.irq());
+        assert_eq!(audio.read(0x5010), Some(0x81));
+        assert!(!audio.irq());
+    }
+}
@@ -0,0 +1,250 @@
+mod bandai;
+mod discrete;
+mod eeprom;
+mod fme7;
+mod mmc1;
+mod mmc3;
+mod mmc5;
+mod mmc5_audio;
+mod namco163;
+mod namco163_audio;
+mod nrom;
+mod nsf;
+mod sunsoft5b_audio;
+mod vrc;
+mod vrc6;
+mod vrc6_audio;
+mod vrc7;
+mod vrc7_audio;
+
+pub use bandai::BandaiFcg;
+pub use discrete::{Axrom, Cnrom, Uxrom};
+pub use eeprom::Eeprom24C02;
+pub use fme7::Fme7;
+pub use mmc1::Mmc1;
+pub use mmc3::Mmc3;
+pub use mmc5::Mmc5;
+pub use mmc5_audio::Mmc5Audio;
+pub use namco163::Namco163;
+pub use namco163_audio::Namco163Audio;
+pub use nrom::Nrom;
+pub use nsf::NsfMapper;
+pub use sunsoft5b_audio::Sunsoft5bAudio;
+pub use vrc::Vrc4;
+pub use vrc6::Vrc6;
+pub use vrc6_audio::Vrc6Audio;
+pub use vrc7::Vrc7;
+pub use vrc7_audio::Vrc7Audio;
+
+use crate::cartridge::Cartridge;
+
+// How the four logical nametables at $2000-$2FFF map onto the console's 2K
+// of VRAM (or the extra 2K some cartridges carry for four screen layouts)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+impl Mirroring {
+    // Offset into nametable memory for a PPU address in $2000-$3EFF
+    pub fn nametable_offset(&self, addr: u16) -> usize {
+        let addr = (addr as usize - 0x2000) & 0x0fff;
+        let table = addr / 0x400;
+        let offset = addr & 0x3ff;
+
+        let page = match self {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+
+        page * 0x400 + offset
+    }
+}
+
+// Everything that lives on the cartridge side of the connector. The CPU
+// hands over its accesses to $4020-$FFFF and the PPU its accesses to the
+// pattern tables, the mapper decides what answers them.
+pub trait Mapper {
+    // Reads return None when nothing on the cartridge drives the bus, which
+    // leaves the open bus value in place
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    // What a read would return, without the side effects reading some
+    // registers has. Only the mappers with such registers override cpu_read.
+    fn cpu_peek(&self, addr: u16) -> Option<u8>;
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    fn ppu_read(&mut self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    // Called when the PPU puts an address on its bus without going through
+    // the pattern tables, like nametable fetches and $2006 writes, for the
+    // mappers that watch the address lines
+    fn ppu_address(&mut self, _addr: u16) {}
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Nametable accesses in $2000-$2FFF. The console's 2K of VRAM is passed
+    // in since the cartridge decides what answers them, which by default is
+    // the VRAM as the mirroring lays it out.
+    fn nametable_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        vram[self.mirroring().nametable_offset(addr) % vram.len()]
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        let offset = self.mirroring().nametable_offset(addr) % vram.len();
+        vram[offset] = data;
+    }
+
+    // Lets the mappers that snoop on the PPU registers see writes to
+    // $2000-$2007
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    // Expansion audio, on the same scale as the APU's own mix
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // Names of the expansion audio channels audio_output mixes, which the
+    // mixer controls address them by
+    fn audio_channels(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    // Scales each of those channels in audio_output, in the same order
+    fn set_audio_gains(&mut self, _gains: &[f32]) {}
+
+    // Memory that a battery keeps alive on boards that have one, usually
+    // the PRG-RAM but some boards save to a serial EEPROM instead
+    fn save_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // State of the cartridge's IRQ output, the line is shared so the CPU
+    // sees an IRQ while any source holds it
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Called after every CPU cycle
+    fn cpu_clock(&mut self) {}
+
+    // Called by the PPU at the end of every scanline
+    fn scanline(&mut self) {}
+}
+
+// Offset of an address inside a bank of the given size, bank numbers wrap
+// around the size of the memory like the unconnected upper lines of the
+// real chips make them do. Memory smaller than a bank repeats inside it.
+pub fn bank_offset(len: usize, bank_size: usize, bank: usize, addr: u16) -> usize {
+    let banks = (len / bank_size).max(1);
+    ((bank % banks) * bank_size + (addr as usize & (bank_size - 1))) % len.max(1)
+}
+
+// Whether a discrete board has bus conflicts. NES 2.0 submapper 1 says it
+// doesn't and 2 says it does (AND-type), anything else falls back to what
+// is usual for the board.
+pub fn bus_conflicts(cartridge: &Cartridge, default: bool) -> bool {
+    match cartridge.submapper {
+        1 => false,
+        2 => true,
+        _ => default,
+    }
+}
+
+// Pattern table memory, either the CHR-ROM of the cartridge or CHR-RAM when
+// the cartridge has none
+pub struct ChrMemory {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl ChrMemory {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        if cartridge.chr_rom.is_empty() {
+            Self {
+                data: vec![0; cartridge.chr_ram_size.max(0x2000)],
+                writable: true,
+            }
+        } else {
+            Self {
+                data: cartridge.chr_rom.clone(),
+                writable: false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn read(&self, offset: usize) -> u8 {
+        self.data[offset % self.data.len()]
+    }
+
+    pub fn write(&mut self, offset: usize, data: u8) {
+        if self.writable {
+            let len = self.data.len();
+            self.data[offset % len] = data;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // A cartridge whose ROMs are made of banks of the given sizes, with
+    // every byte holding the number of its bank
+    pub fn banked_cartridge(
+        mapper: u16,
+        submapper: u8,
+        prg_banks: usize,
+        prg_bank_size: usize,
+        chr_banks: usize,
+        chr_bank_size: usize,
+    ) -> Cartridge {
+        let mut cartridge = Cartridge::from_program(&[]);
+        cartridge.mapper = mapper;
+        cartridge.submapper = submapper;
+        cartridge.prg_rom = (0..prg_banks * prg_bank_size)
+            .map(|n| (n / prg_bank_size) as u8)
+            .collect();
+        cartridge.chr_rom = (0..chr_banks * chr_bank_size)
+            .map(|n| (n / chr_bank_size) as u8)
+            .collect();
+        cartridge
+    }
+
+    #[test]
+    fn test_bank_offset() {
+        assert_eq!(bank_offset(0x8000, 0x2000, 5, 0x9234), 0x3234);
+        // An 8K ROM in a 32K window
+        assert_eq!(bank_offset(0x2000, 0x8000, 0, 0xe001), 0x0001);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        assert_eq!(Mirroring::Horizontal.nametable_offset(0x2400), 0x000);
+        assert_eq!(Mirroring::Horizontal.nametable_offset(0x2800), 0x400);
+        assert_eq!(Mirroring::Vertical.nametable_offset(0x2400), 0x400);
+        assert_eq!(Mirroring::Vertical.nametable_offset(0x2805), 0x005);
+        assert_eq!(Mirroring::SingleScreenUpper.nametable_offset(0x2c10), 0x410);
+        assert_eq!(Mirroring::FourScreen.nametable_offset(0x2c10), 0xc10);
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(Mirroring::Vertical.nametable_offset(0x3401), 0x401);
+    }
+}
@@ -0,0 +1,269 @@
+use super::{bank_offset, ChrMemory, Mapper, Mirroring, Namco163Audio};
+use crate::cartridge::Cartridge;
+
+// Mapper 19, the Namco 163. Three switchable 8K PRG banks, eight 1K CHR
+// banks, four nametable banks that pick either CHR-ROM or one of the
+// console's two nametables, a 15 bit cycle counter IRQ and the wavetable
+// sound chip.
+//
+// CHR bank numbers $E0 and up can also select the console's nametable RAM
+// as pattern memory, which needs the VRAM the PPU only passes in for
+// nametable accesses, so they read CHR-ROM like any other bank.
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    write_protect: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+    audio: Namco163Audio,
+}
+
+impl Namco163 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; ram_size.max(0x2000)],
+            chr: ChrMemory::new(cartridge),
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            nametable_banks: [0xe0; 4],
+            write_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            audio: Namco163Audio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x8000..=0x9fff => self.prg_banks[0] as usize,
+            0xa000..=0xbfff => self.prg_banks[1] as usize,
+            0xc000..=0xdfff => self.prg_banks[2] as usize,
+            _ => (len / 0x2000).max(1) - 1,
+        };
+
+        bank_offset(len, 0x2000, bank & 0x3f, addr)
+    }
+
+    // $F800 has to hold $4x for any write to go through, and each of the
+    // low bits protects one 2K quarter of the RAM
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let quarter = (addr - 0x6000) / 0x800;
+        self.write_protect & 0xf0 == 0x40 && self.write_protect & (1 << quarter) == 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize & 0x1fff) / 0x400];
+        bank_offset(self.chr.len(), 0x400, bank as usize, addr)
+    }
+}
+
+impl Mapper for Namco163 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => Some(self.audio.read_data()),
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => Some(self.audio.peek_data()),
+            0x5000..=0x57ff => Some(self.irq_counter as u8),
+            0x5800..=0x5fff => Some((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7),
+            0x6000..=0x7fff => Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]),
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4fff => self.audio.write_data(data),
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0xff) | (data as u16 & 0x7f) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7fff if self.prg_ram_writable(addr) => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+            0x8000..=0xbfff => self.chr_banks[(addr as usize - 0x8000) / 0x800] = data,
+            0xc000..=0xdfff => self.nametable_banks[(addr as usize - 0xc000) / 0x800] = data,
+            0xe000..=0xe7ff => {
+                self.prg_banks[0] = data & 0x3f;
+                self.audio.set_disabled(data & 0x40 != 0);
+            }
+            0xe800..=0xefff => self.prg_banks[1] = data & 0x3f,
+            0xf000..=0xf7ff => self.prg_banks[2] = data & 0x3f,
+            0xf800..=0xffff => {
+                self.write_protect = data;
+                self.audio.write_address(data);
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_offset(addr), data);
+    }
+
+    fn nametable_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        let bank = self.nametable_banks[(addr as usize >> 10) & 0x03];
+        let offset = addr as usize & 0x3ff;
+
+        if bank >= 0xe0 {
+            vram[(bank as usize & 0x01) * 0x400 + offset]
+        } else {
+            self.chr.read(bank as usize * 0x400 + offset)
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        let bank = self.nametable_banks[(addr as usize >> 10) & 0x03];
+
+        if bank >= 0xe0 {
+            vram[(bank as usize & 0x01) * 0x400 + (addr as usize & 0x3ff)] = data;
+        }
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // Only meaningful while all four nametables come from the console
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_banks.map(|bank| bank & 0x01) {
+            [0, 1, 0, 1] => Mirroring::Vertical,
+            [0, 0, 1, 1] => Mirroring::Horizontal,
+            [1, 1, 1, 1] => Mirroring::SingleScreenUpper,
+            _ => Mirroring::SingleScreenLower,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        // Counts up and stops at $7FFF
+        if self.irq_enabled && self.irq_counter < 0x7fff {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7fff {
+                self.irq_pending = true;
+            }
+        }
+
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn audio_channels(&self) -> Vec<&'static str> {
+        Namco163Audio::CHANNELS.to_vec()
+    }
+
+    fn set_audio_gains(&mut self, gains: &[f32]) {
+        self.audio.set_gains(gains);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::banked_cartridge;
+    use super::*;
+
+    fn cartridge() -> Cartridge {
+        banked_cartridge(19, 0, 16, 0x2000, 256, 0x400)
+    }
+
+    #[test]
+    fn test_prg_and_chr_banks() {
+        let mut n163 = Namco163::new(&cartridge());
+        n163.cpu_write(0xe000, 3);
+        n163.cpu_write(0xe800, 4);
+        n163.cpu_write(0xf000, 5);
+        n163.cpu_write(0xb800, 0x99);
+
+        assert_eq!(n163.cpu_read(0x8000), Some(3));
+        assert_eq!(n163.cpu_read(0xa000), Some(4));
+        assert_eq!(n163.cpu_read(0xc000), Some(5));
+        assert_eq!(n163.cpu_read(0xe000), Some(15));
+        assert_eq!(n163.ppu_read(0x1c00), 0x99);
+    }
+
+    #[test]
+    fn test_nametable_banks() {
+        let mut n163 = Namco163::new(&cartridge());
+        let mut vram = [0; 0x800];
+        n163.cpu_write(0xc000, 0xe0);
+        n163.cpu_write(0xc800, 0xe1);
+        n163.cpu_write(0xd000, 0x20);
+
+        n163.nametable_write(0x2405, 0x33, &mut vram);
+        n163.nametable_write(0x2805, 0x44, &mut vram);
+
+        assert_eq!(vram[0x405], 0x33);
+        assert_eq!(n163.nametable_read(0x2405, &vram), 0x33);
+        // CHR-ROM can't be written
+        assert_eq!(n163.nametable_read(0x2805, &vram), 0x20);
+    }
+
+    #[test]
+    fn test_prg_ram_write_protect() {
+        let mut n163 = Namco163::new(&cartridge());
+        n163.cpu_write(0x6000, 0x11);
+        assert_eq!(n163.cpu_read(0x6000), Some(0));
+
+        // Writable except for the second quarter
+        n163.cpu_write(0xf800, 0x42);
+        n163.cpu_write(0x6000, 0x11);
+        n163.cpu_write(0x6800, 0x22);
+        assert_eq!(n163.cpu_read(0x6000), Some(0x11));
+        assert_eq!(n163.cpu_read(0x6800), Some(0));
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut n163 = Namco163::new(&cartridge());
+        n163.cpu_write(0x5000, 0xfd);
+        n163.cpu_write(0x5800, 0xff);
+
+        n163.cpu_clock();
+        assert!(!n163.irq());
+        n163.cpu_clock();
+        assert!(n163.irq());
+        assert_eq!(n163.cpu_read(0x5800), Some(0xff));
+
+        // Stays at $7FFF until acknowledged
+        n163.cpu_clock();
+        assert_eq!(n163.cpu_read(0x5000), Some(0xff));
+        n163.cpu_write(0x5000, 0);
+        assert!(!n163.irq());
+    }
+}
@@ -0,0 +1,210 @@
+// The Namco 163's sound: up to 8 wavetable channels whose registers and
+// 4 bit waveforms share 128 bytes of internal RAM. The chip updates one
+// channel every 15 CPU cycles and outputs them one after another, so every
+// channel added makes each of them update less often.
+
+// Rough level of the output against the APU mix, the boards use different
+// resistors so the real level varies between games
+const VOLUME: f32 = 0.0015;
+
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+pub struct Namco163Audio {
+    ram: [u8; 0x80],
+    address: u8,
+    auto_increment: bool,
+    disabled: bool,
+    cycles: u8,
+    // Index of the channel updated next, counting down from 7
+    channel: usize,
+    outputs: [i8; 8],
+    gains: [f32; 8],
+}
+
+impl Namco163Audio {
+    pub const CHANNELS: [&'static str; 8] = [
+        "n163-1", "n163-2", "n163-3", "n163-4", "n163-5", "n163-6", "n163-7", "n163-8",
+    ];
+
+    pub fn new() -> Self {
+        Self {
+            ram: [0; 0x80],
+            address: 0,
+            auto_increment: false,
+            disabled: false,
+            cycles: 0,
+            channel: 7,
+            outputs: [0; 8],
+            gains: [1.0; 8],
+        }
+    }
+
+    // Scales each channel's share of output(), in CHANNELS order
+    pub fn set_gains(&mut self, gains: &[f32]) {
+        for (gain, value) in self.gains.iter_mut().zip(gains) {
+            *gain = *value;
+        }
+    }
+
+    pub fn write_address(&mut self, data: u8) {
+        self.address = data & 0x7f;
+        self.auto_increment = data & 0x80 != 0;
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        self.ram[self.address as usize] = data;
+        self.step_address();
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        let data = self.peek_data();
+        self.step_address();
+        data
+    }
+
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.address as usize]
+    }
+
+    fn step_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7f;
+        }
+    }
+
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    // Number of enabled channels, from 1 to 8
+    fn channel_count(&self) -> usize {
+        ((self.ram[0x7f] >> 4) & 0x07) as usize + 1
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if self.disabled {
+            return;
+        }
+
+        self.cycles += 1;
+        if self.cycles < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.cycles = 0;
+
+        self.update_channel(self.channel);
+
+        let first = 8 - self.channel_count();
+        self.channel = if self.channel <= first {
+            7
+        } else {
+            self.channel - 1
+        };
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let registers = &self.ram[base..base + 8];
+
+        let frequency =
+            registers[0] as u32 | (registers[2] as u32) << 8 | (registers[4] as u32 & 0x03) << 16;
+        let length = 256 - (registers[4] & 0xfc) as u32;
+        let mut phase =
+            registers[1] as u32 | (registers[3] as u32) << 8 | (registers[5] as u32) << 16;
+        let offset = registers[6] as u32;
+        let volume = (registers[7] & 0x0f) as i8;
+
+        phase = (phase + frequency) % (length << 16);
+
+        let nibble = (offset + (phase >> 16)) as usize & 0xff;
+        let byte = self.ram[nibble / 2];
+        let sample = if nibble.is_multiple_of(2) {
+            byte & 0x0f
+        } else {
+            byte >> 4
+        };
+        self.outputs[channel] = (sample as i8 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+
+    // The channels take turns on the output, which averages them out
+    pub fn output(&self) -> f32 {
+        if self.disabled {
+            return 0.0;
+        }
+
+        let count = self.channel_count();
+        let sum: f32 = (8 - count..8)
+            .map(|channel| self.outputs[channel] as f32 * self.gains[channel])
+            .sum();
+        sum / count as f32 * VOLUME
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Namco163Audio, address: u8, data: &[u8]) {
+        audio.write_address(address | 0x80);
+        for byte in data {
+            audio.write_data(*byte);
+        }
+    }
+
+    #[test]
+    fn test_auto_increment() {
+        let mut audio = Namco163Audio::new();
+        write(&mut audio, 0x10, &[1, 2, 3]);
+
+        audio.write_address(0x91);
+        assert_eq!(audio.read_data(), 2);
+        assert_eq!(audio.read_data(), 3);
+    }
+
+    #[test]
+    fn test_plays_waveform() {
+        let mut audio = Namco163Audio::new();
+        // A square wave in the first 4 bytes, 8 samples long
+        write(&mut audio, 0x00, &[0xff, 0xff, 0x00, 0x00]);
+        // Channel 7 stepping one sample per update at full volume, with
+        // only one channel enabled
+        write(
+            &mut audio,
+            0x78,
+            &[0x00, 0, 0x00, 0, 0x01 | (256 - 8) as u8, 0, 0x00, 0x0f],
+        );
+
+        let mut levels = Vec::new();
+        for _ in 0..8 {
+            for _ in 0..CYCLES_PER_CHANNEL {
+                audio.clock();
+            }
+            levels.push(audio.outputs[7]);
+        }
+
+        assert_eq!(levels[..3], [7 * 15; 3]);
+        assert_eq!(levels[4..7], [-8 * 15; 3]);
+    }
+
+    #[test]
+    fn test_more_channels_update_less_often() {
+        let mut audio = Namco163Audio::new();
+        // 4 channels enabled
+        write(&mut audio, 0x7f, &[0x3f]);
+
+        let mut updated = Vec::new();
+        for _ in 0..5 {
+            updated.push(audio.channel);
+            for _ in 0..CYCLES_PER_CHANNEL {
+                audio.clock();
+            }
+        }
+
+        assert_eq!(updated, [7, 6, 5, 4, 7]);
+    }
+}
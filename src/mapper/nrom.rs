@@ -0,0 +1,120 @@
+use super::{ChrMemory, Mapper, Mirroring};
+use crate::cartridge::Cartridge;
+
+// Mapper 0, no bank switching at all. 16K of PRG-ROM is mirrored over
+// $8000-$FFFF, 32K fills it, and the pattern tables are 8K of CHR-ROM or
+// CHR-RAM. The PRG-RAM at $6000 only exists on Family Basic, but emulators
+// traditionally provide it for every NROM game.
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; cartridge.prg_ram_size + cartridge.prg_nvram_size],
+            chr: ChrMemory::new(cartridge),
+            mirroring: cartridge.mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xffff => Some(self.prg_rom[(addr as usize - 0x8000) % self.prg_rom.len()]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            if !self.prg_ram.is_empty() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(addr as usize & 0x1fff)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize & 0x1fff, data);
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cartridge(prg_banks: usize, chr_banks: usize) -> Cartridge {
+        let mut cartridge = Cartridge::from_program(&[]);
+        cartridge.prg_rom = (0..prg_banks * 0x4000)
+            .map(|n| (n / 0x4000) as u8)
+            .collect();
+        cartridge.chr_rom = vec![0x55; chr_banks * 0x2000];
+        cartridge
+    }
+
+    #[test]
+    fn test_16k_prg_is_mirrored() {
+        let mut nrom = Nrom::new(&cartridge(1, 1));
+
+        assert_eq!(nrom.cpu_read(0x8000), Some(0));
+        assert_eq!(nrom.cpu_read(0xc000), Some(0));
+    }
+
+    #[test]
+    fn test_32k_prg() {
+        let mut nrom = Nrom::new(&cartridge(2, 1));
+
+        assert_eq!(nrom.cpu_read(0x8000), Some(0));
+        assert_eq!(nrom.cpu_read(0xc000), Some(1));
+        assert_eq!(nrom.cpu_read(0x5000), None);
+    }
+
+    #[test]
+    fn test_chr_rom_is_read_only() {
+        let mut nrom = Nrom::new(&cartridge(1, 1));
+        nrom.ppu_write(0x0010, 0xaa);
+
+        assert_eq!(nrom.ppu_read(0x0010), 0x55);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut nrom = Nrom::new(&cartridge(1, 0));
+        nrom.ppu_write(0x1ff0, 0xaa);
+
+        assert_eq!(nrom.ppu_read(0x1ff0), 0xaa);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut nrom = Nrom::new(&cartridge(1, 1));
+        nrom.cpu_write(0x6123, 0x42);
+
+        assert_eq!(nrom.cpu_read(0x6123), Some(0x42));
+    }
+}
@@ -0,0 +1,335 @@
+use super::{bank_offset, Mapper, Mirroring};
+use super::{Mmc5Audio, Namco163Audio, Sunsoft5bAudio, Vrc6Audio, Vrc7Audio};
+use crate::nsf::{self, Nsf};
+
+// The cartridge side of an NSF player. The tune's data fills $8000-$FFFF
+// in eight 4K banks picked through $5FF8-$5FFF, or sits at its load address
+// if it isn't bankswitched, with 8K of RAM at $6000. The expansion chips
+// the header flags answer at the addresses their games used, in their NSF
+// layout:
+//
+//   VRC6        $9000-$9003, $A000-$A002, $B000-$B002
+//   VRC7        $9010 register select, $9030 data
+//   MMC5        $5000-$5015, the $5205/$5206 multiplier and ExRAM at $5C00
+//   Namco 163   $4800 data, $F800 address
+//   Sunsoft 5B  $C000 address, $E000 data
+pub struct NsfMapper {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    bankswitched: bool,
+    banks: [u8; 8],
+
+    vrc6: Option<Vrc6Audio>,
+    vrc7: Option<Vrc7Audio>,
+    mmc5: Option<Mmc5Audio>,
+    mmc5_exram: Vec<u8>,
+    multiplier: [u8; 2],
+    namco163: Option<Namco163Audio>,
+    sunsoft5b: Option<Sunsoft5bAudio>,
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> Self {
+        let bankswitched = nsf.bankswitched();
+
+        // Bankswitched data starts at the load address's offset into its
+        // bank, the rest goes straight into the 32K at $8000
+        let prg_rom = if bankswitched {
+            let mut prg_rom = vec![0; nsf.load_addr as usize & 0x0fff];
+            prg_rom.extend_from_slice(&nsf.data);
+            prg_rom.resize(prg_rom.len().next_multiple_of(0x1000), 0);
+            prg_rom
+        } else {
+            let mut prg_rom = vec![0; 0x8000];
+            let start = nsf.load_addr as usize - 0x8000;
+            let len = nsf.data.len().min(0x8000 - start);
+            prg_rom[start..start + len].copy_from_slice(&nsf.data[..len]);
+            prg_rom
+        };
+        let chip = |flag: u8| nsf.chips & flag != 0;
+
+        Self {
+            prg_rom,
+            prg_ram: vec![0; 0x2000],
+            bankswitched,
+            banks: if bankswitched {
+                nsf.banks
+            } else {
+                [0, 1, 2, 3, 4, 5, 6, 7]
+            },
+            vrc6: chip(nsf::VRC6).then(Vrc6Audio::new),
+            vrc7: chip(nsf::VRC7).then(Vrc7Audio::new),
+            mmc5: chip(nsf::MMC5).then(Mmc5Audio::new),
+            mmc5_exram: vec![0; 0x400],
+            multiplier: [0xff; 2],
+            namco163: chip(nsf::NAMCO163).then(Namco163Audio::new),
+            sunsoft5b: chip(nsf::SUNSOFT5B).then(Sunsoft5bAudio::new),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank = self.banks[(addr as usize - 0x8000) / 0x1000];
+        bank_offset(self.prg_rom.len(), 0x1000, bank as usize, addr)
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800 if self.namco163.is_some() => {
+                self.namco163.as_mut().map(|n163| n163.read_data())
+            }
+            0x5000..=0x5015 => self.mmc5.as_mut().and_then(|mmc5| mmc5.read(addr)),
+            0x8000..=0xffff => {
+                let data = self.prg_rom[self.prg_offset(addr)];
+                if let Some(mmc5) = &mut self.mmc5 {
+                    mmc5.snoop_read(addr, data);
+                }
+                Some(data)
+            }
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800 => self.namco163.as_ref().map(|n163| n163.peek_data()),
+            0x5000..=0x5015 => self.mmc5.as_ref().and_then(|mmc5| mmc5.peek(addr)),
+            0x5205 if self.mmc5.is_some() => {
+                Some((self.multiplier[0] as u16 * self.multiplier[1] as u16) as u8)
+            }
+            0x5206 if self.mmc5.is_some() => {
+                Some(((self.multiplier[0] as u16 * self.multiplier[1] as u16) >> 8) as u8)
+            }
+            0x5c00..=0x5ff5 if self.mmc5.is_some() => Some(self.mmc5_exram[addr as usize - 0x5c00]),
+            0x6000..=0x7fff => Some(self.prg_ram[addr as usize - 0x6000]),
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800 => {
+                if let Some(n163) = &mut self.namco163 {
+                    n163.write_data(data);
+                }
+            }
+            0x5000..=0x5015 => {
+                if let Some(mmc5) = &mut self.mmc5 {
+                    mmc5.write(addr, data);
+                }
+            }
+            0x5205 => self.multiplier[0] = data,
+            0x5206 => self.multiplier[1] = data,
+            0x5c00..=0x5ff5 => self.mmc5_exram[addr as usize - 0x5c00] = data,
+            0x5ff8..=0x5fff if self.bankswitched => self.banks[addr as usize - 0x5ff8] = data,
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000] = data,
+            0x9000..=0x9003 | 0xa000..=0xa002 | 0xb000..=0xb002 => {
+                if let Some(vrc6) = &mut self.vrc6 {
+                    vrc6.write(addr, data);
+                }
+            }
+            0x9010 => {
+                if let Some(vrc7) = &mut self.vrc7 {
+                    vrc7.write_select(data);
+                }
+            }
+            0x9030 => {
+                if let Some(vrc7) = &mut self.vrc7 {
+                    vrc7.write_data(data);
+                }
+            }
+            0xc000 => {
+                if let Some(sunsoft5b) = &mut self.sunsoft5b {
+                    sunsoft5b.write_address(data);
+                }
+            }
+            0xe000 => {
+                if let Some(sunsoft5b) = &mut self.sunsoft5b {
+                    sunsoft5b.write_data(data);
+                }
+            }
+            0xf800 => {
+                if let Some(n163) = &mut self.namco163 {
+                    n163.write_address(data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // There's nothing on the PPU side, NSF players don't draw anything
+    fn ppu_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn cpu_clock(&mut self) {
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.clock();
+        }
+        if let Some(vrc7) = &mut self.vrc7 {
+            vrc7.clock();
+        }
+        if let Some(mmc5) = &mut self.mmc5 {
+            mmc5.clock();
+        }
+        if let Some(n163) = &mut self.namco163 {
+            n163.clock();
+        }
+        if let Some(sunsoft5b) = &mut self.sunsoft5b {
+            sunsoft5b.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.vrc6.as_ref().map_or(0.0, |vrc6| vrc6.output())
+            + self.vrc7.as_ref().map_or(0.0, |vrc7| vrc7.output())
+            + self.mmc5.as_ref().map_or(0.0, |mmc5| mmc5.output())
+            + self.namco163.as_ref().map_or(0.0, |n163| n163.output())
+            + self
+                .sunsoft5b
+                .as_ref()
+                .map_or(0.0, |sunsoft5b| sunsoft5b.output())
+    }
+
+    // The channels of every chip present, one chip after the other
+    fn audio_channels(&self) -> Vec<&'static str> {
+        let mut channels = Vec::new();
+        if self.vrc6.is_some() {
+            channels.extend(Vrc6Audio::CHANNELS);
+        }
+        if self.vrc7.is_some() {
+            channels.extend(Vrc7Audio::CHANNELS);
+        }
+        if self.mmc5.is_some() {
+            channels.extend(Mmc5Audio::CHANNELS);
+        }
+        if self.namco163.is_some() {
+            channels.extend(Namco163Audio::CHANNELS);
+        }
+        if self.sunsoft5b.is_some() {
+            channels.extend(Sunsoft5bAudio::CHANNELS);
+        }
+        channels
+    }
+
+    fn set_audio_gains(&mut self, gains: &[f32]) {
+        let mut gains = gains;
+        let mut next = |count: usize| {
+            let (chip, rest) = gains.split_at(count.min(gains.len()));
+            gains = rest;
+            chip
+        };
+
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.set_gains(next(Vrc6Audio::CHANNELS.len()));
+        }
+        if let Some(vrc7) = &mut self.vrc7 {
+            vrc7.set_gains(next(Vrc7Audio::CHANNELS.len()));
+        }
+        if let Some(mmc5) = &mut self.mmc5 {
+            mmc5.set_gains(next(Mmc5Audio::CHANNELS.len()));
+        }
+        if let Some(n163) = &mut self.namco163 {
+            n163.set_gains(next(Namco163Audio::CHANNELS.len()));
+        }
+        if let Some(sunsoft5b) = &mut self.sunsoft5b {
+            sunsoft5b.set_gains(next(Sunsoft5bAudio::CHANNELS.len()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nsf::NsfError;
+
+    fn nsf(load_addr: u16, banks: [u8; 8], chips: u8, data: Vec<u8>) -> Result<Nsf, NsfError> {
+        let mut file = b"NESM\x1a\x01\x01\x01".to_vec();
+        file.extend_from_slice(&load_addr.to_le_bytes());
+        file.resize(0x70, 0);
+        file.extend_from_slice(&banks);
+        file.resize(0x7b, 0);
+        file.push(chips);
+        file.resize(0x80, 0);
+        file.extend(data);
+        Nsf::parse(&file)
+    }
+
+    #[test]
+    fn test_unbanked_data_sits_at_load_address() {
+        let nsf = nsf(0xc000, [0; 8], 0, vec![0x11, 0x22]).unwrap();
+        let mut mapper = NsfMapper::new(&nsf);
+
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xc000), Some(0x11));
+        assert_eq!(mapper.cpu_read(0xc001), Some(0x22));
+        // Bank writes do nothing for tunes that aren't bankswitched
+        mapper.cpu_write(0x5ffc, 1);
+        assert_eq!(mapper.cpu_read(0xc000), Some(0x11));
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        // Three 4K banks of data loaded $100 into the first
+        let data = (0..0x3000 - 0x100)
+            .map(|n| ((n + 0x100) / 0x1000) as u8)
+            .collect();
+        let nsf = nsf(0x8100, [0, 1, 2, 0, 0, 0, 0, 2], 0, data).unwrap();
+        let mut mapper = NsfMapper::new(&nsf);
+
+        assert_eq!(mapper.cpu_read(0x80ff), Some(0));
+        assert_eq!(mapper.cpu_read(0x9000), Some(1));
+        assert_eq!(mapper.cpu_read(0xa000), Some(2));
+        assert_eq!(mapper.cpu_read(0xf000), Some(2));
+
+        mapper.cpu_write(0x5fff, 1);
+        assert_eq!(mapper.cpu_read(0xf000), Some(1));
+        // Wraps around the three banks there are
+        mapper.cpu_write(0x5ff8, 4);
+        assert_eq!(mapper.cpu_read(0x8000), Some(1));
+
+        mapper.cpu_write(0x6123, 0x5a);
+        assert_eq!(mapper.cpu_read(0x6123), Some(0x5a));
+    }
+
+    #[test]
+    fn test_expansion_chips() {
+        let plain = nsf(0x8000, [0; 8], 0, vec![0]).unwrap();
+        let mut mapper = NsfMapper::new(&plain);
+        mapper.cpu_write(0x9000, 0x8f);
+        mapper.cpu_write(0x9002, 0xff);
+        assert_eq!(mapper.cpu_read(0x5205), None);
+        assert_eq!(mapper.audio_output(), 0.0);
+
+        let flagged = nsf(0x8000, [0; 8], nsf::VRC6 | nsf::MMC5, vec![0]).unwrap();
+        let mut mapper = NsfMapper::new(&flagged);
+        // VRC6 pulse 1 at full volume and constant, so it's on straight away
+        mapper.cpu_write(0x9000, 0x8f);
+        mapper.cpu_write(0x9002, 0xff);
+        mapper.cpu_write(0x9001, 0x00);
+        mapper.cpu_clock();
+        assert!(mapper.audio_output() > 0.0);
+
+        mapper.cpu_write(0x5205, 0x12);
+        mapper.cpu_write(0x5206, 0x34);
+        assert_eq!(mapper.cpu_read(0x5205), Some(0xa8));
+        assert_eq!(mapper.cpu_read(0x5206), Some(0x03));
+        mapper.cpu_write(0x5c10, 0x77);
+        assert_eq!(mapper.cpu_read(0x5c10), Some(0x77));
+
+        assert_eq!(mapper.audio_channels().len(), 6);
+        assert_eq!(mapper.audio_channels()[3], "mmc5-pulse1");
+        let mut gains = [1.0; 6];
+        gains[0] = 0.0;
+        mapper.set_audio_gains(&gains);
+        assert_eq!(mapper.audio_output(), 0.0);
+    }
+}
@@ -0,0 +1,296 @@
+// The Sunsoft 5B's sound, a YM2149F (a licensed AY-3-8910) built into the
+// FME-7: three square wave channels with a shared noise generator and a
+// shared envelope. The square and noise periods count in steps of 16 CPU
+// cycles and the 32 step envelope in steps of 8.
+
+// Output of a single channel at full volume against the APU mix, a bit
+// louder than an APU pulse channel
+const CHANNEL_VOLUME: f32 = 0.15;
+
+#[derive(Default)]
+struct Tone {
+    period: u16,
+    counter: u16,
+    high: bool,
+}
+
+impl Tone {
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) {
+            self.counter = 0;
+            self.high = !self.high;
+        }
+    }
+}
+
+pub struct Sunsoft5bAudio {
+    address: u8,
+    registers: [u8; 16],
+    tones: [Tone; 3],
+
+    noise_counter: u8,
+    // 17 bit LFSR
+    noise_shift: u32,
+    noise_high: bool,
+
+    envelope_counter: u32,
+    envelope_step: u8,
+    envelope_holding: bool,
+
+    divider: u8,
+    // Amplitude of every 5 bit level, 1.5 dB apart
+    levels: [f32; 32],
+    gains: [f32; 3],
+}
+
+impl Sunsoft5bAudio {
+    pub const CHANNELS: [&'static str; 3] = ["5b-a", "5b-b", "5b-c"];
+
+    pub fn new() -> Self {
+        let mut levels = [0.0; 32];
+        for (level, amplitude) in levels.iter_mut().enumerate().skip(1) {
+            *amplitude = 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0);
+        }
+
+        Self {
+            address: 0,
+            registers: [0; 16],
+            tones: [Tone::default(), Tone::default(), Tone::default()],
+            noise_counter: 0,
+            noise_shift: 1,
+            noise_high: false,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_holding: false,
+            divider: 0,
+            levels,
+            gains: [1.0; 3],
+        }
+    }
+
+    // Scales each channel's share of output(), in CHANNELS order
+    pub fn set_gains(&mut self, gains: &[f32]) {
+        for (gain, value) in self.gains.iter_mut().zip(gains) {
+            *gain = *value;
+        }
+    }
+
+    // $C000, the top 4 bits have to be clear for the chip to take it
+    pub fn write_address(&mut self, data: u8) {
+        self.address = data;
+    }
+
+    // $E000
+    pub fn write_data(&mut self, data: u8) {
+        if self.address & 0xf0 != 0 {
+            return;
+        }
+
+        let register = self.address as usize;
+        self.registers[register] = data;
+
+        match register {
+            0..=5 => {
+                let tone = register / 2;
+                self.tones[tone].period = self.registers[tone * 2] as u16
+                    | (self.registers[tone * 2 + 1] as u16 & 0x0f) << 8;
+            }
+            0x0d => {
+                self.envelope_step = 0;
+                self.envelope_counter = 0;
+                self.envelope_holding = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn envelope_period(&self) -> u32 {
+        (self.registers[0x0b] as u32 | (self.registers[0x0c] as u32) << 8).max(1)
+    }
+
+    // The envelope's level out of the 32 step shape picked by register $0D
+    fn envelope_level(&self) -> u8 {
+        let shape = self.registers[0x0d];
+        let attack = shape & 0x04 != 0;
+        let step = self.envelope_step;
+
+        if step < 32 {
+            return if attack { step } else { 31 - step };
+        }
+
+        let continues = shape & 0x08 != 0;
+        let alternate = shape & 0x02 != 0;
+        let hold = shape & 0x01 != 0;
+
+        if !continues {
+            0
+        } else if hold {
+            // Stays at the last level, or the opposite one when alternating
+            if attack != alternate {
+                31
+            } else {
+                0
+            }
+        } else {
+            let rising = attack != (alternate && (step / 32) % 2 == 1);
+            if rising {
+                step % 32
+            } else {
+                31 - step % 32
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+
+        self.envelope_step = self.envelope_step.wrapping_add(1);
+        // Past the first cycle only the repeating shapes keep going, and
+        // they go back and forth between 32 and 63
+        if self.envelope_step >= 32 {
+            let shape = self.registers[0x0d];
+            if shape & 0x08 == 0 || shape & 0x01 != 0 {
+                self.envelope_holding = true;
+                self.envelope_step = 32;
+            } else if self.envelope_step >= 64 {
+                self.envelope_step = 32;
+            }
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.divider = (self.divider + 1) % 16;
+
+        // The envelope steps twice as often as the tones
+        if self.divider.is_multiple_of(8) {
+            self.envelope_counter += 1;
+            if self.envelope_counter >= self.envelope_period() {
+                self.envelope_counter = 0;
+                self.clock_envelope();
+            }
+        }
+
+        if self.divider != 0 {
+            return;
+        }
+
+        for tone in self.tones.iter_mut() {
+            tone.clock();
+        }
+
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.registers[6] & 0x1f).max(1) {
+            self.noise_counter = 0;
+            let bit = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | bit << 16;
+            self.noise_high = self.noise_shift & 1 != 0;
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[7];
+        let mut sum = 0.0;
+
+        for (channel, tone) in self.tones.iter().enumerate() {
+            // A disabled tone or noise counts as always high
+            let tone_high = tone.high || mixer & (1 << channel) != 0;
+            let noise_high = self.noise_high || mixer & (8 << channel) != 0;
+            if !(tone_high && noise_high) {
+                continue;
+            }
+
+            let volume = self.registers[8 + channel];
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            } else if volume & 0x0f == 0 {
+                0
+            } else {
+                // 4 bit volumes land on every other envelope level
+                (volume & 0x0f) * 2 + 1
+            };
+            sum += self.levels[level as usize] * self.gains[channel];
+        }
+
+        sum * CHANNEL_VOLUME
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Sunsoft5bAudio, register: u8, data: u8) {
+        audio.write_address(register);
+        audio.write_data(data);
+    }
+
+    #[test]
+    fn test_tone_frequency() {
+        let mut audio = Sunsoft5bAudio::new();
+        // Channel A only, period 10 at full volume
+        write(&mut audio, 0x07, 0b111_110);
+        write(&mut audio, 0x00, 10);
+        write(&mut audio, 0x08, 0x0f);
+
+        let mut toggles = 0;
+        let mut last = audio.output();
+        for _ in 0..32 * 10 * 4 {
+            audio.clock();
+            if audio.output() != last {
+                toggles += 1;
+            }
+            last = audio.output();
+        }
+
+        // A full period is 32 * 10 cycles
+        assert_eq!(toggles, 8);
+        assert!((audio.levels[31] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_volume_is_logarithmic() {
+        let audio = Sunsoft5bAudio::new();
+
+        // 3 dB per 4 bit volume step
+        let ratio = audio.levels[31] / audio.levels[29];
+        assert!((ratio - 10f32.powf(3.0 / 20.0)).abs() < 1e-4);
+        assert_eq!(audio.levels[0], 0.0);
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut audio = Sunsoft5bAudio::new();
+        write(&mut audio, 0x0b, 1);
+
+        // Decay then hold at zero
+        write(&mut audio, 0x0d, 0x00);
+        assert_eq!(audio.envelope_level(), 31);
+        for _ in 0..8 * 40 {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level(), 0);
+
+        // Repeating triangle, rises then falls
+        write(&mut audio, 0x0d, 0x0e);
+        for _ in 0..8 * 16 {
+            audio.clock();
+        }
+        let rising = audio.envelope_level();
+        for _ in 0..8 * 32 {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level(), 31 - rising);
+    }
+
+    #[test]
+    fn test_address_with_high_bits_is_ignored() {
+        let mut audio = Sunsoft5bAudio::new();
+        write(&mut audio, 0x18, 0x0f);
+
+        assert_eq!(audio.registers[8], 0);
+    }
+}
@@ -0,0 +1,390 @@
+use super::{bank_offset, ChrMemory, Mapper, Mirroring};
+use crate::cartridge::Cartridge;
+
+// Konami's VRC chips wire their register select pins to different CPU
+// address lines depending on the board, so mappers 21, 23 and 25 each
+// cover several layouts. The NES 2.0 submapper names the layout; without
+// one both candidate lines are ORed together, which works for every board
+// since games only ever write to the addresses their own board decodes.
+fn register_lines(mapper: u16, submapper: u8) -> (u16, u16) {
+    match (mapper, submapper) {
+        // VRC4a and VRC4c
+        (21, 1) => (0x02, 0x04),
+        (21, 2) => (0x40, 0x80),
+        (21, _) => (0x42, 0x84),
+        // VRC2a
+        (22, _) => (0x02, 0x01),
+        // VRC4f, VRC4e and VRC2b
+        (23, 1 | 3) => (0x01, 0x02),
+        (23, 2) => (0x04, 0x08),
+        (23, _) => (0x05, 0x0a),
+        // VRC4b, VRC4d and VRC2c
+        (25, 1 | 3) => (0x02, 0x01),
+        (25, 2) => (0x08, 0x04),
+        (25, _) => (0x0a, 0x05),
+        // VRC6a and VRC6b
+        (26, _) => (0x02, 0x01),
+        _ => (0x01, 0x02),
+    }
+}
+
+// Folds a CPU address into $x000-$x003 using the board's register lines
+pub fn decode_register(addr: u16, lines: (u16, u16)) -> u16 {
+    let a0 = (addr & lines.0 != 0) as u16;
+    let a1 = (addr & lines.1 != 0) as u16;
+
+    (addr & 0xf000) | a1 << 1 | a0
+}
+
+// The IRQ counter shared by the VRC4, VRC6 and VRC7. An 8 bit counter
+// counts up towards $FF, either every CPU cycle or every scanline, in which
+// case a prescaler takes 3 off of 341 every CPU cycle to approximate the
+// 113.667 cycles of a scanline.
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enabled_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        Self {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enabled_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+
+    // The VRC4 takes the latch one nibble at a time
+    pub fn write_latch_low(&mut self, data: u8) {
+        self.latch = (self.latch & 0xf0) | (data & 0x0f);
+    }
+
+    pub fn write_latch_high(&mut self, data: u8) {
+        self.latch = (self.latch & 0x0f) | (data << 4);
+    }
+
+    pub fn write_control(&mut self, data: u8) {
+        self.enabled_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        self.pending = false;
+
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.cycle_mode {
+            self.step();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.step();
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+// Mappers 21, 22, 23 and 25: the VRC2 and VRC4. Two switchable 8K PRG
+// banks, one of which can trade places with the fixed second to last bank
+// on the VRC4, and eight 1K CHR banks. The VRC2 has no IRQ, and mapper
+// 22's VRC2a ignores the low bit of its CHR bank numbers.
+pub struct Vrc4 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+    lines: (u16, u16),
+    vrc2: bool,
+    chr_shift: u8,
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: u8,
+    // Boards without PRG-RAM hang a one bit latch at $6000 instead
+    latch: u8,
+    irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let vrc2 = cartridge.mapper == 22 || cartridge.submapper == 3;
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; cartridge.prg_ram_size + cartridge.prg_nvram_size],
+            chr: ChrMemory::new(cartridge),
+            lines: register_lines(cartridge.mapper, cartridge.submapper),
+            vrc2,
+            chr_shift: (cartridge.mapper == 22) as u8,
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: 0,
+            latch: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / 0x2000;
+
+        let bank = match (addr >> 13) & 0x03 {
+            0 if self.prg_swap => banks.saturating_sub(2),
+            0 => self.prg_banks[0] as usize,
+            1 => self.prg_banks[1] as usize,
+            2 if self.prg_swap => self.prg_banks[0] as usize,
+            2 => banks.saturating_sub(2),
+            _ => banks.max(1) - 1,
+        };
+
+        bank_offset(self.prg_rom.len(), 0x2000, bank, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize & 0x1fff) / 0x400] >> self.chr_shift;
+        bank_offset(self.chr.len(), 0x400, bank as usize, addr)
+    }
+
+    fn write_chr_nibble(&mut self, register: u16, data: u8) {
+        let bank = (((register >> 12) - 0xb) * 2 + ((register >> 1) & 1)) as usize;
+
+        self.chr_banks[bank] = if register & 1 == 0 {
+            (self.chr_banks[bank] & 0x1f0) | (data as u16 & 0x0f)
+        } else {
+            (self.chr_banks[bank] & 0x0f) | (data as u16 & 0x1f) << 4
+        };
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x6000..=0x6fff if self.vrc2 => Some(self.latch),
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+            0x6000..=0x6fff if self.vrc2 => self.latch = data & 0x01,
+            0x8000..=0xffff => {
+                let register = decode_register(addr, self.lines);
+
+                match register {
+                    0x8000..=0x8003 => self.prg_banks[0] = data & 0x1f,
+                    0x9000..=0x9001 => self.mirroring = data & 0x03,
+                    0x9002..=0x9003 if !self.vrc2 => self.prg_swap = data & 0x02 != 0,
+                    0xa000..=0xa003 => self.prg_banks[1] = data & 0x1f,
+                    0xb000..=0xe003 => self.write_chr_nibble(register, data),
+                    0xf000 if !self.vrc2 => self.irq.write_latch_low(data),
+                    0xf001 if !self.vrc2 => self.irq.write_latch_high(data),
+                    0xf002 if !self.vrc2 => self.irq.write_control(data),
+                    0xf003 if !self.vrc2 => self.irq.acknowledge(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_offset(addr), data);
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // The VRC2 only has the first bit
+        let mirroring = if self.vrc2 {
+            self.mirroring & 0x01
+        } else {
+            self.mirroring
+        };
+
+        match mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::banked_cartridge;
+    use super::*;
+
+    fn cartridge(mapper: u16, submapper: u8) -> Cartridge {
+        let mut cartridge = banked_cartridge(mapper, submapper, 16, 0x2000, 256, 0x400);
+        cartridge.prg_ram_size = 0;
+        cartridge
+    }
+
+    #[test]
+    fn test_register_lines() {
+        // $B003 on the VRC4 layouts that wire it in different places
+        assert_eq!(decode_register(0xb006, register_lines(21, 1)), 0xb003);
+        assert_eq!(decode_register(0xb0c0, register_lines(21, 2)), 0xb003);
+        assert_eq!(decode_register(0xb00c, register_lines(23, 2)), 0xb003);
+        assert_eq!(decode_register(0xb002, register_lines(25, 1)), 0xb001);
+        assert_eq!(decode_register(0xb004, register_lines(25, 2)), 0xb002);
+        assert_eq!(decode_register(0xb001, register_lines(22, 0)), 0xb002);
+        // and the ORed lines when the layout isn't known
+        assert_eq!(decode_register(0xb080, register_lines(21, 0)), 0xb002);
+        assert_eq!(decode_register(0xb004, register_lines(21, 0)), 0xb002);
+    }
+
+    #[test]
+    fn test_vrc4_prg_swap() {
+        let mut vrc4 = Vrc4::new(&cartridge(23, 1));
+        vrc4.cpu_write(0x8000, 3);
+        vrc4.cpu_write(0xa000, 4);
+
+        assert_eq!(vrc4.cpu_read(0x8000), Some(3));
+        assert_eq!(vrc4.cpu_read(0xa000), Some(4));
+        assert_eq!(vrc4.cpu_read(0xc000), Some(14));
+        assert_eq!(vrc4.cpu_read(0xe000), Some(15));
+
+        vrc4.cpu_write(0x9002, 0x02);
+        assert_eq!(vrc4.cpu_read(0x8000), Some(14));
+        assert_eq!(vrc4.cpu_read(0xc000), Some(3));
+    }
+
+    #[test]
+    fn test_vrc4_chr_banks_and_mirroring() {
+        let mut vrc4 = Vrc4::new(&cartridge(25, 2));
+        // $C002/$C003 on VRC4d, the low and high nibbles of bank 3
+        vrc4.cpu_write(0xc004, 0x05);
+        vrc4.cpu_write(0xc00c, 0x0a);
+        vrc4.cpu_write(0x9000, 3);
+
+        assert_eq!(vrc4.ppu_read(0x0c00), 0xa5);
+        assert_eq!(vrc4.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_vrc2a_chr_and_latch() {
+        let mut vrc2 = Vrc4::new(&cartridge(22, 0));
+        // $B000 on VRC2a, bank 0 ignores its low bit
+        vrc2.cpu_write(0xb000, 0x07);
+        vrc2.cpu_write(0x9000, 3);
+        vrc2.cpu_write(0x6000, 0xff);
+
+        assert_eq!(vrc2.ppu_read(0x0000), 3);
+        assert_eq!(vrc2.mirroring(), Mirroring::Horizontal);
+        assert_eq!(vrc2.cpu_read(0x6000), Some(1));
+    }
+
+    #[test]
+    fn test_irq_scanline_mode() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xfe);
+        irq.write_control(0x02);
+
+        // Two scanlines of 113 or 114 cycles
+        for _ in 0..227 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+
+        // The acknowledge copies A into E, which was clear
+        irq.acknowledge();
+        for _ in 0..1000 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+    }
+
+    #[test]
+    fn test_irq_cycle_mode() {
+        let mut vrc4 = Vrc4::new(&cartridge(21, 1));
+        // $F000/$F001/$F002 on VRC4a
+        vrc4.cpu_write(0xf000, 0x0d);
+        vrc4.cpu_write(0xf002, 0x0f);
+        vrc4.cpu_write(0xf004, 0x07);
+
+        for _ in 0..2 {
+            vrc4.cpu_clock();
+        }
+        assert!(!vrc4.irq());
+        vrc4.cpu_clock();
+        assert!(vrc4.irq());
+
+        // Enabled again after the acknowledge, so it keeps going
+        vrc4.cpu_write(0xf006, 0);
+        assert!(!vrc4.irq());
+        for _ in 0..0x100 - 0xfd {
+            vrc4.cpu_clock();
+        }
+        assert!(vrc4.irq());
+    }
+}
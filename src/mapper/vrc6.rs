@@ -0,0 +1,231 @@
+use super::vrc::{decode_register, VrcIrq};
+use super::{bank_offset, ChrMemory, Mapper, Mirroring, Vrc6Audio};
+use crate::cartridge::Cartridge;
+
+// Mappers 24 and 26, the VRC6a and VRC6b, which only differ in having A0
+// and A1 swapped. A 16K and an 8K switchable PRG bank, eight CHR
+// registers whose layout depends on $B003, the VRC IRQ and three extra
+// sound channels.
+//
+// $B003 can also map CHR-ROM over the nametables, which no game does, so
+// only its CHR layouts, mirroring and PRG-RAM enable are emulated.
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+    lines: (u16, u16),
+
+    prg_16k: u8,
+    prg_8k: u8,
+    chr_banks: [u8; 8],
+    banking: u8,
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+        let lines = if cartridge.mapper == 26 {
+            (0x02, 0x01)
+        } else {
+            (0x01, 0x02)
+        };
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; ram_size.max(0x2000)],
+            chr: ChrMemory::new(cartridge),
+            lines,
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            banking: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+
+        match addr {
+            0x8000..=0xbfff => bank_offset(len, 0x4000, self.prg_16k as usize, addr),
+            0xc000..=0xdfff => bank_offset(len, 0x2000, self.prg_8k as usize, addr),
+            _ => bank_offset(len, 0x2000, (len / 0x2000).max(1) - 1, addr),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking & 0x80 != 0
+    }
+
+    // 1K bank number for a pattern table address. Mode 0 uses all eight
+    // registers as 1K banks, mode 1 the first four as 2K banks and the
+    // other modes mix the two, 1K banks for $0000 and 2K banks for $1000.
+    fn chr_bank(&self, addr: u16) -> usize {
+        let slot = (addr as usize & 0x1fff) / 0x400;
+        let a10 = slot & 1;
+
+        let two_k = |register: usize| (self.chr_banks[register] as usize & !1) | a10;
+
+        match self.banking & 0x03 {
+            0 => self.chr_banks[slot] as usize,
+            1 => two_k(slot / 2),
+            _ if slot < 4 => self.chr_banks[slot] as usize,
+            _ => two_k(4 + (slot - 4) / 2),
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+            0x8000..=0xffff => {
+                let register = decode_register(addr, self.lines);
+
+                match register {
+                    0x8000..=0x8003 => self.prg_16k = data & 0x0f,
+                    0x9000..=0xb002 => self.audio.write(register, data),
+                    0xb003 => self.banking = data,
+                    0xc000..=0xc003 => self.prg_8k = data & 0x1f,
+                    0xd000..=0xe003 => {
+                        let bank = ((register >> 12) - 0xd) * 4 + (register & 0x03);
+                        self.chr_banks[bank as usize] = data;
+                    }
+                    0xf000 => self.irq.write_latch(data),
+                    0xf001 => self.irq.write_control(data),
+                    0xf002 => self.irq.acknowledge(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let offset = bank_offset(self.chr.len(), 0x400, self.chr_bank(addr), addr);
+        self.chr.read(offset)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let offset = bank_offset(self.chr.len(), 0x400, self.chr_bank(addr), addr);
+        self.chr.write(offset, data);
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.banking >> 2) & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn audio_channels(&self) -> Vec<&'static str> {
+        Vrc6Audio::CHANNELS.to_vec()
+    }
+
+    fn set_audio_gains(&mut self, gains: &[f32]) {
+        self.audio.set_gains(gains);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::banked_cartridge;
+    use super::*;
+
+    fn cartridge(mapper: u16) -> Cartridge {
+        banked_cartridge(mapper, 0, 16, 0x2000, 256, 0x400)
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut vrc6 = Vrc6::new(&cartridge(24));
+        vrc6.cpu_write(0x8000, 2);
+        vrc6.cpu_write(0xc000, 9);
+
+        assert_eq!(vrc6.cpu_read(0x8000), Some(4));
+        assert_eq!(vrc6.cpu_read(0xa000), Some(5));
+        assert_eq!(vrc6.cpu_read(0xc000), Some(9));
+        assert_eq!(vrc6.cpu_read(0xe000), Some(15));
+    }
+
+    #[test]
+    fn test_chr_modes_and_mirroring() {
+        let mut vrc6 = Vrc6::new(&cartridge(26));
+        // $D001 and $B003 on the VRC6b
+        vrc6.cpu_write(0xd002, 0x21);
+        vrc6.cpu_write(0xb003, 0x84);
+
+        assert_eq!(vrc6.ppu_read(0x0400), 0x21);
+        assert_eq!(vrc6.mirroring(), Mirroring::Horizontal);
+
+        // Mode 1 makes $D000 a 2K bank
+        vrc6.cpu_write(0xd000, 0x30);
+        vrc6.cpu_write(0xb003, 0x81);
+        assert_eq!(vrc6.ppu_read(0x0000), 0x30);
+        assert_eq!(vrc6.ppu_read(0x0400), 0x31);
+        assert_eq!(vrc6.ppu_read(0x0800), 0x20);
+    }
+
+    #[test]
+    fn test_prg_ram_enable() {
+        let mut vrc6 = Vrc6::new(&cartridge(24));
+        assert_eq!(vrc6.cpu_read(0x6000), None);
+
+        vrc6.cpu_write(0xb003, 0x80);
+        vrc6.cpu_write(0x6000, 0x5a);
+        assert_eq!(vrc6.cpu_read(0x6000), Some(0x5a));
+    }
+
+    #[test]
+    fn test_audio_and_irq() {
+        let mut vrc6 = Vrc6::new(&cartridge(24));
+        vrc6.cpu_write(0x9000, 0x8f);
+        vrc6.cpu_write(0x9002, 0x80);
+        vrc6.cpu_write(0xf000, 0xff);
+        vrc6.cpu_write(0xf001, 0x06);
+
+        vrc6.cpu_clock();
+
+        assert!(vrc6.audio_output() > 0.0);
+        assert!(vrc6.irq());
+    }
+}
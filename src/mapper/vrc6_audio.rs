@@ -0,0 +1,253 @@
+// The VRC6's sound: two pulse channels with 16 step duty cycles and a
+// sawtooth channel built on an accumulator. All three timers run off the
+// CPU clock, and $9003 can halt them or speed up their period counting.
+
+#[derive(Default)]
+struct Pulse {
+    volume: u8,
+    duty: u8,
+    // Ignores the duty cycle and outputs the volume constantly
+    digitized: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.digitized = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0f;
+            }
+            1 => self.period = (self.period & 0xf00) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | (data as u16 & 0x0f) << 8;
+                self.enabled = data & 0x80 != 0;
+                // Disabling resets the duty cycle
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0f;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sawtooth {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    // Every second timer clock adds the rate to the accumulator, and the
+    // fourteenth clears it
+    clocks: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0xf00) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | (data as u16 & 0x0f) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.accumulator = 0;
+                    self.clocks = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+
+        self.clocks += 1;
+        if self.clocks == 14 {
+            self.clocks = 0;
+            self.accumulator = 0;
+        } else if self.clocks.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+pub struct Vrc6Audio {
+    pulses: [Pulse; 2],
+    sawtooth: Sawtooth,
+    halt: bool,
+    shift: u8,
+    gains: [f32; 3],
+}
+
+impl Vrc6Audio {
+    pub const CHANNELS: [&'static str; 3] = ["vrc6-pulse1", "vrc6-pulse2", "vrc6-sawtooth"];
+
+    pub fn new() -> Self {
+        Self {
+            pulses: [Pulse::default(), Pulse::default()],
+            sawtooth: Sawtooth::default(),
+            halt: false,
+            shift: 0,
+            gains: [1.0; 3],
+        }
+    }
+
+    // Scales each channel's share of output(), in CHANNELS order
+    pub fn set_gains(&mut self, gains: &[f32]) {
+        for (gain, value) in self.gains.iter_mut().zip(gains) {
+            *gain = *value;
+        }
+    }
+
+    // Takes the registers after the board's address lines have been
+    // folded into $9000-$9003, $A000-$A002 and $B000-$B002
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0x9003 => {
+                self.halt = data & 0x01 != 0;
+                self.shift = if data & 0x04 != 0 {
+                    8
+                } else if data & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000..=0x9002 => self.pulses[0].write(register & 0x03, data),
+            0xa000..=0xa002 => self.pulses[1].write(register & 0x03, data),
+            0xb000..=0xb002 => self.sawtooth.write(register & 0x03, data),
+            _ => {}
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+
+        for pulse in self.pulses.iter_mut() {
+            pulse.clock(self.shift);
+        }
+        self.sawtooth.clock(self.shift);
+    }
+
+    // Output on the same scale as the APU mix. The channels add up
+    // linearly, at about the loudness of an APU pulse per volume step.
+    pub fn output(&self) -> f32 {
+        let sum = self.pulses[0].output() as f32 * self.gains[0]
+            + self.pulses[1].output() as f32 * self.gains[1]
+            + self.sawtooth.output() as f32 * self.gains[2];
+        sum * 0.00752
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty_cycle() {
+        let mut audio = Vrc6Audio::new();
+        // Duty 3 is 4 steps out of 16 at volume 10
+        audio.write(0x9000, 0x3a);
+        audio.write(0x9001, 0);
+        audio.write(0x9002, 0x80);
+
+        let mut high = 0;
+        for _ in 0..16 {
+            audio.clock();
+            if audio.output() > 0.0 {
+                high += 1;
+            }
+        }
+
+        assert_eq!(high, 4);
+    }
+
+    #[test]
+    fn test_sawtooth_ramp() {
+        let mut audio = Vrc6Audio::new();
+        audio.write(0xb000, 0x2a);
+        audio.write(0xb001, 0);
+        audio.write(0xb002, 0x80);
+
+        let mut levels = Vec::new();
+        for _ in 0..14 {
+            audio.clock();
+            levels.push(audio.sawtooth.output());
+        }
+
+        // Six additions of 42, then back to zero
+        assert_eq!(levels[11], (6 * 42) >> 3);
+        assert_eq!(levels[13], 0);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut audio = Vrc6Audio::new();
+        audio.write(0xb000, 0x3f);
+        audio.write(0xb002, 0x80);
+        audio.write(0x9003, 0x01);
+
+        for _ in 0..10 {
+            audio.clock();
+        }
+
+        assert_eq!(audio.output(), 0.0);
+    }
+
+    #[test]
+    fn test_gains_only_scale_the_mix() {
+        let mut audio = Vrc6Audio::new();
+        // Pulse 1 digitized at volume 8
+        audio.write(0x9000, 0x88);
+        audio.write(0x9002, 0x80);
+        audio.clock();
+        let full = audio.output();
+
+        audio.set_gains(&[0.5, 1.0, 1.0]);
+        assert!((audio.output() - full / 2.0).abs() < 1e-6);
+        audio.set_gains(&[0.0]);
+        assert_eq!(audio.output(), 0.0);
+        assert_eq!(audio.pulses[0].output(), 8);
+    }
+}
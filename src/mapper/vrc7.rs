@@ -0,0 +1,223 @@
+use super::vrc::VrcIrq;
+use super::{bank_offset, ChrMemory, Mapper, Mirroring, Vrc7Audio};
+use crate::cartridge::Cartridge;
+
+// Mapper 85, the VRC7. Three switchable 8K PRG banks, eight 1K CHR banks,
+// the VRC IRQ and an FM sound chip. Only one address line matters besides
+// the top four: A4 on the VRC7a (submapper 2) and A3 on the VRC7b
+// (submapper 1). The sound chip decodes its own ports, which always sit at
+// $9010 and $9030.
+pub struct Vrc7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMemory,
+    line: u16,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    control: u8,
+    irq: VrcIrq,
+    audio: Vrc7Audio,
+}
+
+impl Vrc7 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+        let line = match cartridge.submapper {
+            1 => 0x08,
+            2 => 0x10,
+            _ => 0x18,
+        };
+
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: vec![0; ram_size.max(0x2000)],
+            chr: ChrMemory::new(cartridge),
+            line,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc7Audio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x8000..=0x9fff => self.prg_banks[0] as usize,
+            0xa000..=0xbfff => self.prg_banks[1] as usize,
+            0xc000..=0xdfff => self.prg_banks[2] as usize,
+            _ => (len / 0x2000).max(1) - 1,
+        };
+
+        bank_offset(len, 0x2000, bank, addr)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize & 0x1fff) / 0x400];
+        bank_offset(self.chr.len(), 0x400, bank as usize, addr)
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr == 0x9010 {
+            self.audio.write_select(data);
+            return;
+        }
+        if addr == 0x9030 {
+            self.audio.write_data(data);
+            return;
+        }
+
+        let high = addr & self.line != 0;
+
+        match (addr & 0xf000, high) {
+            (0x6000 | 0x7000, _) if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+            (0x8000, false) => self.prg_banks[0] = data & 0x3f,
+            (0x8000, true) => self.prg_banks[1] = data & 0x3f,
+            (0x9000, false) => self.prg_banks[2] = data & 0x3f,
+            (0xa000..=0xd000, _) => {
+                let bank = ((addr >> 12) - 0xa) * 2 + high as u16;
+                self.chr_banks[bank as usize] = data;
+            }
+            (0xe000, false) => {
+                self.control = data;
+                self.audio.set_silenced(data & 0x40 != 0);
+            }
+            (0xe000, true) => self.irq.write_latch(data),
+            (0xf000, false) => self.irq.write_control(data),
+            (0xf000, true) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_offset(addr), data);
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn audio_channels(&self) -> Vec<&'static str> {
+        Vrc7Audio::CHANNELS.to_vec()
+    }
+
+    fn set_audio_gains(&mut self, gains: &[f32]) {
+        self.audio.set_gains(gains);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::banked_cartridge;
+    use super::*;
+
+    fn cartridge(submapper: u8) -> Cartridge {
+        banked_cartridge(85, submapper, 16, 0x2000, 256, 0x400)
+    }
+
+    #[test]
+    fn test_banks_on_both_layouts() {
+        for (submapper, high) in [(1, 0x08), (2, 0x10)] {
+            let mut vrc7 = Vrc7::new(&cartridge(submapper));
+            vrc7.cpu_write(0x8000, 3);
+            vrc7.cpu_write(0x8000 | high, 4);
+            vrc7.cpu_write(0x9000, 5);
+            vrc7.cpu_write(0xb000 | high, 0x42);
+
+            assert_eq!(vrc7.cpu_read(0x8000), Some(3));
+            assert_eq!(vrc7.cpu_read(0xa000), Some(4));
+            assert_eq!(vrc7.cpu_read(0xc000), Some(5));
+            assert_eq!(vrc7.cpu_read(0xe000), Some(15));
+            assert_eq!(vrc7.ppu_read(0x0c00), 0x42);
+        }
+    }
+
+    #[test]
+    fn test_control_register() {
+        let mut vrc7 = Vrc7::new(&cartridge(2));
+        assert_eq!(vrc7.cpu_read(0x6000), None);
+
+        vrc7.cpu_write(0xe000, 0x81);
+        vrc7.cpu_write(0x6000, 0x77);
+        assert_eq!(vrc7.cpu_read(0x6000), Some(0x77));
+        assert_eq!(vrc7.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_audio_ports() {
+        for submapper in [1, 2] {
+            let mut vrc7 = Vrc7::new(&cartridge(submapper));
+            vrc7.cpu_write(0x9000, 5);
+            // Instrument 3 on channel 0, keyed on
+            for (register, data) in [(0x30, 0x30), (0x10, 0x80), (0x20, 0x18)] {
+                vrc7.cpu_write(0x9010, register);
+                vrc7.cpu_write(0x9030, data);
+            }
+
+            let mut peak: f32 = 0.0;
+            for _ in 0..36 * 500 {
+                vrc7.cpu_clock();
+                peak = peak.max(vrc7.audio_output().abs());
+            }
+            assert!(peak > 0.01);
+            // The VRC7b's A3 doesn't turn $9010 into a bank register
+            assert_eq!(vrc7.cpu_read(0xc000), Some(5));
+
+            // Holding the sound chip in reset shuts it up
+            vrc7.cpu_write(0xe000, 0x40);
+            vrc7.cpu_clock();
+            assert_eq!(vrc7.audio_output(), 0.0);
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use std::f32::consts::PI;
+
+// The VRC7's sound, a cut down YM2413 (OPLL): six two operator FM channels
+// using either one of 15 built in instruments or a single custom one
+// programmed through registers $00-$07. It generates a sample every 36 CPU
+// cycles.
+//
+// The operators work in the attenuation domain like the real chip, every
+// gain (envelope, total level, key scaling, tremolo and channel volume) is
+// a number of dB summed up before converting to an amplitude, but the
+// tables of the chip are replaced with the curves they approximate.
+
+// The instruments built into the VRC7, from the dump at
+// https://www.nesdev.org/wiki/VRC7_audio
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+    [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+    [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+    [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+    [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06],
+];
+
+const CYCLES_PER_SAMPLE: u32 = 36;
+const SAMPLE_RATE: f32 = 3_579_545.0 / 72.0;
+
+const MULTIPLIERS: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+// Key scale level attenuation in dB at block 7 for the top 4 bits of the
+// frequency number, dropping 6 dB for every block below
+const KEY_SCALE_LEVELS: [f32; 16] = [
+    0.0, 9.0, 12.0, 13.875, 15.0, 16.125, 16.875, 17.625, 18.0, 18.75, 19.125, 19.5, 19.875, 20.25,
+    20.625, 21.0,
+];
+// How much of the above each KSL setting applies, 0, 1.5, 3 and 6 dB per
+// octave
+const KEY_SCALE_FACTORS: [f32; 4] = [0.0, 0.25, 0.5, 1.0];
+
+// The envelope covers 48 dB, anything past that is silence
+const ENVELOPE_MAX: f32 = 48.0;
+
+const TREMOLO_HZ: f32 = 3.7;
+const TREMOLO_DB: f32 = 4.8;
+const VIBRATO_HZ: f32 = 6.4;
+// About 7 cents either way
+const VIBRATO_DEPTH: f32 = 0.004;
+
+// Phase shift in cycles a full scale modulator applies to the carrier
+const MODULATION_DEPTH: f32 = 4.0;
+
+// Output of a single full scale channel against the APU mix
+const CHANNEL_VOLUME: f32 = 0.05;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// The parameters of one operator out of an instrument
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    // Operator 0 is the modulator and 1 the carrier
+    fn new(patch: &[u8; 8], op: usize) -> Self {
+        Self {
+            tremolo: patch[op] & 0x80 != 0,
+            vibrato: patch[op] & 0x40 != 0,
+            sustained: patch[op] & 0x20 != 0,
+            key_scale_rate: patch[op] & 0x10 != 0,
+            multiplier: MULTIPLIERS[(patch[op] & 0x0f) as usize],
+            key_scale_level: patch[2 + op] >> 6,
+            rectified: patch[3] & (0x08 << op) != 0,
+            attack: patch[4 + op] >> 4,
+            decay: patch[4 + op] & 0x0f,
+            sustain_level: patch[6 + op] >> 4,
+            release: patch[6 + op] & 0x0f,
+        }
+    }
+}
+
+struct Operator {
+    // Position in the waveform, in cycles
+    phase: f32,
+    // Attenuation from the envelope in dB
+    envelope: f32,
+    state: EnvelopeState,
+}
+
+impl Operator {
+    fn new() -> Self {
+        Self {
+            phase: 0.0,
+            envelope: ENVELOPE_MAX,
+            state: EnvelopeState::Release,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn update_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, sustain_pedal: bool) {
+        let rate = |value: u8| {
+            if value == 0 {
+                0
+            } else {
+                (4 * value + key_scale).min(63)
+            }
+        };
+
+        match self.state {
+            EnvelopeState::Attack => {
+                let rate = rate(patch.attack);
+                if rate >= 60 {
+                    self.envelope = 0.0;
+                } else if rate > 0 {
+                    // Exponential, reaching full level in about the
+                    // attack time
+                    let time =
+                        2.826 * 4.0 / (4 + (rate & 3)) as f32 / 2f32.powi((rate >> 2) as i32 - 1);
+                    self.envelope *= (-6.0 / (time * SAMPLE_RATE)).exp();
+                    if self.envelope < 0.1 {
+                        self.envelope = 0.0;
+                    }
+                }
+
+                if self.envelope == 0.0 {
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.envelope += decay_step(rate(patch.decay));
+
+                let sustain_level = patch.sustain_level as f32 * 3.0;
+                if self.envelope >= sustain_level {
+                    self.envelope = sustain_level;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            // Percussive instruments keep fading at the release rate while
+            // the key is held
+            EnvelopeState::Sustain if !patch.sustained => {
+                self.envelope += decay_step(rate(patch.release));
+            }
+            EnvelopeState::Sustain => {}
+            EnvelopeState::Release => {
+                let release = if sustain_pedal {
+                    5
+                } else if patch.sustained {
+                    patch.release
+                } else {
+                    7
+                };
+                self.envelope += decay_step(rate(release));
+            }
+        }
+
+        self.envelope = self.envelope.min(ENVELOPE_MAX);
+    }
+
+    fn output(&self, patch: &OperatorPatch, attenuation: f32, modulation: f32) -> f32 {
+        if self.envelope >= ENVELOPE_MAX {
+            return 0.0;
+        }
+
+        let wave = (2.0 * PI * (self.phase + modulation)).sin();
+        let wave = if patch.rectified && wave < 0.0 {
+            0.0
+        } else {
+            wave
+        };
+
+        wave * 10f32.powf(-(self.envelope + attenuation) / 20.0)
+    }
+}
+
+// dB the envelope moves by per sample while decaying or releasing. It
+// covers 96 dB in about 39 seconds at rate 4 and twice as fast every four
+// rates up.
+fn decay_step(rate: u8) -> f32 {
+    if rate == 0 {
+        return 0.0;
+    }
+
+    let time = 39.28 * 4.0 / (4 + (rate & 3)) as f32 / 2f32.powi((rate >> 2) as i32 - 1);
+    96.0 / (time * SAMPLE_RATE)
+}
+
+struct Channel {
+    frequency: u16,
+    block: u8,
+    key: bool,
+    sustain_pedal: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    // Last two modulator outputs, averaged for the feedback
+    feedback: [f32; 2],
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            frequency: 0,
+            block: 0,
+            key: false,
+            sustain_pedal: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+        }
+    }
+
+    fn key_scale(&self, patch: &OperatorPatch) -> u8 {
+        let key = self.block * 2 + (self.frequency >> 8) as u8;
+        if patch.key_scale_rate {
+            key
+        } else {
+            key >> 2
+        }
+    }
+
+    fn key_scale_level(&self, patch: &OperatorPatch) -> f32 {
+        let level =
+            KEY_SCALE_LEVELS[(self.frequency >> 5) as usize & 0x0f] - 6.0 * (7 - self.block) as f32;
+        level.max(0.0) * KEY_SCALE_FACTORS[patch.key_scale_level as usize]
+    }
+}
+
+pub struct Vrc7Audio {
+    select: u8,
+    custom_patch: [u8; 8],
+    channels: [Channel; 6],
+    silenced: bool,
+    cycles: u32,
+    // Position of the tremolo and vibrato oscillators, in cycles
+    tremolo_phase: f32,
+    vibrato_phase: f32,
+    sample: f32,
+    gains: [f32; 6],
+}
+
+impl Vrc7Audio {
+    pub const CHANNELS: [&'static str; 6] = [
+        "vrc7-fm1", "vrc7-fm2", "vrc7-fm3", "vrc7-fm4", "vrc7-fm5", "vrc7-fm6",
+    ];
+
+    pub fn new() -> Self {
+        Self {
+            select: 0,
+            custom_patch: [0; 8],
+            channels: std::array::from_fn(|_| Channel::new()),
+            silenced: false,
+            cycles: 0,
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+            sample: 0.0,
+            gains: [1.0; 6],
+        }
+    }
+
+    // Scales each channel's share of output(), in CHANNELS order
+    pub fn set_gains(&mut self, gains: &[f32]) {
+        for (gain, value) in self.gains.iter_mut().zip(gains) {
+            *gain = *value;
+        }
+    }
+
+    pub fn write_select(&mut self, data: u8) {
+        self.select = data;
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        let register = self.select as usize;
+        let channel = register & 0x0f;
+
+        match register {
+            0x00..=0x07 => self.custom_patch[register] = data,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[channel];
+                channel.frequency = (channel.frequency & 0x100) | data as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[channel];
+                channel.frequency = (channel.frequency & 0xff) | (data as u16 & 0x01) << 8;
+                channel.block = (data >> 1) & 0x07;
+                channel.sustain_pedal = data & 0x20 != 0;
+
+                let key = data & 0x10 != 0;
+                if key && !channel.key {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key && channel.key {
+                    channel.modulator.state = EnvelopeState::Release;
+                    channel.carrier.state = EnvelopeState::Release;
+                }
+                channel.key = key;
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[channel];
+                channel.instrument = data >> 4;
+                channel.volume = data & 0x0f;
+            }
+            _ => {}
+        }
+    }
+
+    // $E000 bit 6 holds the chip in reset, which clears it and keeps it
+    // quiet
+    pub fn set_silenced(&mut self, silenced: bool) {
+        if silenced && !self.silenced {
+            self.channels = std::array::from_fn(|_| Channel::new());
+            self.custom_patch = [0; 8];
+            self.sample = 0.0;
+        }
+        self.silenced = silenced;
+    }
+
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 {
+            self.custom_patch
+        } else {
+            PATCHES[instrument as usize - 1]
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.cycles += 1;
+        if self.cycles < CYCLES_PER_SAMPLE {
+            return;
+        }
+        self.cycles = 0;
+
+        if !self.silenced {
+            self.sample = self.generate_sample();
+        }
+    }
+
+    fn generate_sample(&mut self) -> f32 {
+        self.tremolo_phase = (self.tremolo_phase + TREMOLO_HZ / SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_HZ / SAMPLE_RATE).fract();
+        let tremolo = TREMOLO_DB * (1.0 - (2.0 * PI * self.tremolo_phase).cos()) / 2.0;
+        let vibrato = 1.0 + VIBRATO_DEPTH * (2.0 * PI * self.vibrato_phase).sin();
+
+        let mut mix = 0.0;
+        for n in 0..self.channels.len() {
+            let patch = self.patch(self.channels[n].instrument);
+            let modulator = OperatorPatch::new(&patch, 0);
+            let carrier = OperatorPatch::new(&patch, 1);
+            let total_level = (patch[2] & 0x3f) as f32 * 0.75;
+            let feedback = patch[3] & 0x07;
+
+            let channel = &mut self.channels[n];
+            let step = channel.frequency as f32 * (1 << channel.block) as f32 / (1 << 19) as f32;
+
+            // Modulator
+            let mut attenuation = total_level + channel.key_scale_level(&modulator);
+            if modulator.tremolo {
+                attenuation += tremolo;
+            }
+            let feedback_shift = if feedback == 0 {
+                0.0
+            } else {
+                (channel.feedback[0] + channel.feedback[1]) / 2.0 * (1 << (feedback - 1)) as f32
+                    / 32.0
+            };
+            let modulator_out = channel
+                .modulator
+                .output(&modulator, attenuation, feedback_shift);
+            channel.feedback = [channel.feedback[1], modulator_out];
+
+            // Carrier
+            let mut attenuation = channel.volume as f32 * 3.0 + channel.key_scale_level(&carrier);
+            if carrier.tremolo {
+                attenuation += tremolo;
+            }
+            mix += channel
+                .carrier
+                .output(&carrier, attenuation, modulator_out * MODULATION_DEPTH)
+                * self.gains[n];
+
+            for (operator, patch) in [
+                (&mut channel.modulator, &modulator),
+                (&mut channel.carrier, &carrier),
+            ] {
+                let step = step * patch.multiplier * if patch.vibrato { vibrato } else { 1.0 };
+                operator.phase = (operator.phase + step).fract();
+            }
+
+            let (modulator_scale, carrier_scale) =
+                (channel.key_scale(&modulator), channel.key_scale(&carrier));
+            let pedal = channel.sustain_pedal;
+            channel
+                .modulator
+                .update_envelope(&modulator, modulator_scale, pedal);
+            channel
+                .carrier
+                .update_envelope(&carrier, carrier_scale, pedal);
+        }
+
+        mix * CHANNEL_VOLUME
+    }
+
+    pub fn output(&self) -> f32 {
+        if self.silenced {
+            0.0
+        } else {
+            self.sample
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Vrc7Audio, register: u8, data: u8) {
+        audio.write_select(register);
+        audio.write_data(data);
+    }
+
+    // Runs for a number of samples and returns the loudest one
+    fn peak(audio: &mut Vrc7Audio, samples: u32) -> f32 {
+        let mut peak: f32 = 0.0;
+        for _ in 0..samples * CYCLES_PER_SAMPLE {
+            audio.clock();
+            peak = peak.max(audio.output().abs());
+        }
+        peak
+    }
+
+    // A 440 Hz note on channel 0 with the given instrument, full volume
+    fn key_on(audio: &mut Vrc7Audio, instrument: u8) {
+        write(audio, 0x30, instrument << 4);
+        write(audio, 0x10, 0x22);
+        write(audio, 0x20, 0x11 | 4 << 1);
+    }
+
+    #[test]
+    fn test_silent_until_key_on() {
+        let mut audio = Vrc7Audio::new();
+        assert_eq!(peak(&mut audio, 1000), 0.0);
+
+        key_on(&mut audio, 3);
+        assert!(peak(&mut audio, 1000) > 0.01);
+    }
+
+    #[test]
+    fn test_pitch() {
+        let mut audio = Vrc7Audio::new();
+        // A sine carrier with no modulation, attack and decay at their
+        // fastest so the level stays put
+        for (register, data) in [0x01, 0x21, 0x3f, 0x00, 0xff, 0xf0, 0x0f, 0x0f]
+            .into_iter()
+            .enumerate()
+        {
+            write(&mut audio, register as u8, data);
+        }
+        key_on(&mut audio, 0);
+        peak(&mut audio, 100);
+
+        // Count the rising zero crossings over one second of samples
+        let mut crossings = 0;
+        let mut last = audio.output();
+        for _ in 0..SAMPLE_RATE as u32 {
+            for _ in 0..CYCLES_PER_SAMPLE {
+                audio.clock();
+            }
+            if last < 0.0 && audio.output() >= 0.0 {
+                crossings += 1;
+            }
+            last = audio.output();
+        }
+
+        // Frequency 0x122 at block 4 is 0x122 * 49716 * 16 / 2^19 Hz
+        let expected = (0x122 as f32 * SAMPLE_RATE * 16.0 / (1 << 19) as f32) as i32;
+        assert!((crossings - expected).abs() <= 1);
+    }
+
+    #[test]
+    fn test_key_off_releases() {
+        let mut audio = Vrc7Audio::new();
+        key_on(&mut audio, 1);
+        let held = peak(&mut audio, 2000);
+
+        write(&mut audio, 0x20, 4 << 1);
+        peak(&mut audio, 40000);
+
+        assert!(peak(&mut audio, 1000) < held / 10.0);
+    }
+
+    #[test]
+    fn test_reset_silences() {
+        let mut audio = Vrc7Audio::new();
+        key_on(&mut audio, 5);
+        peak(&mut audio, 100);
+
+        audio.set_silenced(true);
+        assert_eq!(peak(&mut audio, 100), 0.0);
+
+        // and forgets the note
+        audio.set_silenced(false);
+        assert_eq!(peak(&mut audio, 100), 0.0);
+    }
+}
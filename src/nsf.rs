@@ -0,0 +1,600 @@
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::region::Region;
+
+// NSF and NSFe music rips. Both hold a game's sound driver and music data
+// along with the addresses of two routines: INIT, which sets up a track,
+// and PLAY, which gets called at a fixed rate (usually once per frame) to
+// advance it. NSFe packs the same fields into chunks and adds per track
+// names and lengths.
+
+const NSF_MAGIC: &[u8] = b"NESM\x1a";
+const NSFE_MAGIC: &[u8] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// The expansion chip bits of the header
+pub const VRC6: u8 = 0x01;
+pub const VRC7: u8 = 0x02;
+pub const FDS: u8 = 0x04;
+pub const MMC5: u8 = 0x08;
+pub const NAMCO163: u8 = 0x10;
+pub const SUNSOFT5B: u8 = 0x20;
+
+const CHIP_NAMES: [(u8, &str); 6] = [
+    (VRC6, "VRC6"),
+    (VRC7, "VRC7"),
+    (FDS, "FDS"),
+    (MMC5, "MMC5"),
+    (NAMCO163, "Namco 163"),
+    (SUNSOFT5B, "Sunsoft 5B"),
+];
+
+// Chips whose sound the player can produce
+const SUPPORTED_CHIPS: u8 = VRC6 | VRC7 | MMC5 | NAMCO163 | SUNSOFT5B;
+
+// Play rates NSFe files without a RATE chunk run at, in microseconds
+const DEFAULT_NTSC_SPEED: u16 = 16639;
+const DEFAULT_PAL_SPEED: u16 = 19997;
+
+#[derive(Debug, PartialEq)]
+pub enum NsfError {
+    UnknownFormat,
+    Truncated,
+    MissingChunk(&'static str),
+    // NSFe chunks with an upper case first letter have to be understood
+    UnsupportedChunk(String),
+    LoadAddress(u16),
+    NoData,
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NsfError::UnknownFormat => write!(f, "not an NSF or NSFe file"),
+            NsfError::Truncated => write!(f, "the file is shorter than its header says"),
+            NsfError::MissingChunk(id) => write!(f, "the NSFe file has no {} chunk", id),
+            NsfError::UnsupportedChunk(id) => write!(f, "NSFe chunk {} isn't supported", id),
+            NsfError::LoadAddress(addr) => {
+                write!(f, "music loaded at ${:04x} is outside $8000-$FFFF", addr)
+            }
+            NsfError::NoData => write!(f, "the file has no music data"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Track {
+    pub name: Option<String>,
+    // Milliseconds, NSFe only
+    pub length: Option<u32>,
+    pub fade: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    // Zero based
+    pub start_track: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    // Microseconds between PLAY calls
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // The initial values of $5FF8-$5FFF. The tune is only bankswitched if
+    // any of them is set.
+    pub banks: [u8; 8],
+    pub region: Region,
+    pub chips: u8,
+    pub data: Vec<u8>,
+    pub tracks: Vec<Track>,
+}
+
+impl Nsf {
+    pub fn is_nsf(bytes: &[u8]) -> bool {
+        bytes.starts_with(NSF_MAGIC)
+    }
+
+    pub fn is_nsfe(bytes: &[u8]) -> bool {
+        bytes.starts_with(NSFE_MAGIC)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Nsf, NsfError> {
+        if Nsf::is_nsf(bytes) {
+            Nsf::from_nsf(bytes)
+        } else if Nsf::is_nsfe(bytes) {
+            Nsf::from_nsfe(bytes)
+        } else {
+            Err(NsfError::UnknownFormat)
+        }
+    }
+
+    pub fn from_nsf(bytes: &[u8]) -> Result<Nsf, NsfError> {
+        if !Nsf::is_nsf(bytes) {
+            return Err(NsfError::UnknownFormat);
+        }
+        let header = bytes.get(..NSF_HEADER_SIZE).ok_or(NsfError::Truncated)?;
+        let word = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+
+        let nsf = Nsf {
+            title: text(&header[0x0e..0x2e]),
+            artist: text(&header[0x2e..0x4e]),
+            copyright: text(&header[0x4e..0x6e]),
+            ripper: String::new(),
+            start_track: header[0x07].saturating_sub(1),
+            load_addr: word(0x08),
+            init_addr: word(0x0a),
+            play_addr: word(0x0c),
+            ntsc_speed: word(0x6e),
+            pal_speed: word(0x78),
+            banks: header[0x70..0x78].try_into().unwrap(),
+            region: region(header[0x7a]),
+            chips: header[0x7b],
+            data: bytes[NSF_HEADER_SIZE..].to_vec(),
+            tracks: vec![Track::default(); header[0x06] as usize],
+        };
+        nsf.check()?;
+
+        Ok(nsf)
+    }
+
+    pub fn from_nsfe(bytes: &[u8]) -> Result<Nsf, NsfError> {
+        if !Nsf::is_nsfe(bytes) {
+            return Err(NsfError::UnknownFormat);
+        }
+
+        let mut info = None;
+        let mut data = None;
+        let mut banks = [0; 8];
+        let mut speeds = (DEFAULT_NTSC_SPEED, DEFAULT_PAL_SPEED);
+        let mut strings = Vec::new();
+        let mut names = Vec::new();
+        let mut lengths = Vec::new();
+        let mut fades = Vec::new();
+
+        let mut offset = NSFE_MAGIC.len();
+        loop {
+            let header = bytes.get(offset..offset + 8).ok_or(NsfError::Truncated)?;
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let id = &header[4..8];
+            let chunk = bytes
+                .get(offset + 8..offset + 8 + len)
+                .ok_or(NsfError::Truncated)?;
+            offset += 8 + len;
+
+            match id {
+                b"INFO" if chunk.len() >= 9 => info = Some(chunk),
+                b"INFO" => return Err(NsfError::Truncated),
+                b"DATA" => data = Some(chunk),
+                b"BANK" => {
+                    for (bank, value) in banks.iter_mut().zip(chunk) {
+                        *bank = *value;
+                    }
+                }
+                b"RATE" if chunk.len() >= 4 => {
+                    speeds = (
+                        u16::from_le_bytes([chunk[0], chunk[1]]),
+                        u16::from_le_bytes([chunk[2], chunk[3]]),
+                    );
+                }
+                b"auth" => strings = strings_of(chunk),
+                b"tlbl" => names = strings_of(chunk),
+                b"time" => lengths = times_of(chunk),
+                b"fade" => fades = times_of(chunk),
+                b"NEND" => break,
+                [b'A'..=b'Z', ..] => {
+                    return Err(NsfError::UnsupportedChunk(
+                        String::from_utf8_lossy(id).into_owned(),
+                    ));
+                }
+                // Playlists, plst/psfx, and other optional extras
+                _ => {}
+            }
+        }
+
+        let info = info.ok_or(NsfError::MissingChunk("INFO"))?;
+        let data = data.ok_or(NsfError::MissingChunk("DATA"))?;
+        let word = |offset: usize| u16::from_le_bytes([info[offset], info[offset + 1]]);
+        let songs = info.get(8).copied().unwrap_or(1);
+
+        let tracks = (0..songs as usize)
+            .map(|track| Track {
+                name: names.get(track).cloned(),
+                length: lengths.get(track).copied().flatten(),
+                fade: fades.get(track).copied().flatten(),
+            })
+            .collect();
+        let mut strings = strings.into_iter();
+        let mut string = || strings.next().unwrap_or_default();
+
+        let nsf = Nsf {
+            title: string(),
+            artist: string(),
+            copyright: string(),
+            ripper: string(),
+            start_track: info.get(9).copied().unwrap_or(0),
+            load_addr: word(0),
+            init_addr: word(2),
+            play_addr: word(4),
+            ntsc_speed: speeds.0,
+            pal_speed: speeds.1,
+            banks,
+            region: region(info[6]),
+            chips: info[7],
+            data: data.to_vec(),
+            tracks,
+        };
+        nsf.check()?;
+
+        Ok(nsf)
+    }
+
+    fn check(&self) -> Result<(), NsfError> {
+        if self.load_addr < 0x8000 {
+            return Err(NsfError::LoadAddress(self.load_addr));
+        }
+        // The mapper would have no ROM to read from
+        if self.data.is_empty() {
+            return Err(NsfError::NoData);
+        }
+        Ok(())
+    }
+
+    pub fn songs(&self) -> u8 {
+        self.tracks.len() as u8
+    }
+
+    pub fn bankswitched(&self) -> bool {
+        self.banks.iter().any(|bank| *bank != 0)
+    }
+
+    pub fn chip_names(&self) -> Vec<&'static str> {
+        CHIP_NAMES
+            .iter()
+            .filter(|(chip, _)| self.chips & chip != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    // Flagged chips the player has no emulation for, their channels stay
+    // silent
+    pub fn unsupported_chips(&self) -> Vec<&'static str> {
+        CHIP_NAMES
+            .iter()
+            .filter(|(chip, _)| self.chips & chip & !SUPPORTED_CHIPS != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    // Microseconds between PLAY calls on a console of the given region
+    pub fn speed(&self, region: Region) -> u16 {
+        match region {
+            Region::Ntsc => self.ntsc_speed,
+            Region::Pal | Region::Dendy => self.pal_speed,
+        }
+    }
+}
+
+// Bit 0 asks for PAL, bit 1 says the tune works on both, which plays it on
+// NTSC
+fn region(flags: u8) -> Region {
+    if flags & 0x03 == 0x01 {
+        Region::Pal
+    } else {
+        Region::Ntsc
+    }
+}
+
+// A zero padded field of the NSF header
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// Zero terminated strings one after the other
+fn strings_of(chunk: &[u8]) -> Vec<String> {
+    let chunk = chunk.strip_suffix(&[0]).unwrap_or(chunk);
+    chunk.split(|c| *c == 0).map(text).collect()
+}
+
+// Signed 32 bit millisecond counts, negative for unknown
+fn times_of(chunk: &[u8]) -> Vec<Option<u32>> {
+    chunk
+        .chunks_exact(4)
+        .map(|time| {
+            let time = i32::from_le_bytes(time.try_into().unwrap());
+            u32::try_from(time).ok()
+        })
+        .collect()
+}
+
+// Longest INIT or PLAY may run before the tune is given up on, in seconds
+// of CPU time. Some drivers unpack their data in INIT, but none take
+// anywhere near this long.
+const CALL_LIMIT_SECONDS: f64 = 10.0;
+
+// Drives an NSF loaded into the CPU through an NsfMapper the way the
+// hardware players do: INIT once per track, then PLAY at the tune's rate
+// with the CPU idling in between
+pub struct NsfPlayer {
+    nsf: Nsf,
+    track: u8,
+    // CPU cycles between PLAY calls, and the cycle the next one is due on
+    play_period: f64,
+    next_play: f64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Self {
+        Self {
+            track: nsf.start_track,
+            nsf,
+            play_period: 0.0,
+            next_play: 0.0,
+        }
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    // Zero based
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    // Resets the console's memory, bank registers and APU to what a track
+    // starts from and runs INIT for it
+    pub fn start_track(&mut self, cpu: &mut CPU, track: u8) -> Result<(), String> {
+        if track >= self.nsf.songs() {
+            return Err(format!(
+                "track {} doesn't exist, there are {}",
+                track as u16 + 1,
+                self.nsf.songs()
+            ));
+        }
+        self.track = track;
+
+        for addr in (0x0000..0x0800).chain(0x6000..0x8000) {
+            cpu.mem_write(addr, 0);
+        }
+        for addr in 0x4000..=0x4013 {
+            cpu.mem_write(addr, 0);
+        }
+        cpu.mem_write(0x4015, 0x00);
+        cpu.mem_write(0x4015, 0x0f);
+        // The frame IRQ would otherwise go off with nothing to handle it
+        cpu.mem_write(0x4017, 0x40);
+        if self.nsf.bankswitched() {
+            for (n, bank) in self.nsf.banks.iter().enumerate() {
+                cpu.mem_write(0x5ff8 + n as u16, *bank);
+            }
+        }
+
+        cpu.acc_reg = track;
+        cpu.reg_x = (cpu.region != Region::Ntsc) as u8;
+        cpu.reg_y = 0;
+        cpu.stack_ptr = 0xfd;
+        // Interrupts masked, the tune runs with nothing but INIT and PLAY
+        cpu.status = 0b0000_0100;
+        cpu.call(self.nsf.init_addr, self.call_limit(cpu))?;
+
+        let speed = match self.nsf.speed(cpu.region) {
+            // Treated as once per frame
+            0 => {
+                let (cycles, frames) = cpu.region.cpu_cycles_per_frame();
+                cycles as f64 / frames as f64
+            }
+            speed => speed as f64 * cpu.region.cpu_clock_hz() / 1_000_000.0,
+        };
+        self.play_period = speed;
+        self.next_play = cpu.cycles as f64;
+
+        Ok(())
+    }
+
+    // Runs a video frame's worth of cycles, calling PLAY whenever it is due
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        let (cycles, frames) = cpu.region.cpu_cycles_per_frame();
+        cpu.frame_count += 1;
+        let frame_end = cpu.frame_count * cycles as u64 / frames as u64;
+
+        while cpu.cycles < frame_end {
+            if cpu.cycles as f64 >= self.next_play {
+                cpu.call(self.nsf.play_addr, self.call_limit(cpu))?;
+                // A PLAY that overruns its slot delays the next one rather
+                // than having them pile up
+                self.next_play += self.play_period;
+                if self.next_play < cpu.cycles as f64 {
+                    self.next_play = cpu.cycles as f64;
+                }
+            } else {
+                let until = (self.next_play.ceil() as u64).min(frame_end);
+                cpu.idle(until.max(cpu.cycles + 1) - cpu.cycles);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn call_limit(&self, cpu: &CPU) -> u64 {
+        (cpu.region.cpu_clock_hz() * CALL_LIMIT_SECONDS) as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::NsfMapper;
+
+    // An NSF header in front of the given code, loaded at $8000 with INIT
+    // at $8000 and PLAY at $8003
+    fn nsf_file(code: &[u8], songs: u8, banks: [u8; 8]) -> Vec<u8> {
+        let mut file = vec![0; NSF_HEADER_SIZE];
+        file[..5].copy_from_slice(NSF_MAGIC);
+        file[0x05] = 1;
+        file[0x06] = songs;
+        file[0x07] = 1;
+        file[0x08..0x0e].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+        file[0x0e..0x13].copy_from_slice(b"Title");
+        file[0x2e..0x34].copy_from_slice(b"Artist");
+        file[0x4e..0x52].copy_from_slice(b"1986");
+        file[0x6e..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        file[0x70..0x78].copy_from_slice(&banks);
+        file[0x78..0x7a].copy_from_slice(&19997u16.to_le_bytes());
+        file.extend_from_slice(code);
+        file
+    }
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn player(code: &[u8]) -> (NsfPlayer, CPU) {
+        let nsf = Nsf::parse(&nsf_file(code, 3, [0; 8])).unwrap();
+        let mut cpu = CPU::new();
+        cpu.insert_cartridge(Box::new(NsfMapper::new(&nsf)));
+        (NsfPlayer::new(nsf), cpu)
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let mut file = nsf_file(&[0x60], 12, [0, 1, 2, 3, 4, 5, 6, 7]);
+        file[0x07] = 3;
+        file[0x7a] = 0x01;
+        file[0x7b] = VRC6 | FDS;
+        let nsf = Nsf::parse(&file).unwrap();
+
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.copyright, "1986");
+        assert_eq!(nsf.songs(), 12);
+        assert_eq!(nsf.start_track, 2);
+        assert_eq!(nsf.play_addr, 0x8003);
+        assert_eq!(nsf.region, Region::Pal);
+        assert_eq!(nsf.speed(Region::Pal), 19997);
+        assert!(nsf.bankswitched());
+        assert_eq!(nsf.chip_names(), ["VRC6", "FDS"]);
+        assert_eq!(nsf.unsupported_chips(), ["FDS"]);
+        assert_eq!(nsf.data, [0x60]);
+
+        assert_eq!(Nsf::parse(&file[..0x40]).unwrap_err(), NsfError::Truncated);
+        assert_eq!(Nsf::parse(b"NES\x1a").unwrap_err(), NsfError::UnknownFormat);
+        file[0x09] = 0x60;
+        assert_eq!(
+            Nsf::parse(&file).unwrap_err(),
+            NsfError::LoadAddress(0x6000)
+        );
+        file[0x09] = 0x80;
+        assert_eq!(
+            Nsf::parse(&file[..NSF_HEADER_SIZE]).unwrap_err(),
+            NsfError::NoData
+        );
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        let mut file = NSFE_MAGIC.to_vec();
+        file.extend(chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x02, VRC7, 3, 1],
+        ));
+        file.extend(chunk(b"DATA", &[0x60, 0x00, 0x00, 0x60]));
+        file.extend(chunk(b"RATE", &[0x0a, 0x41, 0x1d, 0x4e]));
+        file.extend(chunk(b"auth", b"Game\0Composer\0\0Ripper\0"));
+        file.extend(chunk(b"tlbl", b"Intro\0Stage 1\0"));
+        file.extend(chunk(b"time", &[0x10, 0x27, 0, 0, 0xff, 0xff, 0xff, 0xff]));
+        file.extend(chunk(b"plst", &[1, 0]));
+        file.extend(chunk(b"NEND", &[]));
+        let nsf = Nsf::parse(&file).unwrap();
+
+        assert_eq!(nsf.title, "Game");
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.copyright, "");
+        assert_eq!(nsf.ripper, "Ripper");
+        assert_eq!(nsf.songs(), 3);
+        assert_eq!(nsf.start_track, 1);
+        assert_eq!(nsf.ntsc_speed, 0x410a);
+        assert_eq!(nsf.region, Region::Ntsc);
+        assert_eq!(nsf.chips, VRC7);
+        assert_eq!(nsf.data.len(), 4);
+        assert_eq!(
+            nsf.tracks[0],
+            Track {
+                name: Some("Intro".to_string()),
+                length: Some(10_000),
+                fade: None,
+            }
+        );
+        assert_eq!(nsf.tracks[1].name.as_deref(), Some("Stage 1"));
+        assert_eq!(nsf.tracks[1].length, None);
+        assert_eq!(nsf.tracks[2], Track::default());
+
+        let mut unknown = NSFE_MAGIC.to_vec();
+        unknown.extend(chunk(b"INFO", &[0; 9]));
+        unknown.extend(chunk(b"DATA", &[0x60]));
+        unknown.extend(chunk(b"NEW!", &[]));
+        assert_eq!(
+            Nsf::parse(&unknown).unwrap_err(),
+            NsfError::UnsupportedChunk("NEW!".to_string())
+        );
+        let mut missing = NSFE_MAGIC.to_vec();
+        missing.extend(chunk(b"INFO", &[0; 9]));
+        missing.extend(chunk(b"NEND", &[]));
+        assert_eq!(
+            Nsf::parse(&missing).unwrap_err(),
+            NsfError::MissingChunk("DATA")
+        );
+        let mut empty = NSFE_MAGIC.to_vec();
+        empty.extend(chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0, 0, 1],
+        ));
+        empty.extend(chunk(b"DATA", &[]));
+        empty.extend(chunk(b"NEND", &[]));
+        assert_eq!(Nsf::parse(&empty).unwrap_err(), NsfError::NoData);
+    }
+
+    #[test]
+    fn test_init_gets_track_and_region() {
+        // INIT: ADC #$10, RTS. PLAY: RTS.
+        let (mut player, mut cpu) = player(&[0x69, 0x10, 0x60, 0x60]);
+        cpu.mem_write(0x0200, 0x55);
+
+        player.start_track(&mut cpu, 2).unwrap();
+        assert_eq!(cpu.acc_reg, 0x12);
+        assert_eq!(cpu.reg_x, 0);
+        assert_eq!(cpu.mem_read(0x0200), 0);
+        assert_eq!(player.track(), 2);
+
+        assert!(player.start_track(&mut cpu, 3).is_err());
+    }
+
+    #[test]
+    fn test_play_runs_at_the_tune_rate() {
+        // INIT: RTS. PLAY: ADC #$01, RTS.
+        let (mut player, mut cpu) = player(&[0x60, 0x00, 0x00, 0x69, 0x01, 0x60]);
+        player.start_track(&mut cpu, 0).unwrap();
+
+        // 16639 us is a little faster than the NTSC frame rate, so 60
+        // frames hold 60 or 61 calls
+        for _ in 0..60 {
+            player.run_frame(&mut cpu).unwrap();
+        }
+        assert!((60..=61).contains(&cpu.acc_reg));
+    }
+
+    #[test]
+    fn test_unsupported_opcode_fails_the_call() {
+        let (mut player, mut cpu) = player(&[0xea, 0x60]);
+        let err = player.start_track(&mut cpu, 0).unwrap_err();
+        assert!(err.contains("$8000"), "{}", err);
+    }
+}
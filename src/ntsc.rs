@@ -0,0 +1,368 @@
+use std::f32::consts::PI;
+
+use crate::frame::{Frame, HEIGHT, WIDTH};
+
+// Software NTSC filter. It rebuilds the composite signal the PPU would put on
+// the wire out of palette indices and emphasis bits, then decodes it back
+// into RGB the way a TV would, which gives the colour fringing and dot crawl
+// of the real console.
+// The signal model follows https://www.nesdev.org/wiki/NTSC_video
+
+// Each PPU dot lasts 8 samples of the 12 sample long colour subcarrier cycle
+const SAMPLES_PER_PIXEL: usize = 8;
+const SUBCARRIER_SAMPLES: usize = 12;
+
+// A scanline is 341 dots, so every line starts 341 * 8 % 12 = 4 samples
+// further into the subcarrier than the previous one
+const LINE_PHASE_STEP: usize = 4;
+
+// The decoder locks onto the colour burst, which the PPU outputs as colour 8.
+// Measured in subcarrier samples, this puts the I/Q reference 3.9 samples
+// ahead of the phase the signal table is indexed with
+const BURST_PHASE: f32 = 3.9;
+
+// Demodulating against a full cycle only recovers half of the chroma
+// amplitude, a TV makes up for it in its gain
+const CHROMA_GAIN: f32 = 2.0;
+
+// Voltage levels relative to sync for the luma levels 0-3, while the square
+// wave is low and while it is high
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const ATTENUATION: f32 = 0.746;
+
+const GAMMA: f32 = 2.0;
+
+pub struct NtscSettings {
+    // Rotation of the decoded colours in degrees
+    pub hue: f32,
+    // 1.0 keeps the colours as decoded, 0.0 gives a black and white picture
+    pub saturation: f32,
+    // Positive values sharpen the luma, negative values blur it (-1.0..=1.0)
+    pub sharpness: f32,
+    // How much of the chroma/luma crosstalk to keep, 0.0 removes it
+    // completely and 1.0 keeps all of it (0.0..=1.0)
+    pub artifacting: f32,
+}
+
+impl NtscSettings {
+    pub fn new() -> Self {
+        Self {
+            hue: 0.0,
+            saturation: 1.0,
+            sharpness: 0.0,
+            artifacting: 1.0,
+        }
+    }
+}
+
+pub struct NtscFilter {
+    pub settings: NtscSettings,
+    // Normalized signal level of every pixel value at every subcarrier phase
+    signal_table: Vec<[f32; SUBCARRIER_SAMPLES]>,
+    frame_phase: usize,
+    odd_frame: bool,
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscSettings) -> Self {
+        let mut signal_table = vec![[0.0; SUBCARRIER_SAMPLES]; 512];
+
+        for (pixel, levels) in signal_table.iter_mut().enumerate() {
+            for (phase, level) in levels.iter_mut().enumerate() {
+                *level = (signal(pixel as u16, phase) - BLACK) / (WHITE - BLACK);
+            }
+        }
+
+        Self {
+            settings,
+            signal_table,
+            frame_phase: 0,
+            odd_frame: false,
+        }
+    }
+
+    // Filters a frame into packed 24 bit RGB of the same dimensions
+    pub fn apply(&mut self, frame: &Frame) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(WIDTH * HEIGHT * 3);
+        let mut yiq = vec![[0.0; 3]; WIDTH];
+        let mut samples = vec![0.0; WIDTH * SAMPLES_PER_PIXEL];
+
+        for y in 0..HEIGHT {
+            let line = &frame.pixels[y * WIDTH..(y + 1) * WIDTH];
+            let phase = (self.frame_phase + y * LINE_PHASE_STEP) % SUBCARRIER_SAMPLES;
+
+            self.decode_line(line, phase, &mut samples, &mut yiq);
+            for color in yiq.iter() {
+                rgb.extend_from_slice(&yiq_to_rgb(*color));
+            }
+        }
+
+        // A frame is 262 * 341 dots, which moves the phase by 4 samples, but
+        // every other frame is one dot shorter and moves it by 8 instead
+        self.frame_phase += if self.odd_frame { 8 } else { 4 };
+        self.frame_phase %= SUBCARRIER_SAMPLES;
+        self.odd_frame = !self.odd_frame;
+
+        rgb
+    }
+
+    // Builds the 512 entry palette the filter would decode for each pixel
+    // value if it were drawn over a flat area
+    pub fn palette(&self) -> [[u8; 3]; 512] {
+        let mut palette = [[0; 3]; 512];
+        let mut samples = vec![0.0; 4 * SAMPLES_PER_PIXEL];
+        let mut yiq = vec![[0.0; 3]; 4];
+
+        for (pixel, color) in palette.iter_mut().enumerate() {
+            self.decode_line(&[pixel as u16; 4], 0, &mut samples, &mut yiq);
+            *color = yiq_to_rgb(yiq[2]);
+        }
+
+        palette
+    }
+
+    fn decode_line(&self, line: &[u16], phase: usize, samples: &mut [f32], yiq: &mut [[f32; 3]]) {
+        let artifacting = self.settings.artifacting.clamp(0.0, 1.0);
+
+        if artifacting >= 1.0 {
+            self.decode_line_at(line, phase, samples, yiq);
+            return;
+        }
+
+        // The three possible line phases average out the crosstalk, so
+        // blending towards their mean removes the artifacts
+        let mut merged = vec![[0.0; 3]; line.len()];
+        for step in 0..3 {
+            let shifted = (phase + step * LINE_PHASE_STEP) % SUBCARRIER_SAMPLES;
+            self.decode_line_at(line, shifted, samples, yiq);
+
+            for (sum, color) in merged.iter_mut().zip(yiq.iter()) {
+                for c in 0..3 {
+                    sum[c] += color[c] / 3.0;
+                }
+            }
+        }
+
+        self.decode_line_at(line, phase, samples, yiq);
+        for (color, mean) in yiq.iter_mut().zip(merged.iter()) {
+            for c in 0..3 {
+                color[c] = color[c] * artifacting + mean[c] * (1.0 - artifacting);
+            }
+        }
+    }
+
+    fn decode_line_at(
+        &self,
+        line: &[u16],
+        phase: usize,
+        samples: &mut [f32],
+        yiq: &mut [[f32; 3]],
+    ) {
+        for (x, pixel) in line.iter().enumerate() {
+            let levels = &self.signal_table[(*pixel & 0x1ff) as usize];
+            for p in 0..SAMPLES_PER_PIXEL {
+                let sample = x * SAMPLES_PER_PIXEL + p;
+                samples[sample] = levels[(phase + sample) % SUBCARRIER_SAMPLES];
+            }
+        }
+
+        let hue = self.settings.hue * PI / 180.0;
+        let mut cos = [0.0; SUBCARRIER_SAMPLES];
+        let mut sin = [0.0; SUBCARRIER_SAMPLES];
+        for k in 0..SUBCARRIER_SAMPLES {
+            let angle = PI * (k as f32 + BURST_PHASE) / 6.0 + hue;
+            cos[k] = angle.cos();
+            sin[k] = angle.sin();
+        }
+
+        let len = line.len() * SAMPLES_PER_PIXEL;
+        let sample_at = |p: isize| {
+            if p < 0 || p as usize >= len {
+                0.0
+            } else {
+                samples[p as usize]
+            }
+        };
+
+        for (x, color) in yiq.iter_mut().enumerate().take(line.len()) {
+            let center = (x * SAMPLES_PER_PIXEL + SAMPLES_PER_PIXEL / 2) as isize;
+
+            // One whole subcarrier cycle around the pixel gives the luma and
+            // both chroma components
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for p in center - 6..center + 6 {
+                let level = sample_at(p) / SUBCARRIER_SAMPLES as f32;
+                let k = (phase as isize + p).rem_euclid(SUBCARRIER_SAMPLES as isize) as usize;
+
+                y += level;
+                i += level * CHROMA_GAIN * cos[k];
+                q += level * CHROMA_GAIN * sin[k];
+            }
+
+            // Sharpness pushes the luma away from (or towards) the average
+            // of two subcarrier cycles
+            let mut wide = 0.0;
+            for p in center - 12..center + 12 {
+                wide += sample_at(p) / (2 * SUBCARRIER_SAMPLES) as f32;
+            }
+            y += self.settings.sharpness * (y - wide);
+
+            *color = [
+                y,
+                i * self.settings.saturation,
+                q * self.settings.saturation,
+            ];
+        }
+    }
+}
+
+// Signal level of a pixel value at the given subcarrier phase
+fn signal(pixel: u16, phase: usize) -> f32 {
+    let color = (pixel & 0x0f) as usize;
+    let mut level = ((pixel >> 4) & 0x03) as usize;
+    let emphasis = pixel >> 6;
+
+    // Colours $xE and $xF are always output at level 1
+    if color > 13 {
+        level = 1;
+    }
+
+    let mut low = SIGNAL_LOW[level];
+    let mut high = SIGNAL_HIGH[level];
+    // Colour 0 is only the high level and 13-15 only the low level
+    if color == 0 {
+        low = high;
+    }
+    if color > 12 {
+        high = low;
+    }
+
+    let in_color_phase = |color: usize| (color + phase) % SUBCARRIER_SAMPLES < 6;
+    let mut value = if in_color_phase(color) { high } else { low };
+
+    // Emphasis attenuates the parts of the wave in phase with red, green
+    // and blue
+    if (emphasis & 0b001 != 0 && in_color_phase(0))
+        || (emphasis & 0b010 != 0 && in_color_phase(4))
+        || (emphasis & 0b100 != 0 && in_color_phase(8))
+    {
+        value *= ATTENUATION;
+    }
+
+    value
+}
+
+fn yiq_to_rgb(yiq: [f32; 3]) -> [u8; 3] {
+    let [y, i, q] = yiq;
+
+    // FCC sanctioned conversion matrix
+    let r = y + 0.946882 * i + 0.623557 * q;
+    let g = y - 0.274788 * i - 0.635691 * q;
+    let b = y - 1.108545 * i + 1.709007 * q;
+
+    [gamma_fix(r), gamma_fix(g), gamma_fix(b)]
+}
+
+fn gamma_fix(value: f32) -> u8 {
+    if value <= 0.0 {
+        0
+    } else {
+        (value.powf(2.2 / GAMMA) * 255.95).min(255.0) as u8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn brightness(color: [u8; 3]) -> u32 {
+        color.iter().map(|c| *c as u32).sum()
+    }
+
+    #[test]
+    fn test_black_frame_decodes_to_black() {
+        let mut frame = Frame::new();
+        frame.pixels.iter_mut().for_each(|p| *p = 0x0f);
+
+        let mut filter = NtscFilter::new(NtscSettings::new());
+        let rgb = filter.apply(&frame);
+
+        assert_eq!(rgb.len(), WIDTH * HEIGHT * 3);
+        assert!(rgb.iter().all(|c| *c == 0));
+    }
+
+    #[test]
+    fn test_palette_grey_ramp() {
+        let palette = NtscFilter::new(NtscSettings::new()).palette();
+
+        assert!(brightness(palette[0x0f]) < brightness(palette[0x00]));
+        assert!(brightness(palette[0x00]) < brightness(palette[0x10]));
+        assert!(brightness(palette[0x10]) < brightness(palette[0x20]));
+    }
+
+    #[test]
+    fn test_no_saturation_is_grey() {
+        let mut settings = NtscSettings::new();
+        settings.saturation = 0.0;
+        let palette = NtscFilter::new(settings).palette();
+
+        let [r, g, b] = palette[0x16];
+        assert_eq!(r, g);
+        assert_eq!(g, b);
+    }
+
+    // Index of the channel that clearly dominates the colour, if any
+    fn dominant(color: [u8; 3]) -> Option<usize> {
+        let channels = color.map(|c| c as u32);
+        (0..3).find(|&n| (0..3).all(|m| m == n || channels[n] > 2 * channels[m]))
+    }
+
+    #[test]
+    fn test_palette_hues() {
+        let palette = NtscFilter::new(NtscSettings::new()).palette();
+
+        assert_eq!(dominant(palette[0x16]), Some(0));
+        assert_eq!(dominant(palette[0x1a]), Some(1));
+        assert_eq!(dominant(palette[0x12]), Some(2));
+    }
+
+    #[test]
+    fn test_hue_rotates_colours() {
+        let normal = NtscFilter::new(NtscSettings::new()).palette();
+
+        let mut settings = NtscSettings::new();
+        settings.hue = 90.0;
+        let rotated = NtscFilter::new(settings).palette();
+
+        assert_ne!(normal[0x16], rotated[0x16]);
+        assert_eq!(normal[0x20], rotated[0x20]);
+    }
+
+    #[test]
+    fn test_emphasis_darkens() {
+        let palette = NtscFilter::new(NtscSettings::new()).palette();
+
+        assert!(brightness(palette[0x20 | (0b111 << 6)]) < brightness(palette[0x20]));
+    }
+
+    #[test]
+    fn test_no_artifacting_is_stable_between_frames() {
+        let mut frame = Frame::new();
+        for (n, pixel) in frame.pixels.iter_mut().enumerate() {
+            *pixel = if n % 2 == 0 { 0x30 } else { 0x0f };
+        }
+
+        let mut settings = NtscSettings::new();
+        settings.artifacting = 0.0;
+        let mut filter = NtscFilter::new(settings);
+
+        let first = filter.apply(&frame);
+        let second = filter.apply(&frame);
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert!((*a as i32 - *b as i32).abs() <= 1);
+        }
+    }
+}
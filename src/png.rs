@@ -0,0 +1,127 @@
+use std::{fs, io, path::Path};
+
+use crate::crc32::Crc32;
+
+// Minimal PNG encoder for 24 bit RGB images. The image data goes into
+// uncompressed deflate blocks, which keeps the encoder tiny at the cost of
+// bigger files.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+// Largest amount of data a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+pub fn encode(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    assert_eq!(rgb.len(), (width * height * 3) as usize);
+
+    let mut png = SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, truecolour, deflate, adaptive filtering, no
+    // interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Every scanline starts with its filter type, 0 meaning unfiltered
+    let stride = (width * 3) as usize;
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in rgb.chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+
+    write_chunk(&mut png, b"IEND", &[]);
+
+    png
+}
+
+pub fn write(path: &Path, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    fs::write(path, encode(width, height, rgb))
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+
+    // The checksum covers the chunk type and the data but not the length
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend_from_slice(&crc.finish().to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window and no preset dictionary
+    let mut zlib = vec![0x78, 0x01];
+
+    let blocks: Vec<&[u8]> = data.chunks(MAX_STORED_BLOCK).collect();
+    if blocks.is_empty() {
+        zlib.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+
+    for (n, block) in blocks.iter().enumerate() {
+        let last = n == blocks.len() - 1;
+        let len = block.len() as u16;
+
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+
+    zlib.extend_from_slice(&adler32(data).to_be_bytes());
+    zlib
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crc32::crc32;
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_png_layout() {
+        let rgb = vec![0x80; 4 * 2 * 3];
+        let png = encode(4, 2, &rgb);
+
+        assert_eq!(&png[0..8], &SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..20], &4u32.to_be_bytes());
+        assert_eq!(&png[20..24], &2u32.to_be_bytes());
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        let ihdr_crc = u32::from_be_bytes(png[29..33].try_into().unwrap());
+        assert_eq!(ihdr_crc, crc32(&png[12..29]));
+    }
+
+    #[test]
+    fn test_stored_blocks_split_large_images() {
+        let data = vec![7; MAX_STORED_BLOCK + 10];
+        let zlib = zlib_stored(&data);
+
+        // Header, two block headers, the data and the checksum
+        assert_eq!(zlib.len(), 2 + 5 + 5 + data.len() + 4);
+        assert_eq!(zlib[2], 0);
+        assert_eq!(zlib[2 + 5 + MAX_STORED_BLOCK], 1);
+    }
+}
@@ -0,0 +1,708 @@
+use crate::frame::Frame;
+use crate::mapper::Mapper;
+use crate::region::Region;
+
+// The 2C02 picture processor. It runs one dot per call to tick, 341 dots a
+// scanline, and makes the same memory accesses in the same order as the
+// real chip while rendering: for every tile a nametable byte, an attribute
+// byte and two pattern bytes, then two garbage nametable reads and the two
+// pattern bytes of each of the next line's 8 sprite slots, then the first
+// two tiles of the next line and two dummy nametable reads. Mappers that
+// count scanlines off the address bus (MMC3's A12, MMC5's nametable reads)
+// see the same thing they would on a console.
+//
+// Scanlines 0-239 are drawn, the post-render lines follow, vblank starts
+// where the region says and the pre-render line closes the frame.
+pub struct Ppu {
+    region: Region,
+    // The picture, drawn a pixel per dot while the beam is on the screen
+    pub frame: Frame,
+    pub scanline: u32,
+    pub dot: u32,
+    odd_frame: bool,
+
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    // The PPU's data bus, which reads of the write only registers return
+    io_latch: u8,
+    oam_addr: u8,
+    oam: [u8; 0x100],
+    palette: [u8; 0x20],
+    // The console's 2K of nametable RAM plus the 2K four screen boards add
+    vram: [u8; 0x1000],
+    read_buffer: u8,
+
+    // The scroll registers: the VRAM address (v), the one it gets reloaded
+    // from (t), fine X and the toggle shared by $2005 and $2006
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    write_toggle: bool,
+
+    // The background tile being fetched and the shift registers feeding
+    // the pixels
+    tile: u8,
+    attribute: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+    shift_lo: u16,
+    shift_hi: u16,
+    attribute_lo: u16,
+    attribute_hi: u16,
+
+    // OAM indices of the sprites found for the next line, and the sprites
+    // being drawn on this one
+    found: [u8; 8],
+    found_count: usize,
+    sprites: [Sprite; 8],
+    sprite_count: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Sprite {
+    x: u8,
+    attributes: u8,
+    // Already flipped horizontally when the sprite asks for it
+    pattern_lo: u8,
+    pattern_hi: u8,
+    zero: bool,
+}
+
+impl Ppu {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            frame: Frame::new(),
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            io_latch: 0,
+            oam_addr: 0,
+            oam: [0; 0x100],
+            palette: [0; 0x20],
+            vram: [0; 0x1000],
+            read_buffer: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_toggle: false,
+            tile: 0,
+            attribute: 0,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            shift_lo: 0,
+            shift_hi: 0,
+            attribute_lo: 0,
+            attribute_hi: 0,
+            found: [0; 8],
+            found_count: 0,
+            sprites: [Sprite::default(); 8],
+            sprite_count: 0,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    // State of the NMI output, low while vblank is flagged and $2000 asks
+    // for it
+    pub fn nmi(&self) -> bool {
+        self.status & 0x80 != 0 && self.ctrl & 0x80 != 0
+    }
+
+    fn pre_render_scanline(&self) -> u32 {
+        self.region.vblank_start_scanline() + self.region.vblank_scanlines()
+    }
+
+    fn rendering(&self) -> bool {
+        self.mask & 0x18 != 0
+    }
+
+    fn sprite_height(&self) -> u32 {
+        if self.ctrl & 0x20 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    // $2000-$2007, mirrored up to $3FFF
+    pub fn read_register(&mut self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        let data = match addr & 0x07 {
+            2 => {
+                let data = (self.status & 0xe0) | (self.io_latch & 0x1f);
+                self.status &= !0x80;
+                self.write_toggle = false;
+                data
+            }
+            4 => {
+                let data = self.oam[self.oam_addr as usize];
+                // The attribute byte has no bits 2-4
+                if self.oam_addr & 0x03 == 0x02 {
+                    data & 0xe3
+                } else {
+                    data
+                }
+            }
+            7 => {
+                let addr = self.v & 0x3fff;
+                let data = if addr >= 0x3f00 {
+                    // Palette reads skip the buffer, which gets the
+                    // nametable byte underneath instead
+                    self.read_buffer = self.read_bus(addr - 0x1000, mapper);
+                    self.palette[palette_index(addr)] | (self.io_latch & 0xc0)
+                } else {
+                    let data = self.read_buffer;
+                    self.read_buffer = self.read_bus(addr, mapper);
+                    data
+                };
+                self.increment_address();
+                data
+            }
+            _ => self.io_latch,
+        };
+
+        self.io_latch = data;
+        data
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+        self.io_latch = data;
+        mapper.ppu_register_write(addr, data);
+
+        match addr & 0x07 {
+            0 => {
+                self.ctrl = data;
+                self.t = (self.t & 0xf3ff) | ((data as u16 & 0x03) << 10);
+            }
+            1 => self.mask = data,
+            3 => self.oam_addr = data,
+            4 => {
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            5 if !self.write_toggle => {
+                self.t = (self.t & 0xffe0) | (data as u16 >> 3);
+                self.fine_x = data & 0x07;
+                self.write_toggle = true;
+            }
+            5 => {
+                self.t =
+                    (self.t & 0x8c1f) | ((data as u16 & 0x07) << 12) | ((data as u16 >> 3) << 5);
+                self.write_toggle = false;
+            }
+            6 if !self.write_toggle => {
+                self.t = (self.t & 0x00ff) | ((data as u16 & 0x3f) << 8);
+                self.write_toggle = true;
+            }
+            6 => {
+                self.t = (self.t & 0xff00) | data as u16;
+                self.v = self.t;
+                self.write_toggle = false;
+                mapper.ppu_address(self.v & 0x3fff);
+            }
+            7 => {
+                self.write_bus(self.v & 0x3fff, data, mapper);
+                self.increment_address();
+            }
+            _ => {}
+        }
+    }
+
+    fn increment_address(&mut self) {
+        let step = if self.ctrl & 0x04 != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7fff;
+    }
+
+    fn read_bus(&mut self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        match addr {
+            0x0000..=0x1fff => mapper.ppu_read(addr),
+            0x2000..=0x3eff => {
+                mapper.ppu_address(addr);
+                mapper.nametable_read(addr, &self.vram)
+            }
+            _ => self.palette[palette_index(addr)],
+        }
+    }
+
+    fn write_bus(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+        match addr {
+            0x0000..=0x1fff => mapper.ppu_write(addr, data),
+            0x2000..=0x3eff => {
+                mapper.ppu_address(addr);
+                mapper.nametable_write(addr, data, &mut self.vram);
+            }
+            _ => self.palette[palette_index(addr)] = data & 0x3f,
+        }
+    }
+
+    // Runs the PPU for one dot
+    pub fn tick(&mut self, mapper: &mut dyn Mapper) {
+        let pre_render = self.scanline == self.pre_render_scanline();
+        let visible = self.scanline < 240;
+
+        if self.dot == 1 {
+            if self.scanline == self.region.vblank_start_scanline() {
+                self.status |= 0x80;
+            }
+            if pre_render {
+                // Vblank, sprite 0 hit and sprite overflow
+                self.status &= !0xe0;
+            }
+        }
+
+        if self.rendering() && (visible || pre_render) {
+            self.render_dot(mapper);
+        } else if visible && (1..=256).contains(&self.dot) {
+            let x = self.dot as usize - 1;
+            self.frame
+                .set(x, self.scanline as usize, self.backdrop_pixel());
+        }
+
+        self.dot += 1;
+        // Odd frames skip the last dot of the pre-render line on NTSC
+        // consoles, as long as something is being drawn
+        if pre_render && self.dot == 340 && self.odd_frame && self.rendering() {
+            self.dot = 341;
+        }
+        if self.dot > 340 {
+            mapper.scanline();
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > self.pre_render_scanline() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame && self.region == Region::Ntsc;
+            }
+        }
+    }
+
+    // What a dot of a visible or pre-render line does while rendering is on
+    fn render_dot(&mut self, mapper: &mut dyn Mapper) {
+        let dot = self.dot;
+        // Dot 0 is idle
+        if dot == 0 {
+            return;
+        }
+
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.shift_background();
+        }
+        if (dot - 1).is_multiple_of(8) && ((9..=257).contains(&dot) || (329..=337).contains(&dot)) {
+            self.reload_background();
+        }
+        if self.scanline < 240 && (1..=256).contains(&dot) {
+            self.render_pixel();
+        }
+
+        if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+            match (dot - 1) % 8 {
+                0 => self.tile = self.fetch_nametable(mapper),
+                2 => self.fetch_attribute(mapper),
+                4 => {
+                    let addr = self.background_pattern_addr();
+                    self.pattern_lo = mapper.ppu_read(addr);
+                }
+                6 => {
+                    let addr = self.background_pattern_addr() + 8;
+                    self.pattern_hi = mapper.ppu_read(addr);
+                }
+                7 => self.increment_x(),
+                _ => {}
+            }
+        }
+
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.v = (self.v & !0x041f) | (self.t & 0x041f);
+                self.evaluate_sprites();
+            }
+            280..=304 if self.scanline == self.pre_render_scanline() => {
+                self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+            }
+            321 => self.sprite_count = self.found_count,
+            337 | 339 => {
+                self.fetch_nametable(mapper);
+            }
+            _ => {}
+        }
+
+        if (257..=320).contains(&dot) {
+            self.oam_addr = 0;
+            let slot = (dot - 257) as usize / 8;
+            match (dot - 257) % 8 {
+                0 | 2 => {
+                    self.fetch_nametable(mapper);
+                }
+                4 => self.sprites[slot].pattern_lo = self.fetch_sprite_pattern(slot, 0, mapper),
+                6 => self.sprites[slot].pattern_hi = self.fetch_sprite_pattern(slot, 8, mapper),
+                _ => {}
+            }
+        }
+    }
+
+    fn fetch_nametable(&mut self, mapper: &mut dyn Mapper) -> u8 {
+        let addr = 0x2000 | (self.v & 0x0fff);
+        mapper.ppu_address(addr);
+        mapper.nametable_read(addr, &self.vram)
+    }
+
+    fn fetch_attribute(&mut self, mapper: &mut dyn Mapper) {
+        let v = self.v;
+        let addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+        mapper.ppu_address(addr);
+        let attribute = mapper.nametable_read(addr, &self.vram);
+
+        let shift = ((v >> 4) & 0x04) | (v & 0x02);
+        self.attribute = (attribute >> shift) & 0x03;
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        let table = (self.ctrl as u16 & 0x10) << 8;
+        table + self.tile as u16 * 16 + ((self.v >> 12) & 0x07)
+    }
+
+    fn shift_background(&mut self) {
+        self.shift_lo <<= 1;
+        self.shift_hi <<= 1;
+        self.attribute_lo <<= 1;
+        self.attribute_hi <<= 1;
+    }
+
+    fn reload_background(&mut self) {
+        let fill = |bit: u8| if bit != 0 { 0xff } else { 0x00 };
+
+        self.shift_lo = (self.shift_lo & 0xff00) | self.pattern_lo as u16;
+        self.shift_hi = (self.shift_hi & 0xff00) | self.pattern_hi as u16;
+        self.attribute_lo = (self.attribute_lo & 0xff00) | fill(self.attribute & 0x01);
+        self.attribute_hi = (self.attribute_hi & 0xff00) | fill(self.attribute & 0x02);
+    }
+
+    fn increment_x(&mut self) {
+        if self.v & 0x001f == 31 {
+            self.v = (self.v & !0x001f) ^ 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+
+        self.v &= !0x7000;
+        let mut y = (self.v & 0x03e0) >> 5;
+        if y == 29 {
+            y = 0;
+            self.v ^= 0x0800;
+        } else if y == 31 {
+            // Rows 30 and 31 are attribute data, wrapping from there
+            // doesn't switch nametables
+            y = 0;
+        } else {
+            y += 1;
+        }
+        self.v = (self.v & !0x03e0) | (y << 5);
+    }
+
+    // Finds the first 8 sprites on the next line. Sprites are drawn a line
+    // below the Y in OAM, so that's the ones covering this line's number.
+    fn evaluate_sprites(&mut self) {
+        self.found_count = 0;
+        if self.scanline >= 240 {
+            return;
+        }
+
+        let height = self.sprite_height();
+        for index in 0..64 {
+            let y = self.oam[index * 4] as u32;
+            if self.scanline < y || self.scanline - y >= height {
+                continue;
+            }
+            if self.found_count == 8 {
+                self.status |= 0x20;
+                break;
+            }
+            self.found[self.found_count] = index as u8;
+            self.found_count += 1;
+        }
+    }
+
+    // One of the pattern bytes of a sprite slot. Empty slots still fetch
+    // tile $FF, which is what keeps A12 going on MMC3 boards.
+    fn fetch_sprite_pattern(&mut self, slot: usize, plane: u16, mapper: &mut dyn Mapper) -> u8 {
+        let height = self.sprite_height();
+        let (tile, row, attributes) = if slot < self.found_count {
+            let index = self.found[slot] as usize * 4;
+            let row = self.scanline - self.oam[index] as u32;
+            (self.oam[index + 1], row, self.oam[index + 2])
+        } else {
+            (0xff, 0, 0)
+        };
+        let row = if attributes & 0x80 != 0 {
+            height - 1 - row
+        } else {
+            row
+        } as u16;
+
+        let addr = if height == 16 {
+            let table = (tile as u16 & 0x01) << 12;
+            let tile = (tile & 0xfe) as u16 + row / 8;
+            table + tile * 16 + (row & 0x07)
+        } else {
+            let table = (self.ctrl as u16 & 0x08) << 9;
+            table + tile as u16 * 16 + row
+        };
+        let data = mapper.ppu_read(addr + plane);
+
+        if slot < self.found_count {
+            let index = self.found[slot] as usize * 4;
+            let sprite = &mut self.sprites[slot];
+            sprite.x = self.oam[index + 3];
+            sprite.attributes = attributes;
+            sprite.zero = index == 0;
+            if attributes & 0x40 != 0 {
+                return data.reverse_bits();
+            }
+        }
+        data
+    }
+
+    fn backdrop_pixel(&self) -> u16 {
+        // With rendering off a VRAM address pointing into the palette
+        // shows that colour instead
+        let index = if self.v & 0x3f00 == 0x3f00 {
+            palette_index(self.v)
+        } else {
+            0
+        };
+        self.color(index)
+    }
+
+    fn color(&self, index: usize) -> u16 {
+        let greyscale = if self.mask & 0x01 != 0 { 0x30 } else { 0x3f };
+        (self.palette[index] & greyscale) as u16 | (self.mask as u16 & 0xe0) << 1
+    }
+
+    fn render_pixel(&mut self) {
+        let x = self.dot as usize - 1;
+
+        let mut background = 0;
+        let mut background_palette = 0;
+        if self.mask & 0x08 != 0 && (x >= 8 || self.mask & 0x02 != 0) {
+            let bit = 15 - self.fine_x as u16;
+            background = ((self.shift_hi >> bit) & 0x01) << 1 | ((self.shift_lo >> bit) & 0x01);
+            background_palette =
+                ((self.attribute_hi >> bit) & 0x01) << 1 | ((self.attribute_lo >> bit) & 0x01);
+        }
+
+        let mut sprite = None;
+        if self.mask & 0x10 != 0 && (x >= 8 || self.mask & 0x04 != 0) {
+            sprite = self.sprites[..self.sprite_count].iter().find_map(|sprite| {
+                let column = x
+                    .checked_sub(sprite.x as usize)
+                    .filter(|column| *column < 8)?;
+                let bit = 7 - column;
+                let pixel =
+                    ((sprite.pattern_hi >> bit) & 0x01) << 1 | ((sprite.pattern_lo >> bit) & 0x01);
+                (pixel != 0).then_some((pixel, sprite))
+            });
+        }
+
+        if let Some((_, sprite)) = sprite {
+            if sprite.zero && background != 0 && x != 255 {
+                self.status |= 0x40;
+            }
+        }
+
+        let index = match sprite {
+            Some((pixel, sprite)) if background == 0 || sprite.attributes & 0x20 == 0 => {
+                0x10 + (sprite.attributes as usize & 0x03) * 4 + pixel as usize
+            }
+            _ if background != 0 => background_palette as usize * 4 + background as usize,
+            _ => 0,
+        };
+        let pixel = self.color(index);
+        self.frame.set(x, self.scanline as usize, pixel);
+    }
+}
+
+// Index into palette RAM, where the backdrop entries of the sprite
+// palettes mirror the background ones
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1f;
+    if index & 0x13 == 0x10 {
+        index & 0x0f
+    } else {
+        index
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    fn mapper() -> Box<dyn Mapper> {
+        Cartridge::from_program(&[]).create_mapper().unwrap()
+    }
+
+    fn write(ppu: &mut Ppu, mapper: &mut dyn Mapper, addr: u16, data: &[u8]) {
+        ppu.write_register(0x2006, (addr >> 8) as u8, mapper);
+        ppu.write_register(0x2006, addr as u8, mapper);
+        for byte in data {
+            ppu.write_register(0x2007, *byte, mapper);
+        }
+    }
+
+    fn run_frame(ppu: &mut Ppu, mapper: &mut dyn Mapper) {
+        loop {
+            ppu.tick(mapper);
+            if ppu.scanline == 0 && ppu.dot == 0 {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_vram_access_and_read_buffer() {
+        let mut mapper = mapper();
+        let mut ppu = Ppu::new(Region::Ntsc);
+        write(&mut ppu, mapper.as_mut(), 0x2005, &[0x11, 0x22]);
+        write(&mut ppu, mapper.as_mut(), 0x3f11, &[0x30]);
+
+        ppu.write_register(0x2006, 0x20, mapper.as_mut());
+        ppu.write_register(0x2006, 0x05, mapper.as_mut());
+        // The first read comes out of the buffer
+        ppu.read_register(0x2007, mapper.as_mut());
+        assert_eq!(ppu.read_register(0x2007, mapper.as_mut()), 0x11);
+        assert_eq!(ppu.read_register(0x2007, mapper.as_mut()), 0x22);
+
+        // Horizontal mirroring puts $2405 on top of $2005
+        ppu.write_register(0x2006, 0x24, mapper.as_mut());
+        ppu.write_register(0x2006, 0x05, mapper.as_mut());
+        ppu.read_register(0x2007, mapper.as_mut());
+        assert_eq!(ppu.read_register(0x2007, mapper.as_mut()), 0x11);
+
+        // $3F11 and its mirror at $3F31, read without the buffer
+        ppu.write_register(0x2006, 0x3f, mapper.as_mut());
+        ppu.write_register(0x2006, 0x31, mapper.as_mut());
+        assert_eq!(ppu.read_register(0x2007, mapper.as_mut()), 0x30);
+        // $3F10 is the backdrop
+        assert_eq!(palette_index(0x3f10), 0x00);
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut mapper = mapper();
+        let mut ppu = Ppu::new(Region::Ntsc);
+        ppu.write_register(0x2000, 0x80, mapper.as_mut());
+
+        while !ppu.nmi() {
+            ppu.tick(mapper.as_mut());
+        }
+        assert_eq!((ppu.scanline, ppu.dot), (241, 2));
+
+        // Reading $2002 acknowledges it
+        assert_eq!(ppu.read_register(0x2002, mapper.as_mut()) & 0x80, 0x80);
+        assert!(!ppu.nmi());
+        assert_eq!(ppu.read_register(0x2002, mapper.as_mut()) & 0x80, 0x00);
+    }
+
+    #[test]
+    fn test_frame_length() {
+        let mut mapper = mapper();
+
+        for (region, lines) in [
+            (Region::Ntsc, 262),
+            (Region::Pal, 312),
+            (Region::Dendy, 312),
+        ] {
+            let mut ppu = Ppu::new(region);
+            let mut dots = 0;
+            loop {
+                ppu.tick(mapper.as_mut());
+                dots += 1;
+                if ppu.scanline == 0 && ppu.dot == 0 {
+                    break;
+                }
+            }
+            assert_eq!(dots, lines * 341);
+        }
+
+        // With rendering on every other NTSC frame is a dot shorter
+        let mut ppu = Ppu::new(Region::Ntsc);
+        ppu.write_register(0x2001, 0x08, mapper.as_mut());
+        run_frame(&mut ppu, mapper.as_mut());
+        let mut dots = 0;
+        loop {
+            ppu.tick(mapper.as_mut());
+            dots += 1;
+            if ppu.scanline == 0 && ppu.dot == 0 {
+                break;
+            }
+        }
+        assert_eq!(dots, 262 * 341 - 1);
+    }
+
+    #[test]
+    fn test_draws_background_and_sprites() {
+        let mut mapper = mapper();
+        let mut ppu = Ppu::new(Region::Ntsc);
+        // Tile 1 is solid colour 1, tile 2 solid colour 3
+        write(&mut ppu, mapper.as_mut(), 0x0010, &[0xff; 8]);
+        write(&mut ppu, mapper.as_mut(), 0x0020, &[0xff; 16]);
+        // Tile 1 in the third column of the top row, palette 1 for the
+        // top right 16x16 area of the first attribute byte
+        write(&mut ppu, mapper.as_mut(), 0x2002, &[0x01]);
+        write(&mut ppu, mapper.as_mut(), 0x23c0, &[0x04]);
+        write(&mut ppu, mapper.as_mut(), 0x3f00, &[0x0f, 0, 0, 0, 0, 0x16]);
+        write(&mut ppu, mapper.as_mut(), 0x3f13, &[0x2a]);
+        // Sprite 0 on tile 2 at (20, 5), overlapping the background tile
+        for (n, byte) in [4, 2, 0, 20].iter().enumerate() {
+            ppu.write_register(0x2003, n as u8, mapper.as_mut());
+            ppu.write_register(0x2004, *byte, mapper.as_mut());
+        }
+        ppu.write_register(0x2006, 0, mapper.as_mut());
+        ppu.write_register(0x2006, 0, mapper.as_mut());
+        ppu.write_register(0x2001, 0x1e, mapper.as_mut());
+
+        // The first frame starts without the pre-render line's prefetch
+        run_frame(&mut ppu, mapper.as_mut());
+        while ppu.scanline < 20 {
+            ppu.tick(mapper.as_mut());
+        }
+        assert_eq!(ppu.read_register(0x2002, mapper.as_mut()) & 0x40, 0x40);
+        run_frame(&mut ppu, mapper.as_mut());
+
+        assert_eq!(ppu.frame.get(15, 0), 0x0f);
+        assert_eq!(ppu.frame.get(16, 0), 0x16);
+        assert_eq!(ppu.frame.get(23, 0), 0x16);
+        assert_eq!(ppu.frame.get(24, 0), 0x0f);
+        assert_eq!(ppu.frame.get(20, 4), 0x16);
+        assert_eq!(ppu.frame.get(20, 5), 0x2a);
+        assert_eq!(ppu.frame.get(27, 12), 0x2a);
+        assert_eq!(ppu.frame.get(28, 12), 0x0f);
+    }
+
+    #[test]
+    fn test_backdrop_and_emphasis_with_rendering_off() {
+        let mut mapper = mapper();
+        let mut ppu = Ppu::new(Region::Ntsc);
+        write(&mut ppu, mapper.as_mut(), 0x3f00, &[0x21]);
+        ppu.write_register(0x2006, 0, mapper.as_mut());
+        ppu.write_register(0x2006, 0, mapper.as_mut());
+        ppu.write_register(0x2001, 0x20, mapper.as_mut());
+
+        run_frame(&mut ppu, mapper.as_mut());
+
+        assert!(ppu.frame.pixels.iter().all(|pixel| *pixel == 0x21 | 0x040));
+    }
+}
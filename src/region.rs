@@ -0,0 +1,250 @@
+// Timing differences between the console revisions. The NTSC console is the
+// reference, the PAL console runs a slower CPU against a faster PPU and the
+// Dendy clones mix PAL frame timing with NTSC ratios.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// CPU cycles, counted from the $4017 write, at which the frame counter
+// steps. The 4 step sequence raises the IRQ over its last three entries.
+const NTSC_FOUR_STEP: [u32; 6] = [7457, 14913, 22371, 29828, 29829, 29830];
+const NTSC_FIVE_STEP: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FOUR_STEP: [u32; 6] = [8313, 16627, 24939, 33252, 33253, 33254];
+const PAL_FIVE_STEP: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+impl Region {
+    // Picks the region from a 16 byte iNES or NES 2.0 header
+    pub fn detect(header: &[u8]) -> Region {
+        let nes2 = (header[7] & 0x0c) == 0x08;
+
+        if nes2 {
+            match header[12] & 0x03 {
+                1 => Region::Pal,
+                3 => Region::Dendy,
+                // Multi region dumps run fine on the NTSC timing
+                _ => Region::Ntsc,
+            }
+        } else if header[9] & 0x01 != 0 {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        }
+    }
+
+    // A region forced by the user always wins over the one the file asks
+    // for, which comes from the header, a UNIF or NSF field or the game
+    // database's correction of them
+    pub fn select(detected: Region, user_override: Option<Region>) -> Region {
+        user_override.unwrap_or(detected)
+    }
+
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    pub fn master_clock_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 236_250_000.0 / 11.0,
+            Region::Pal | Region::Dendy => 26_601_712.5,
+        }
+    }
+
+    // Master clock cycles per CPU cycle
+    pub fn cpu_clock_divider(&self) -> u32 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    // Master clock cycles per PPU dot
+    pub fn ppu_clock_divider(&self) -> u32 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> f64 {
+        self.master_clock_hz() / self.cpu_clock_divider() as f64
+    }
+
+    // PPU dots per CPU cycle as a (numerator, denominator) pair, 3 on NTSC
+    // and Dendy but 3.2 on PAL
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (u32, u32) {
+        let cpu = self.cpu_clock_divider();
+        let ppu = self.ppu_clock_divider();
+
+        if cpu.is_multiple_of(ppu) {
+            (cpu / ppu, 1)
+        } else {
+            (cpu, ppu)
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u32 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Idle scanlines between the last visible line and the start of vblank.
+    // The Dendy keeps NTSC's short vblank and pads the frame here instead.
+    pub fn post_render_scanlines(&self) -> u32 {
+        match self {
+            Region::Ntsc | Region::Pal => 1,
+            Region::Dendy => 51,
+        }
+    }
+
+    pub fn vblank_scanlines(&self) -> u32 {
+        match self {
+            Region::Ntsc | Region::Dendy => 20,
+            Region::Pal => 70,
+        }
+    }
+
+    // Scanline on which the vblank flag gets set
+    pub fn vblank_start_scanline(&self) -> u32 {
+        240 + self.post_render_scanlines()
+    }
+
+    pub fn ppu_dots_per_frame(&self) -> u32 {
+        self.scanlines_per_frame() * 341
+    }
+
+    // CPU cycles per frame as a (numerator, denominator) pair
+    pub fn cpu_cycles_per_frame(&self) -> (u32, u32) {
+        let (dots, cycles) = self.ppu_dots_per_cpu_cycle();
+        (self.ppu_dots_per_frame() * cycles, dots)
+    }
+
+    // Frame rate as a (numerator, denominator) pair in Hz
+    pub fn frame_rate(&self) -> (u32, u32) {
+        match self {
+            Region::Ntsc => (236_250_000, 11 * 4 * self.ppu_dots_per_frame()),
+            Region::Pal | Region::Dendy => (53_203_425, 2 * 5 * self.ppu_dots_per_frame()),
+        }
+    }
+
+    // The Dendy's APU runs off the same logic as the NTSC one
+    pub fn frame_counter_four_step(&self) -> &'static [u32; 6] {
+        match self {
+            Region::Pal => &PAL_FOUR_STEP,
+            Region::Ntsc | Region::Dendy => &NTSC_FOUR_STEP,
+        }
+    }
+
+    pub fn frame_counter_five_step(&self) -> &'static [u32; 5] {
+        match self {
+            Region::Pal => &PAL_FIVE_STEP,
+            Region::Ntsc | Region::Dendy => &NTSC_FIVE_STEP,
+        }
+    }
+
+    // DMC output periods in CPU cycles
+    pub fn dmc_rates(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &PAL_DMC_RATES,
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_RATES,
+        }
+    }
+
+    // Noise timer periods in CPU cycles
+    pub fn noise_periods(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &PAL_NOISE_PERIODS,
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(flags_7: u8, flags_9: u8, flags_12: u8) -> [u8; 16] {
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(b"NES\x1a");
+        header[7] = flags_7;
+        header[9] = flags_9;
+        header[12] = flags_12;
+        header
+    }
+
+    #[test]
+    fn test_detect_ines() {
+        assert_eq!(Region::detect(&header(0, 0, 0)), Region::Ntsc);
+        assert_eq!(Region::detect(&header(0, 1, 0)), Region::Pal);
+    }
+
+    #[test]
+    fn test_detect_nes2() {
+        assert_eq!(Region::detect(&header(0x08, 0, 0)), Region::Ntsc);
+        assert_eq!(Region::detect(&header(0x08, 0, 1)), Region::Pal);
+        assert_eq!(Region::detect(&header(0x08, 0, 2)), Region::Ntsc);
+        assert_eq!(Region::detect(&header(0x08, 0, 3)), Region::Dendy);
+    }
+
+    #[test]
+    fn test_user_override_wins() {
+        let pal = header(0x08, 0, 1);
+
+        assert_eq!(Region::select(Region::detect(&pal), None), Region::Pal);
+        assert_eq!(
+            Region::select(Region::detect(&pal), Some(Region::Dendy)),
+            Region::Dendy
+        );
+    }
+
+    #[test]
+    fn test_ppu_cpu_ratio() {
+        assert_eq!(Region::Ntsc.ppu_dots_per_cpu_cycle(), (3, 1));
+        assert_eq!(Region::Pal.ppu_dots_per_cpu_cycle(), (16, 5));
+        assert_eq!(Region::Dendy.ppu_dots_per_cpu_cycle(), (3, 1));
+    }
+
+    #[test]
+    fn test_frame_length() {
+        assert_eq!(Region::Ntsc.cpu_cycles_per_frame(), (89342, 3));
+        assert_eq!(Region::Pal.cpu_cycles_per_frame(), (106392 * 5, 16));
+        assert_eq!(Region::Dendy.cpu_cycles_per_frame(), (106392, 3));
+
+        let (num, den) = Region::Ntsc.frame_rate();
+        assert!((num as f64 / den as f64 - 60.0988).abs() < 0.001);
+        let (num, den) = Region::Pal.frame_rate();
+        assert!((num as f64 / den as f64 - 50.0070).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_scanline_layout_adds_up() {
+        for region in [Region::Ntsc, Region::Pal, Region::Dendy] {
+            let lines = region.vblank_start_scanline() + region.vblank_scanlines() + 1;
+            assert_eq!(lines, region.scanlines_per_frame());
+        }
+    }
+}
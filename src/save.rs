@@ -0,0 +1,153 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::mapper::Mapper;
+
+// Battery backed cartridge memory, kept in a .sav file next to the ROM. The
+// file holds the raw memory contents and nothing else, which is the layout
+// other emulators read and write too.
+pub struct SaveFile {
+    path: PathBuf,
+    // What the file holds right now, so it only gets rewritten when the
+    // game changed something
+    saved: Vec<u8>,
+}
+
+impl SaveFile {
+    pub fn path_for(rom: &Path) -> PathBuf {
+        rom.with_extension("sav")
+    }
+
+    // Fills the mapper's save memory from the file, if there is one yet.
+    // A file of the wrong size still gets loaded as far as it goes.
+    pub fn open(path: PathBuf, mapper: &mut dyn Mapper) -> io::Result<SaveFile> {
+        let Some(ram) = mapper.save_ram_mut() else {
+            return Ok(SaveFile {
+                path,
+                saved: Vec::new(),
+            });
+        };
+
+        match fs::read(&path) {
+            Ok(data) => {
+                let len = data.len().min(ram.len());
+                ram[..len].copy_from_slice(&data[..len]);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok(SaveFile {
+            path,
+            saved: ram.to_vec(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Writes the save memory out if it changed since the last time.
+    // Returns whether the file was written.
+    pub fn flush(&mut self, mapper: &dyn Mapper) -> io::Result<bool> {
+        let ram = match mapper.save_ram() {
+            Some(ram) if !ram.is_empty() && ram != self.saved.as_slice() => ram,
+            _ => return Ok(false),
+        };
+
+        // Goes through a temporary file so that a crash halfway through
+        // can't leave a truncated save behind
+        let temp = self.path.with_extension("sav.tmp");
+        fs::write(&temp, ram)?;
+        fs::rename(&temp, &self.path)?;
+
+        self.saved = ram.to_vec();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    fn mapper() -> Box<dyn Mapper> {
+        let mut cartridge = Cartridge::from_program(&[]);
+        cartridge.battery = true;
+        cartridge.create_mapper().unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nemulator_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_path_next_to_rom() {
+        assert_eq!(
+            SaveFile::path_for(Path::new("games/zelda.nes")),
+            PathBuf::from("games/zelda.sav")
+        );
+    }
+
+    #[test]
+    fn test_flush_only_writes_changes() {
+        let dir = temp_dir("save_flush");
+        let path = dir.join("game.sav");
+        let mut mapper = mapper();
+
+        let mut save = SaveFile::open(path.clone(), mapper.as_mut()).unwrap();
+        assert!(!save.flush(mapper.as_ref()).unwrap());
+        assert!(!path.exists());
+
+        mapper.cpu_write(0x6010, 0x42);
+        assert!(save.flush(mapper.as_ref()).unwrap());
+        assert!(!save.flush(mapper.as_ref()).unwrap());
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(data[0x10], 0x42);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_loads_existing_save() {
+        let dir = temp_dir("save_load");
+        let path = dir.join("game.sav");
+        fs::write(&path, [1, 2, 3]).unwrap();
+
+        let mut mapper = mapper();
+        SaveFile::open(path, mapper.as_mut()).unwrap();
+
+        assert_eq!(mapper.cpu_read(0x6000), Some(1));
+        assert_eq!(mapper.cpu_read(0x6002), Some(3));
+        assert_eq!(mapper.cpu_read(0x6003), Some(0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_eeprom_is_saved() {
+        let dir = temp_dir("save_eeprom");
+        let path = dir.join("game.sav");
+        fs::write(&path, [0x12; 0x100]).unwrap();
+
+        // Bandai's boards save to a 24C02 instead of PRG-RAM
+        let mut cartridge = Cartridge::from_program(&[]);
+        cartridge.mapper = 16;
+        cartridge.battery = true;
+        let mut mapper = cartridge.create_mapper().unwrap();
+
+        let mut save = SaveFile::open(path.clone(), mapper.as_mut()).unwrap();
+        assert_eq!(mapper.save_ram().unwrap(), &[0x12; 0x100]);
+        mapper.save_ram_mut().unwrap()[0] = 0x34;
+        assert!(save.flush(mapper.as_ref()).unwrap());
+        assert_eq!(fs::read(&path).unwrap()[0], 0x34);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
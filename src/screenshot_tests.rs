@@ -0,0 +1,304 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::cpu::CPU;
+use crate::crc32::Crc32;
+use crate::frame::Frame;
+use crate::gamedb::GameDb;
+use crate::headless::Headless;
+
+// Screenshot regression tests. Each PPU test ROM runs headlessly for a fixed
+// number of frames and the hash of the last frame gets compared against the
+// recorded one in goldens/ppu_screenshots.txt, and setting NEMULATOR_BLESS
+// records new hashes instead of checking them.
+// The ROMs aren't part of the repository and the CPU doesn't run them yet, so
+// that test is ignored by default. Once run, a missing ROM, a missing golden
+// or a blank frame is a failure rather than a skip. Scenes set up through the
+// PPU registers, like a game's init code would, stand in for them in a plain
+// cargo test.
+
+const PPU_TEST_ROMS: [(&str, u64); 21] = [
+    ("blargg_ppu_tests_2005.09.15b/palette_ram.nes", 60),
+    ("blargg_ppu_tests_2005.09.15b/power_up_palette.nes", 60),
+    ("blargg_ppu_tests_2005.09.15b/sprite_ram.nes", 60),
+    ("blargg_ppu_tests_2005.09.15b/vbl_clear_time.nes", 60),
+    ("blargg_ppu_tests_2005.09.15b/vram_access.nes", 60),
+    ("sprite_hit_tests_2005.10.05/01.basics.nes", 120),
+    ("sprite_hit_tests_2005.10.05/02.alignment.nes", 120),
+    ("sprite_hit_tests_2005.10.05/03.corners.nes", 120),
+    ("sprite_hit_tests_2005.10.05/04.flip.nes", 120),
+    ("sprite_hit_tests_2005.10.05/05.left_clip.nes", 120),
+    ("sprite_hit_tests_2005.10.05/06.right_edge.nes", 120),
+    ("sprite_hit_tests_2005.10.05/07.screen_bottom.nes", 120),
+    ("sprite_hit_tests_2005.10.05/08.double_height.nes", 120),
+    ("sprite_hit_tests_2005.10.05/09.timing_basics.nes", 120),
+    ("sprite_hit_tests_2005.10.05/10.timing_order.nes", 120),
+    ("sprite_hit_tests_2005.10.05/11.edge_timing.nes", 120),
+    ("sprite_overflow_tests/1.Basics.nes", 120),
+    ("sprite_overflow_tests/2.Details.nes", 120),
+    ("sprite_overflow_tests/3.Timing.nes", 120),
+    ("sprite_overflow_tests/4.Obscure.nes", 120),
+    ("ppu_open_bus/ppu_open_bus.nes", 120),
+];
+
+struct Golden {
+    frames: u64,
+    hash: u32,
+}
+
+fn golden_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("goldens/ppu_screenshots.txt")
+}
+
+fn rom_dir() -> PathBuf {
+    match env::var("NEMULATOR_TEST_ROMS") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => Path::new(env!("CARGO_MANIFEST_DIR")).join("test_roms"),
+    }
+}
+
+fn parse_goldens(text: &str) -> BTreeMap<String, Golden> {
+    let mut goldens = BTreeMap::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [rom, frames, hash] = fields[..] {
+            if let (Ok(frames), Ok(hash)) = (frames.parse(), u32::from_str_radix(hash, 16)) {
+                goldens.insert(rom.to_string(), Golden { frames, hash });
+                continue;
+            }
+        }
+        panic!("malformed golden line: {}", line);
+    }
+
+    goldens
+}
+
+fn format_goldens(header: &str, goldens: &BTreeMap<String, Golden>) -> String {
+    let mut text = header.to_string();
+
+    for (rom, golden) in goldens.iter() {
+        text.push_str(&format!("{} {} {:08x}\n", rom, golden.frames, golden.hash));
+    }
+
+    text
+}
+
+// Hashes the palette indices and emphasis bits rather than RGB, so palette
+// and filter tweaks don't invalidate the goldens
+fn frame_hash(frame: &Frame) -> u32 {
+    let mut crc = Crc32::new();
+    for pixel in frame.pixels.iter() {
+        crc.update(&pixel.to_le_bytes());
+    }
+
+    crc.finish()
+}
+
+// Every test draws something, a frame of nothing but the backdrop means it
+// never got going and its hash says nothing
+fn drawn_frame_hash(frame: &Frame, frames: u64) -> Result<u32, String> {
+    if frame.pixels.iter().all(|pixel| *pixel == frame.pixels[0]) {
+        return Err(format!("the frame is blank after {} frames", frames));
+    }
+
+    Ok(frame_hash(frame))
+}
+
+fn screenshot_hash(rom: &Path, frames: u64) -> Result<u32, String> {
+    let mut console = Headless::boot(rom, None, Some(&GameDb::embedded()))?;
+    for _ in 0..frames {
+        console.run_frame()?;
+    }
+
+    drawn_frame_hash(&console.cpu.ppu.frame, frames)
+}
+
+// Lets the PPU draw until it's past the last visible line of another frame
+fn run_frames(cpu: &mut CPU, frames: u64) {
+    for _ in 0..frames {
+        while cpu.ppu.scanline != 240 {
+            cpu.idle(1);
+        }
+        while cpu.ppu.scanline == 240 {
+            cpu.idle(1);
+        }
+    }
+}
+
+fn write_vram(cpu: &mut CPU, addr: u16, data: &[u8]) {
+    cpu.mem_write(0x2006, (addr >> 8) as u8);
+    cpu.mem_write(0x2006, addr as u8);
+    for byte in data {
+        cpu.mem_write(0x2007, *byte);
+    }
+}
+
+// A scrolled background of three tiles under every attribute combination,
+// and sprites flipped both ways and behind the background, uploaded with
+// OAM DMA during vblank
+fn tiles_and_sprites(cpu: &mut CPU) {
+    run_frames(cpu, 1);
+
+    // Tile 1 is a diagonal in colour 1 over a colour 2 border, tile 2 a
+    // checkerboard of colours 1 and 3
+    let diagonal = [0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01];
+    let border = [0xff, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0xff];
+    let checker = [0xaa, 0x55, 0xaa, 0x55, 0xaa, 0x55, 0xaa, 0x55];
+    write_vram(cpu, 0x0010, &[diagonal, border].concat());
+    write_vram(cpu, 0x0020, &[checker, [0xf0; 8]].concat());
+
+    let tiles: Vec<u8> = (0..960).map(|n| ((n % 32 + n / 32) % 3) as u8).collect();
+    let attributes: Vec<u8> = (0..64).map(|n| (n * 0x1b) as u8).collect();
+    write_vram(cpu, 0x2000, &tiles);
+    write_vram(cpu, 0x23c0, &attributes);
+    write_vram(
+        cpu,
+        0x3f00,
+        &[
+            0x0f, 0x16, 0x2a, 0x30, 0x0f, 0x12, 0x21, 0x3c, 0x0f, 0x19, 0x28, 0x37, 0x0f, 0x06,
+            0x15, 0x24, 0x0f, 0x30, 0x27, 0x11, 0x0f, 0x14, 0x23, 0x32, 0x0f, 0x1c, 0x2b, 0x3a,
+            0x0f, 0x07, 0x17, 0x27,
+        ],
+    );
+
+    let sprites = [
+        [40, 1, 0x00, 40],
+        [40, 2, 0x41, 48],
+        [60, 1, 0x82, 100],
+        [60, 2, 0x23, 104],
+        [200, 1, 0xc1, 250],
+    ];
+    let oam = sprites.iter().flatten().chain([0xff].iter().cycle());
+    for (addr, byte) in (0x0200..0x0300).zip(oam) {
+        cpu.mem_write(addr, *byte);
+    }
+    cpu.mem_write(0x4014, 0x02);
+
+    cpu.mem_write(0x2006, 0x00);
+    cpu.mem_write(0x2006, 0x00);
+    cpu.mem_write(0x2005, 3);
+    cpu.mem_write(0x2005, 5);
+    cpu.mem_write(0x2000, 0x00);
+    cpu.mem_write(0x2001, 0x1e);
+}
+
+// A scene drawn without a ROM: its name, what sets it up and the number of
+// frames it runs after that
+type Scene = (&'static str, fn(&mut CPU), u64);
+
+const SYNTHETIC_SCENES: [Scene; 1] = [("synthetic/tiles_and_sprites", tiles_and_sprites, 2)];
+
+// Checks hashes, or errors getting them, against the goldens file and fails
+// on any mismatch. With NEMULATOR_BLESS set it records them instead.
+fn check_goldens(screenshots: Vec<(String, u64, Result<u32, String>)>) {
+    let bless = env::var("NEMULATOR_BLESS").is_ok();
+    let text = fs::read_to_string(golden_path())
+        .unwrap_or_else(|err| panic!("can't read {}: {}", golden_path().display(), err));
+    let header: String = text
+        .lines()
+        .take_while(|line| line.starts_with('#'))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    let mut goldens = parse_goldens(&text);
+    let mut failures = Vec::new();
+
+    for (name, frames, hash) in screenshots {
+        let hash = match hash {
+            Ok(hash) => hash,
+            Err(err) => {
+                failures.push(format!("{}: {}", name, err));
+                continue;
+            }
+        };
+
+        if bless {
+            goldens.insert(name, Golden { frames, hash });
+            continue;
+        }
+
+        match goldens.get(&name) {
+            Some(golden) if golden.frames == frames && golden.hash == hash => {}
+            Some(golden) if golden.frames == frames => failures.push(format!(
+                "{}: frame hash {:08x}, expected {:08x}",
+                name, hash, golden.hash
+            )),
+            _ => failures.push(format!(
+                "{}: no golden for {} frames, rerun with NEMULATOR_BLESS=1 to record it",
+                name, frames
+            )),
+        }
+    }
+
+    if bless {
+        fs::write(golden_path(), format_goldens(&header, &goldens)).unwrap();
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+#[ignore = "needs the PPU test ROMs in test_roms/"]
+fn test_ppu_test_rom_screenshots() {
+    let screenshots = PPU_TEST_ROMS
+        .iter()
+        .map(|(rom, frames)| {
+            let path = rom_dir().join(rom);
+            let hash = if path.exists() {
+                screenshot_hash(&path, *frames)
+            } else {
+                Err(format!("ROM not found in {}", rom_dir().display()))
+            };
+            (rom.to_string(), *frames, hash)
+        })
+        .collect();
+
+    check_goldens(screenshots);
+}
+
+#[test]
+fn test_synthetic_screenshots() {
+    let screenshots = SYNTHETIC_SCENES
+        .iter()
+        .map(|(name, setup, frames)| {
+            let mut cpu = CPU::new();
+            cpu.load(vec![]);
+            setup(&mut cpu);
+            run_frames(&mut cpu, *frames);
+            (
+                name.to_string(),
+                *frames,
+                drawn_frame_hash(&cpu.ppu.frame, *frames),
+            )
+        })
+        .collect();
+
+    check_goldens(screenshots);
+}
+
+#[test]
+fn test_goldens_round_trip() {
+    let text = "# header\nsome/rom.nes 60 0badf00d\n";
+    let goldens = parse_goldens(text);
+
+    assert_eq!(goldens["some/rom.nes"].frames, 60);
+    assert_eq!(goldens["some/rom.nes"].hash, 0x0bad_f00d);
+    assert_eq!(format_goldens("# header\n", &goldens), text);
+}
+
+#[test]
+fn test_frame_hash_sees_emphasis() {
+    let mut frame = Frame::new();
+    let plain = frame_hash(&frame);
+
+    frame.set(0, 0, 0x40);
+    assert_ne!(frame_hash(&frame), plain);
+}
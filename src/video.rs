@@ -0,0 +1,100 @@
+use std::io::{self, Write};
+
+use crate::frame::{HEIGHT, WIDTH};
+use crate::region::Region;
+
+// Writers for uncompressed video streams out of packed 24 bit RGB frames
+
+// Raw RGB24, frames back to back with no header at all
+pub struct RawWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> RawWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn write_frame(&mut self, rgb: &[u8]) -> io::Result<()> {
+        self.out.write_all(rgb)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// YUV4MPEG2 with full resolution chroma, which most video tools take as
+// input directly
+pub struct Y4mWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut out: W, region: Region) -> io::Result<Self> {
+        let (num, den) = region.frame_rate();
+        // The NES pixel aspect ratio is 8:7
+        writeln!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A8:7 C444",
+            WIDTH, HEIGHT, num, den
+        )?;
+
+        Ok(Self { out })
+    }
+
+    pub fn write_frame(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let pixels = rgb.len() / 3;
+        let mut planes = vec![0u8; pixels * 3];
+
+        for (n, color) in rgb.chunks(3).enumerate() {
+            let [y, u, v] = rgb_to_yuv(color[0], color[1], color[2]);
+            planes[n] = y;
+            planes[pixels + n] = u;
+            planes[2 * pixels + n] = v;
+        }
+
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&planes)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// BT.601 studio swing, what Y4M consumers expect by default
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> [u8; 3] {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+
+    let y = 16.0 + (65.738 * r + 129.057 * g + 25.064 * b) / 256.0;
+    let u = 128.0 + (-37.945 * r - 74.494 * g + 112.439 * b) / 256.0;
+    let v = 128.0 + (112.439 * r - 94.154 * g - 18.285 * b) / 256.0;
+
+    [y.round() as u8, u.round() as u8, v.round() as u8]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_y4m_header_and_frame() {
+        let mut out = Vec::new();
+        let mut writer = Y4mWriter::new(&mut out, Region::Ntsc).unwrap();
+        writer.write_frame(&vec![0; WIDTH * HEIGHT * 3]).unwrap();
+        writer.finish().unwrap();
+
+        let header = b"YUV4MPEG2 W256 H240 F236250000:3931048 Ip A8:7 C444\nFRAME\n";
+        assert_eq!(&out[..header.len()], &header[..]);
+        assert_eq!(out.len(), header.len() + WIDTH * HEIGHT * 3);
+        // Black is 16 on the luma plane and 128 on the chroma ones
+        assert_eq!(out[header.len()], 16);
+        assert_eq!(out[out.len() - 1], 128);
+    }
+
+    #[test]
+    fn test_rgb_to_yuv_white() {
+        assert_eq!(rgb_to_yuv(255, 255, 255), [235, 128, 128]);
+    }
+}
@@ -0,0 +1,82 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::apu;
+
+// 16 bit mono PCM WAV files. The sizes in the header aren't known until the
+// recording stops, so they get patched in by finish.
+
+const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        // Bytes per second, bytes per sample frame and bits per sample
+        out.write_all(&(sample_rate * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { out, data_size: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let bytes: Vec<u8> = apu::to_i16(samples)
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+
+        self.out.write_all(&bytes)?;
+        self.data_size += bytes.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.data_size.to_le_bytes())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_and_samples() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut out, 44_100).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0]).unwrap();
+        writer.write_samples(&[0.5]).unwrap();
+        writer.finish().unwrap();
+
+        let wav = out.into_inner();
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 44_100);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 8);
+        assert_eq!(wav[44..], [0x00, 0x00, 0xff, 0x7f, 0x01, 0x80, 0xff, 0x3f]);
+    }
+}